@@ -0,0 +1,28 @@
+//! Feed arbitrary bytes to the backend message parsers, which should
+//! return protocol errors rather than panic.
+//!
+//!     cargo fuzz run server_messages
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate pg;
+
+use pg::error::PgError;
+use pg::servermsg::{take_msg, ServerMsg};
+
+fn check<T>(result: pg::Result<T>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(PgError::Protocol(_)) => None,
+        Err(err) => panic!("Expected a protocol error, got {:?}", err),
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check(ServerMsg::from_slice(data));
+    let mut input = data;
+    while let Some((message, rest)) = check(take_msg(input)) {
+        check(ServerMsg::from_slice(message));
+        input = rest;
+    }
+});
@@ -0,0 +1,592 @@
+//! An asynchronous client, built on tokio.
+//!
+//! `connect` spawns a task which owns the socket.  Queries are handed to
+//! that task as soon as they are created, and written to the socket in
+//! order without waiting for earlier results, so several query futures can
+//! be in flight on one connection at once.  Dropping a query future does
+//! not disturb the connection: the task still reads the server's response
+//! and discards it.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tracing::Span;
+use Result;
+use cancel::CancelToken;
+use config::Config;
+use error::PgError;
+use metrics::{ConnectEvent, Hooks, QueryEvent, connect_span, query_span};
+use protocol::{Event, Protocol};
+
+/// A handle to an asynchronous connection.
+///
+/// The connection is closed once the handle and all of its query futures
+/// have been dropped.
+#[derive(Debug)]
+pub struct Connection {
+    requests: mpsc::UnboundedSender<Request>,
+    cancel_token: Option<CancelToken>,
+}
+
+impl Connection {
+    /// Open a connection.  Must be called from within a tokio runtime.
+    pub fn connect(config: &Config) -> Connect {
+        let address = (config.host.clone(), config.port);
+        Connect {
+            config: config.clone(),
+            span: connect_span(config),
+            started: Instant::now(),
+            state: ConnectState::Connecting(Box::pin(TcpStream::connect(address))),
+        }
+    }
+
+    /// Send a query to the server.
+    ///
+    /// The query is queued as soon as this is called, so queries run in the
+    /// order they were created, whatever order their futures are awaited in.
+    pub fn query(&self, sql: &str) -> QueryFuture {
+        let (sender, receiver) = oneshot::channel();
+        let request = Request {
+            span: query_span(sql),
+            sql: sql.to_string(),
+            started: Instant::now(),
+            response: sender,
+        };
+        // If the connection has closed, the request is dropped along with
+        // its sender, and the future resolves to an error.
+        let _ = self.requests.send(request);
+        QueryFuture { response: receiver }
+    }
+
+    /// Get a token that can cancel the query the server is running.
+    pub fn cancel_token(&self) -> Option<CancelToken> {
+        self.cancel_token.clone()
+    }
+
+    /// Whether the connection to the server has been lost.
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+}
+
+/// The future returned by `Connection::connect`.
+pub struct Connect {
+    config: Config,
+    span: Span,
+    started: Instant,
+    state: ConnectState,
+}
+
+enum ConnectState {
+    Connecting(Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>),
+    Starting(oneshot::Receiver<Result<Startup>>, mpsc::UnboundedSender<Request>),
+    Done,
+}
+
+impl Connect {
+    fn poll_connect(&mut self, cx: &mut Context) -> Poll<Result<(Connection, Option<Duration>)>> {
+        loop {
+            let next = match self.state {
+                ConnectState::Connecting(ref mut connecting) => {
+                    let stream = match connecting.as_mut().poll(cx) {
+                        Poll::Ready(Ok(stream)) => stream,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(PgError::Io(err))),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    if let Err(err) = stream.set_nodelay(true) {
+                        return Poll::Ready(Err(PgError::Io(err)));
+                    }
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    let (ready_sender, ready_receiver) = oneshot::channel();
+                    let database = match self.config.database {
+                        Some(ref db) => db.clone(),
+                        None => self.config.user.clone(),
+                    };
+                    let password = match self.config.fetch_password() {
+                        Ok(password) => password,
+                        Err(err) => return Poll::Ready(Err(err)),
+                    };
+                    let mut protocol = Protocol::new(&self.config.user, password, &database);
+                    protocol.set_max_message_size(self.config.max_message_size);
+                    tokio::spawn(Driver {
+                        stream: stream,
+                        protocol: protocol,
+                        max_result_size: self.config.max_result_size,
+                        hooks: self.config.hooks.clone(),
+                        started: self.started,
+                        requests: receiver,
+                        pending: VecDeque::new(),
+                        startup: Some(ready_sender),
+                        auth_span: Some(debug_span!(parent: &self.span, "auth")),
+                        auth_duration: None,
+                        write_buffer: vec![],
+                        closing: false,
+                    });
+                    ConnectState::Starting(ready_receiver, sender)
+                },
+                ConnectState::Starting(ref mut ready, ref sender) => {
+                    let startup = match Pin::new(ready).poll(cx) {
+                        Poll::Ready(Ok(Ok(startup))) => startup,
+                        Poll::Ready(Ok(Err(err))) => return Poll::Ready(Err(err)),
+                        Poll::Ready(Err(_)) => return Poll::Ready(Err(closed())),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    let host = &self.config.host;
+                    let port = self.config.port;
+                    let conn = Connection {
+                        requests: sender.clone(),
+                        cancel_token: startup.backend_key.map(|(pid, key)| CancelToken::new(host, port, pid, &key)),
+                    };
+                    self.state = ConnectState::Done;
+                    return Poll::Ready(Ok((conn, startup.auth_duration)));
+                },
+                ConnectState::Done => panic!("Connect polled after completion"),
+            };
+            self.state = next;
+        }
+    }
+}
+
+impl Future for Connect {
+    type Output = Result<Connection>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Connection>> {
+        let this = &mut *self;
+        let _enter = this.span.clone().entered();
+        let result = match this.poll_connect(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.config.hooks.connected(&this.span, &ConnectEvent {
+            duration: this.started.elapsed(),
+            auth_duration: result.as_ref().ok().and_then(|&(_, auth_duration)| auth_duration),
+            error: result.as_ref().err(),
+        });
+        Poll::Ready(result.map(|(conn, _)| conn))
+    }
+}
+
+/// The future returned by `Connection::query`.
+///
+/// It can be dropped at any point without affecting other queries.
+#[derive(Debug)]
+pub struct QueryFuture {
+    response: oneshot::Receiver<Result<Vec<Vec<String>>>>,
+}
+
+impl Future for QueryFuture {
+    type Output = Result<Vec<Vec<String>>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Vec<Vec<String>>>> {
+        match Pin::new(&mut self.response).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(closed())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn closed() -> PgError {
+    PgError::Error("Connection closed".to_string())
+}
+
+#[derive(Debug)]
+struct Request {
+    span: Span,
+    sql: String,
+    started: Instant,
+    response: oneshot::Sender<Result<Vec<Vec<String>>>>,
+}
+
+/// A query which has been sent, and the response received so far.
+struct Pending {
+    request: Request,
+    rows: Vec<Vec<String>>,
+    /// The total length of the values in `rows`.
+    size: usize,
+    error: Option<PgError>,
+}
+
+impl Pending {
+    fn finish(self, hooks: &Hooks) {
+        let result = match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.rows),
+        };
+        let request = self.request;
+        hooks.query_finished(&request.span, &QueryEvent {
+            sql: &request.sql,
+            duration: request.started.elapsed(),
+            rows: result.as_ref().map(|rows| rows.len() as u64).unwrap_or(0),
+            error: result.as_ref().err(),
+        });
+        let _ = request.response.send(result);
+    }
+}
+
+struct Startup {
+    backend_key: Option<(u32, Vec<u8>)>,
+    auth_duration: Option<Duration>,
+}
+
+/// The task which owns the socket, writing queries and routing each
+/// response to the query that is waiting for it.
+struct Driver {
+    stream: TcpStream,
+    protocol: Protocol,
+    max_result_size: Option<usize>,
+    hooks: Hooks,
+    started: Instant,
+    requests: mpsc::UnboundedReceiver<Request>,
+    pending: VecDeque<Pending>,
+    startup: Option<oneshot::Sender<Result<Startup>>>,
+    /// Open from the start of the connection until authentication succeeds.
+    auth_span: Option<Span>,
+    auth_duration: Option<Duration>,
+    /// Output taken from the protocol which the socket has not accepted yet.
+    write_buffer: Vec<u8>,
+    closing: bool,
+}
+
+impl Driver {
+    fn poll_io(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        loop {
+            if self.startup.is_none() {
+                self.poll_requests(cx);
+            }
+            if let Err(err) = self.poll_write(cx) {
+                return Poll::Ready(Err(err));
+            }
+            if self.closing && self.write_buffer.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            match self.poll_read(cx) {
+                Poll::Ready(Ok(0)) => {
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof, "Server closed the connection");
+                    return Poll::Ready(Err(PgError::Io(err)));
+                },
+                Poll::Ready(Ok(_)) => {
+                    if let Err(err) = self.process_messages() {
+                        return Poll::Ready(Err(err));
+                    }
+                },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Queue every new request.  Once the handle is gone and every response
+    /// has arrived, say goodbye to the server.
+    fn poll_requests(&mut self, cx: &mut Context) {
+        while !self.closing {
+            match self.requests.poll_recv(cx) {
+                Poll::Ready(Some(request)) => {
+                    if let Err(err) = self.protocol.query(&request.sql) {
+                        let _ = request.response.send(Err(err));
+                        continue;
+                    }
+                    self.pending.push_back(Pending {
+                        request: request,
+                        rows: vec![],
+                        size: 0,
+                        error: None,
+                    });
+                },
+                Poll::Ready(None) => {
+                    if self.pending.is_empty() {
+                        self.protocol.terminate();
+                        self.closing = true;
+                    }
+                    break;
+                },
+                Poll::Pending => break,
+            }
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context) -> Result<()> {
+        self.write_buffer.extend(self.protocol.take_output());
+        while self.write_buffer.len() > 0 {
+            match Pin::new(&mut self.stream).poll_write(cx, &self.write_buffer) {
+                Poll::Ready(Ok(0)) => {
+                    return Err(PgError::Io(io::Error::new(io::ErrorKind::WriteZero, "Socket closed")));
+                },
+                Poll::Ready(Ok(count)) => {
+                    self.write_buffer.drain(..count);
+                    self.hooks.bytes_sent(count);
+                },
+                Poll::Ready(Err(err)) => return Err(PgError::Io(err)),
+                Poll::Pending => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_read(&mut self, cx: &mut Context) -> Poll<Result<usize>> {
+        let mut chunk = [0; 8192];
+        let mut buf = ReadBuf::new(&mut chunk);
+        match Pin::new(&mut self.stream).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => {
+                let count = buf.filled().len();
+                self.protocol.receive(buf.filled());
+                self.hooks.bytes_received(count);
+                Poll::Ready(Ok(count))
+            },
+            Poll::Ready(Err(err)) => Poll::Ready(Err(PgError::Io(err))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn process_messages(&mut self) -> Result<()> {
+        while let Some(event) = try!(self.protocol.next_event()) {
+            try!(match self.startup {
+                Some(_) => {
+                    let span = self.auth_span.clone().unwrap_or_else(Span::none);
+                    span.in_scope(|| self.handle_startup(event))
+                },
+                None => self.handle_response(event),
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_startup(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Authenticated => {
+                debug!("authenticated");
+                self.auth_span = None;
+                self.auth_duration = Some(self.started.elapsed());
+            },
+            Event::ReadyForQuery(_) => {
+                if let Some(ready) = self.startup.take() {
+                    let _ = ready.send(Ok(Startup {
+                        backend_key: self.protocol.backend_key().map(|(pid, key)| (pid, key.to_vec())),
+                        auth_duration: self.auth_duration,
+                    }));
+                }
+            },
+            Event::Error(err) => return Err(PgError::Db(err)),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn handle_response(&mut self, event: Event) -> Result<()> {
+        let pending = match self.pending.front_mut() {
+            Some(pending) => pending,
+            None => {
+                // Only an error, such as a shutdown notice, can arrive
+                // while no query is running.
+                return match event {
+                    Event::Error(err) => Err(PgError::Db(err)),
+                    _ => Ok(()),
+                };
+            },
+        };
+        match event {
+            Event::DataRow(row) => {
+                if !pending.request.response.is_closed() {
+                    pending.size += row.iter().map(|value| value.len()).sum::<usize>();
+                    if let Some(limit) = self.max_result_size {
+                        if pending.size > limit {
+                            return Err(PgError::ResultTooLarge(limit));
+                        }
+                    }
+                    pending.rows.push(row);
+                }
+            },
+            Event::Error(err) => {
+                pending.error = Some(PgError::Db(err));
+            },
+            Event::CopyIn(..) => {
+                pending.error = Some(PgError::Error("COPY is not supported by the async client".to_string()));
+                try!(self.protocol.copy_fail("COPY is not supported by the async client"));
+            },
+            Event::CopyOut(..) => {
+                pending.error = Some(PgError::Error("COPY is not supported by the async client".to_string()));
+            },
+            Event::ReadyForQuery(_) => {
+                if let Some(pending) = self.pending.pop_front() {
+                    pending.finish(&self.hooks);
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Hand `err` to whoever is waiting on the connection.  Any other
+    /// queries fail with a closed connection when their senders are dropped.
+    fn fail(&mut self, err: PgError) {
+        match err {
+            PgError::Protocol(_) | PgError::MessageTooLarge(_) | PgError::ResultTooLarge(_) => {
+                // The server is still there, so say goodbye if the socket
+                // will take it without waiting.
+                self.protocol.terminate();
+                self.write_buffer.extend(self.protocol.take_output());
+                let _ = self.stream.try_write(&self.write_buffer);
+            },
+            _ => {},
+        }
+        if let Some(ready) = self.startup.take() {
+            let _ = ready.send(Err(err));
+        } else if let Some(mut pending) = self.pending.pop_front() {
+            pending.error = Some(err);
+            pending.finish(&self.hooks);
+        }
+        self.pending.clear();
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.poll_io(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(()),
+            Poll::Ready(Err(err)) => {
+                self.fail(err);
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::{Builder, Runtime};
+    use error::PgError;
+    use metrics::MetricsCollector;
+    use testing::{Auth, MockBackend, MockServer, Received, Response};
+    use super::Connection;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_io().build().unwrap()
+    }
+
+    /// A mock server which answers the queries in these tests.
+    fn mock() -> MockServer {
+        let series = (1..1001).map(|n| vec![Some(n.to_string())]).collect();
+        MockBackend::new()
+            .auth(Auth::Md5("secret".to_string()))
+            .on_query("SELECT 1, 'two';", Response::rows(&["?column?", "?column?"], &[&["1", "two"]]))
+            .on_query("SELECT 1;", Response::rows(&["?column?"], &[&["1"]]))
+            .on_query("SELECT 'fast';", Response::rows(&["?column?"], &[&["fast"]]))
+            .on_query("SELECT 'after';", Response::rows(&["?column?"], &[&["after"]]))
+            .on_query(
+                "SELECT 'slow' FROM pg_sleep(0.2);",
+                Response::delay(Duration::from_millis(200), Response::rows(&["?column?"], &[&["slow"]])),
+            )
+            .on_query("SELECT * FROM no_such_table;", Response::error("42P01", "relation \"no_such_table\" does not exist"))
+            .on_query("SELECT generate_series(1, 1000);", Response::Rows(vec!["generate_series".to_string()], series))
+            .on_query("SELECT pg_terminate_backend(pg_backend_pid());", Response::Disconnect)
+            .start()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_query() {
+        let server = mock();
+        let runtime = runtime();
+        let conn = runtime.block_on(Connection::connect(&server.config("cliff").password("secret"))).unwrap();
+        let rows = runtime.block_on(conn.query("SELECT 1, 'two';")).unwrap();
+        assert_eq!(rows, vec![vec!["1".to_string(), "two".to_string()]]);
+        assert!(conn.cancel_token().is_some());
+    }
+
+    #[test]
+    fn test_pipelined_queries() {
+        let server = mock();
+        let runtime = runtime();
+        let conn = runtime.block_on(Connection::connect(&server.config("cliff").password("secret"))).unwrap();
+        let slow = conn.query("SELECT 'slow' FROM pg_sleep(0.2);");
+        let failing = conn.query("SELECT * FROM no_such_table;");
+        let fast = conn.query("SELECT 'fast';");
+        assert_eq!(runtime.block_on(fast).unwrap(), vec![vec!["fast".to_string()]]);
+        match runtime.block_on(failing) {
+            Err(PgError::Db(err)) => assert_eq!(err.code, "42P01"),
+            other => panic!("Expected an undefined table error, got {:?}", other),
+        }
+        assert_eq!(runtime.block_on(slow).unwrap(), vec![vec!["slow".to_string()]]);
+    }
+
+    #[test]
+    fn test_dropped_query() {
+        let server = mock();
+        let runtime = runtime();
+        let conn = runtime.block_on(Connection::connect(&server.config("cliff").password("secret"))).unwrap();
+        drop(conn.query("SELECT generate_series(1, 1000);"));
+        let rows = runtime.block_on(conn.query("SELECT 'after';")).unwrap();
+        assert_eq!(rows, vec![vec!["after".to_string()]]);
+    }
+
+    #[test]
+    fn test_failed_connect() {
+        let runtime = runtime();
+        let server = mock();
+        let collector = Arc::new(MetricsCollector::new());
+        let config = server.config("notauser").password("wrong").instrumentation(collector.clone());
+        assert!(runtime.block_on(Connection::connect(&config)).is_err());
+        assert_eq!(collector.snapshot().connect_errors, 1);
+    }
+
+    #[test]
+    fn test_max_result_size() {
+        let value = "x".repeat(60);
+        let server = MockBackend::new()
+            .on_query("SELECT repeat('x', 60);", Response::rows(&["repeat"], &[&[&value]]))
+            .on_query("SELECT repeat('x', 60) FROM generate_series(1, 2);", Response::rows(&["repeat"], &[&[&value], &[&value]]))
+            .on_query("SELECT 1;", Response::rows(&["?column?"], &[&["1"]]))
+            .start()
+            .unwrap();
+        let runtime = runtime();
+        let conn = runtime.block_on(Connection::connect(&server.config("cliff").max_result_size(Some(100)))).unwrap();
+        assert!(runtime.block_on(conn.query("SELECT repeat('x', 60);")).is_ok());
+        match runtime.block_on(conn.query("SELECT repeat('x', 60) FROM generate_series(1, 2);")) {
+            Err(PgError::ResultTooLarge(100)) => {},
+            other => panic!("Expected the result to be refused, got {:?}", other),
+        }
+        assert!(runtime.block_on(conn.query("SELECT 1;")).is_err());
+    }
+
+    #[test]
+    fn test_terminate_after_result_too_large() {
+        let value = "x".repeat(60);
+        let server = MockBackend::new()
+            .on_query("SELECT two", Response::rows(&["x"], &[&[&value], &[&value]]))
+            .start()
+            .unwrap();
+        let runtime = runtime();
+        let conn = runtime.block_on(Connection::connect(&server.config("cliff").max_result_size(Some(100)))).unwrap();
+        match runtime.block_on(conn.query("SELECT two;")) {
+            Err(PgError::ResultTooLarge(100)) => {},
+            other => panic!("Expected the result to be refused, got {:?}", other),
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.received().contains(&Received::Terminate) {
+            assert!(Instant::now() < deadline, "Terminate was never received");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_closed_connection() {
+        let server = mock();
+        let runtime = runtime();
+        let conn = runtime.block_on(Connection::connect(&server.config("cliff").password("secret"))).unwrap();
+        let result = runtime.block_on(conn.query("SELECT pg_terminate_backend(pg_backend_pid());"));
+        assert!(result.is_err());
+        assert!(runtime.block_on(conn.query("SELECT 1;")).is_err());
+    }
+}
@@ -1,21 +1,201 @@
 use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use crypto::md5::Md5;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::{OsRng, Rng};
+use secret::Secret;
 
-pub fn build_md5_hash(user: &str, password: &str, salt: &[u8]) -> String {
+pub fn build_md5_hash(user: &str, password: &str, salt: &[u8]) -> Secret {
     let mut userpasshasher = Md5::new();
     let mut final_hash = String::with_capacity(35);
     final_hash.extend("md5".chars());
 
     userpasshasher.input_str(password);
     userpasshasher.input_str(user);
-    let hash = userpasshasher.result_str();
+    // The unsalted hash is all a server stores, so it is as good as the
+    // password.
+    let hash = Secret::from(userpasshasher.result_str());
+    userpasshasher.reset();
     let mut saltedhasher = Md5::new();
-    saltedhasher.input_str(&hash);
+    saltedhasher.input_str(hash.expose());
     saltedhasher.input(salt);
     final_hash.extend(saltedhasher.result_str().chars());
-    final_hash
+    Secret::from(final_hash)
 }
-    
+
+/// The server's side of a SCRAM-SHA-256 exchange, as described in RFC 5802
+/// and 7677.  Channel binding is not supported.
+pub(crate) struct ScramServer {
+    password: Secret,
+    salt: Vec<u8>,
+    iterations: u32,
+    /// The client-first-message-bare, server-first-message and full nonce,
+    /// once the exchange has started.
+    first: Option<(String, String, String)>,
+}
+
+impl ScramServer {
+    pub fn new(password: &str) -> ScramServer {
+        ScramServer {
+            password: Secret::new(password),
+            salt: random_bytes(16),
+            iterations: 4096,
+            first: None,
+        }
+    }
+
+    /// Answer the client-first-message.  None if it is malformed.
+    pub fn server_first(&mut self, client_first: &[u8]) -> Option<Vec<u8>> {
+        let client_first = String::from_utf8_lossy(client_first).into_owned();
+        let client_first_bare = match client_first.splitn(3, ',').nth(2) {
+            Some(bare) if client_first.starts_with("n,") || client_first.starts_with("y,") => bare.to_string(),
+            _ => return None,
+        };
+        let client_nonce = match scram_attribute(&client_first_bare, 'r') {
+            Some(nonce) => nonce,
+            None => return None,
+        };
+        let nonce = format!("{}{}", client_nonce, base64_encode(&random_bytes(18)));
+        let server_first = format!("r={},s={},i={}", nonce, base64_encode(&self.salt), self.iterations);
+        self.first = Some((client_first_bare, server_first.clone(), nonce));
+        Some(server_first.into_bytes())
+    }
+
+    /// Check the proof in the client-final-message, and answer with the
+    /// server's signature.  None if the client did not prove it knows the
+    /// password.
+    pub fn server_final(&self, client_final: &[u8]) -> Option<Vec<u8>> {
+        let (client_first_bare, server_first, nonce) = match self.first {
+            Some((ref client_first_bare, ref server_first, ref nonce)) => (client_first_bare, server_first, nonce),
+            None => return None,
+        };
+        let client_final = String::from_utf8_lossy(client_final).into_owned();
+        let (without_proof, proof) = match client_final.rfind(",p=") {
+            Some(index) => (&client_final[..index], &client_final[index + 3..]),
+            None => return None,
+        };
+        if scram_attribute(without_proof, 'r').as_ref() != Some(nonce) {
+            return None;
+        }
+        let proof = match base64_decode(proof) {
+            Some(proof) => proof,
+            None => return None,
+        };
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let salted_password = scram_salted_password(self.password.expose(), &self.salt, self.iterations);
+        let stored_key = sha256(&hmac_sha256(&salted_password, b"Client Key"));
+        let signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        if proof.len() != signature.len() {
+            return None;
+        }
+        let client_key: Vec<u8> = proof.iter().zip(signature.iter()).map(|(a, b)| a ^ b).collect();
+        if !secure_eq(&sha256(&client_key), &stored_key) {
+            return None;
+        }
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+        let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+        Some(format!("v={}", base64_encode(&server_signature)).into_bytes())
+    }
+}
+
+/// Random bytes from the operating system's secure random number
+/// generator.  Panics if the generator can't be opened.
+pub(crate) fn random_bytes(count: usize) -> Vec<u8> {
+    let mut rng = OsRng::new().expect("Could not open the operating system's random number generator");
+    let mut bytes = vec![0; count];
+    rng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Compare secrets in time which doesn't depend on where they differ.
+pub(crate) fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    // fixed_time_eq reads the first byte even of empty slices.
+    a.len() == b.len() && (a.is_empty() || fixed_time_eq(a, b))
+}
+
+pub(crate) fn scram_attribute(message: &str, name: char) -> Option<String> {
+    message.split(',')
+        .find(|attribute| attribute.starts_with(name) && attribute[name.len_utf8()..].starts_with('='))
+        .map(|attribute| attribute[name.len_utf8() + 1..].to_string())
+}
+
+pub(crate) fn scram_salted_password(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+    let mut salted = vec![0; 32];
+    pbkdf2(&mut mac, salt, iterations, &mut salted);
+    salted
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+pub(crate) fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut digest = vec![0; 32];
+    hasher.result(&mut digest);
+    digest
+}
+
+const BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub(crate) fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let value = match BASE64.iter().position(|&c| c == byte) {
+            Some(value) => value as u32,
+            None => return None,
+        };
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// The client's side of a SCRAM exchange, for testing the server's.
+#[cfg(test)]
+pub(crate) fn scram_client_final(password: &str, client_first_bare: &str, server_first: &str) -> (String, String) {
+    let nonce = scram_attribute(server_first, 'r').unwrap();
+    let salt = base64_decode(&scram_attribute(server_first, 's').unwrap()).unwrap();
+    let iterations = scram_attribute(server_first, 'i').unwrap().parse().unwrap();
+    let without_proof = format!("c=biws,r={}", nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let salted_password = scram_salted_password(password, &salt, iterations);
+    let client_key = hmac_sha256(&salted_password, b"Client Key");
+    let signature = hmac_sha256(&sha256(&client_key), auth_message.as_bytes());
+    let proof: Vec<u8> = client_key.iter().zip(signature.iter()).map(|(a, b)| a ^ b).collect();
+    let server_key = hmac_sha256(&salted_password, b"Server Key");
+    let server_final = format!("v={}", base64_encode(&hmac_sha256(&server_key, auth_message.as_bytes())));
+    (format!("{},p={}", without_proof, base64_encode(&proof)), server_final)
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -23,6 +203,44 @@ mod tests {
 
     #[test]
     fn test_md5_hash() {
-        assert_eq!(build_md5_hash("", "", b"abcd"), "md5743b08b8561cc75c4f899c35d6c3c3eb");
+        assert_eq!(build_md5_hash("", "", b"abcd").expose(), "md5743b08b8561cc75c4f899c35d6c3c3eb");
+    }
+
+    #[test]
+    fn test_secure_eq() {
+        assert!(secure_eq(b"", b""));
+        assert!(secure_eq(b"secret", b"secret"));
+        assert!(!secure_eq(b"secret", b"secreT"));
+        assert!(!secure_eq(b"secret", b"secrets"));
+        assert!(!secure_eq(b"", b"s"));
+    }
+
+    #[test]
+    fn test_random_bytes() {
+        assert_eq!(random_bytes(0).len(), 0);
+        assert_eq!(random_bytes(18).len(), 18);
+        assert!(random_bytes(16) != random_bytes(16));
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in &[&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x10"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data.to_vec());
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn test_scram_server() {
+        let mut server = ScramServer::new("secret");
+        let server_first = String::from_utf8(server.server_first(b"n,,n=,r=clientnonce").unwrap()).unwrap();
+        assert!(server_first.starts_with("r=clientnonce"));
+        let (client_final, expected) = scram_client_final("secret", "n=,r=clientnonce", &server_first);
+        assert_eq!(server.server_final(client_final.as_bytes()).unwrap(), expected.into_bytes());
+
+        let (client_final, _) = scram_client_final("wrong", "n=,r=clientnonce", &server_first);
+        assert_eq!(server.server_final(client_final.as_bytes()), None);
+        assert_eq!(ScramServer::new("secret").server_first(b"p=tls-server-end-point"), None);
     }
 }
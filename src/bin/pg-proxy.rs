@@ -0,0 +1,1014 @@
+//! A connection pooling proxy for Postgres.
+//!
+//! Clients connect to the proxy as they would to the server, and their work
+//! runs on a bounded pool of server connections.  In session mode a client
+//! keeps one server connection until it disconnects.  In transaction mode it
+//! only holds one while a transaction is open, and its named prepared
+//! statements are prepared again on whichever connection it is given.
+//!
+//! Connecting to the `pgproxy` database opens an admin console, which
+//! answers `SHOW STATS`.
+extern crate pg;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use pg::{Config, Connection, Pool, PooledConnection, Result, Secret, TransactionStatus};
+use pg::error::{DbError, PgError};
+use pg::message::{read_frontend_message, Bind, Describe, FrontendMsg, Message, Parse, Target};
+use pg::server::{AuthMethod, Authenticator, Column, Passwords, QueryHandler, QueryResult, Server, Session, Trust};
+use pg::servermsg::ServerMsg;
+
+const USAGE: &'static str = "\
+Usage: pg-proxy --user USER [OPTIONS]
+
+Server options:
+    --host HOST               server host (default 127.0.0.1)
+    --port PORT               server port (default 5432)
+    --user USER               user the proxy connects as
+    --password PASSWORD       its password (default $PGPASSWORD)
+    --database DATABASE       database to connect to (default USER)
+
+Proxy options:
+    --listen ADDRESS          address to accept clients on (default 127.0.0.1:6432)
+    --pool-size N             most server connections to open (default 10)
+    --pool-mode MODE          session or transaction (default session)
+    --checkout-timeout SECS   how long a client waits for a connection (default 30)
+    --auth METHOD             trust, cleartext, md5 or scram-sha-256 (default trust)
+    --users FILE              client passwords, one `user password` pair per line";
+
+/// Clients connecting to this database get the admin console.
+const ADMIN_DATABASE: &'static str = "pgproxy";
+
+/// Settings passed on to clients as the server reported them.
+const REPORTED_PARAMETERS: &'static [&'static str] = &[
+    "server_version",
+    "server_encoding",
+    "client_encoding",
+    "DateStyle",
+    "IntervalStyle",
+    "TimeZone",
+    "integer_datetimes",
+    "standard_conforming_strings",
+];
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("pg-proxy: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        },
+    };
+    let result = Proxy::new(&options).and_then(|proxy| {
+        let listener = try!(TcpListener::bind(&options.listen[..]));
+        proxy.serve(listener)
+    });
+    if let Err(err) = result {
+        eprintln!("pg-proxy: {}", err);
+        process::exit(1);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PoolMode {
+    Session,
+    Transaction,
+}
+
+#[derive(Debug)]
+struct Options {
+    listen: String,
+    host: String,
+    port: u16,
+    user: String,
+    password: Option<Secret>,
+    database: Option<String>,
+    pool_size: usize,
+    pool_mode: PoolMode,
+    checkout_timeout: Duration,
+    auth: AuthMethod,
+    users: Option<String>,
+}
+
+impl Options {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> std::result::Result<Options, String> {
+        let mut options = Options {
+            listen: "127.0.0.1:6432".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 5432,
+            user: String::new(),
+            password: env::var("PGPASSWORD").ok().map(Secret::from),
+            database: None,
+            pool_size: 10,
+            pool_mode: PoolMode::Session,
+            checkout_timeout: Duration::from_secs(30),
+            auth: AuthMethod::Trust,
+            users: None,
+        };
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("{} needs a value", arg)),
+            };
+            match &arg[..] {
+                "--listen" => options.listen = value,
+                "--host" => options.host = value,
+                "--port" => options.port = try!(number(&arg, &value)),
+                "--user" => options.user = value,
+                "--password" => options.password = Some(Secret::from(value)),
+                "--database" => options.database = Some(value),
+                "--pool-size" => options.pool_size = try!(number(&arg, &value)),
+                "--pool-mode" => options.pool_mode = match &value[..] {
+                    "session" => PoolMode::Session,
+                    "transaction" => PoolMode::Transaction,
+                    _ => return Err(format!("unknown pool mode {}", value)),
+                },
+                "--checkout-timeout" => options.checkout_timeout = Duration::from_secs(try!(number(&arg, &value))),
+                "--auth" => options.auth = match &value[..] {
+                    "trust" => AuthMethod::Trust,
+                    "cleartext" => AuthMethod::Cleartext,
+                    "md5" => AuthMethod::Md5,
+                    "scram-sha-256" => AuthMethod::ScramSha256,
+                    _ => return Err(format!("unknown authentication method {}", value)),
+                },
+                "--users" => options.users = Some(value),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        if options.user.is_empty() {
+            return Err("--user is required".to_string());
+        }
+        if options.auth != AuthMethod::Trust && options.users.is_none() {
+            return Err("--users is required to check client passwords".to_string());
+        }
+        Ok(options)
+    }
+
+    fn config(&self) -> Config {
+        let mut config = Config::new(&self.user).host(&self.host).port(self.port);
+        if let Some(ref password) = self.password {
+            config = config.password(password.clone());
+        }
+        if let Some(ref database) = self.database {
+            config = config.database(database);
+        }
+        config
+    }
+
+    fn authenticator(&self) -> Result<Arc<dyn Authenticator>> {
+        let path = match self.users {
+            Some(ref path) => path,
+            None => return Ok(Arc::new(Trust)),
+        };
+        let mut passwords = Passwords::new(self.auth);
+        for line in BufReader::new(try!(File::open(path))).lines() {
+            let line = Secret::from(try!(line));
+            let line = line.expose().trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find(char::is_whitespace) {
+                Some(split) => passwords = passwords.user(&line[..split], line[split..].trim()),
+                None => return Err(PgError::Error(format!("Expected `user password` in {}: {}", path, line))),
+            }
+        }
+        Ok(Arc::new(passwords))
+    }
+}
+
+fn number<T: FromStr>(arg: &str, value: &str) -> std::result::Result<T, String> {
+    value.parse().map_err(|_| format!("{} needs a number, not {}", arg, value))
+}
+
+/// Counters for the admin console.
+#[derive(Debug, Default)]
+struct Stats {
+    clients: AtomicUsize,
+    transactions: AtomicUsize,
+    queries: AtomicUsize,
+    received: AtomicUsize,
+    sent: AtomicUsize,
+    /// Microseconds clients spent waiting for a server connection.
+    wait_time: AtomicUsize,
+}
+
+fn add(counter: &AtomicUsize, amount: usize) {
+    counter.fetch_add(amount, Ordering::Relaxed);
+}
+
+/// Answers the admin console's commands.
+struct Admin {
+    database: String,
+    pool: Pool,
+    stats: Arc<Stats>,
+}
+
+impl QueryHandler for Admin {
+    fn query(&self, _session: &Session, sql: &str) -> Result<Vec<QueryResult>> {
+        let words: Vec<String> = sql.trim().trim_end_matches(';').split_whitespace().map(str::to_uppercase).collect();
+        if words != ["SHOW", "STATS"] {
+            let message = format!("unknown command \"{}\", try SHOW STATS", sql.trim());
+            return Err(PgError::Db(DbError::new("42601", &message)));
+        }
+        let columns = vec![
+            Column::text("database"),
+            Column::new("total_xact_count", 20),
+            Column::new("total_query_count", 20),
+            Column::new("total_received", 20),
+            Column::new("total_sent", 20),
+            Column::new("total_wait_time", 20),
+            Column::new("cl_active", 20),
+            Column::new("sv_active", 20),
+            Column::new("sv_idle", 20),
+        ];
+        let idle = self.pool.idle_count();
+        let counts = vec![
+            self.stats.transactions.load(Ordering::Relaxed),
+            self.stats.queries.load(Ordering::Relaxed),
+            self.stats.received.load(Ordering::Relaxed),
+            self.stats.sent.load(Ordering::Relaxed),
+            self.stats.wait_time.load(Ordering::Relaxed),
+            self.stats.clients.load(Ordering::Relaxed),
+            self.pool.size().saturating_sub(idle),
+            idle,
+        ];
+        let mut row = vec![Some(self.database.clone())];
+        row.extend(counts.into_iter().map(|count| Some(count.to_string())));
+        Ok(vec![QueryResult::Rows(columns, vec![row])])
+    }
+}
+
+#[derive(Clone)]
+struct Proxy {
+    database: String,
+    pool_mode: PoolMode,
+    pool: Pool,
+    server: Server,
+    stats: Arc<Stats>,
+    /// The statements prepared on each server connection in transaction
+    /// mode, by backend process ID.
+    prepared: Arc<Mutex<HashMap<u32, HashSet<String>>>>,
+}
+
+impl Proxy {
+    /// Set up the pool, checking that the server can be reached.
+    fn new(options: &Options) -> Result<Proxy> {
+        let config = options.config();
+        let prepared = Arc::new(Mutex::new(HashMap::new()));
+        let pool = {
+            let prepared = prepared.clone();
+            try!(Pool::builder()
+                .max_size(options.pool_size)
+                .checkout_timeout(options.checkout_timeout)
+                .discard_on_return(options.pool_mode == PoolMode::Session)
+                .build(move || {
+                    let conn = try!(config.connect());
+                    // A new server process may reuse the ID of one which
+                    // has gone, along with its statements.
+                    if let Some(pid) = pid(&conn) {
+                        prepared.lock().unwrap().remove(&pid);
+                    }
+                    Ok(conn)
+                }))
+        };
+        let database = options.database.clone().unwrap_or_else(|| options.user.clone());
+        let stats = Arc::new(Stats::default());
+        let admin = Admin { database: database.clone(), pool: pool.clone(), stats: stats.clone() };
+        let mut server = Server::new(Arc::new(admin)).authenticator(try!(options.authenticator()));
+        {
+            let conn = try!(pool.get());
+            for &name in REPORTED_PARAMETERS {
+                if let Some(value) = conn.parameter(name) {
+                    server = server.parameter(name, value);
+                }
+            }
+        }
+        Ok(Proxy {
+            database: database,
+            pool_mode: options.pool_mode,
+            pool: pool,
+            server: server,
+            stats: stats,
+            prepared: prepared,
+        })
+    }
+
+    /// Accept clients until the listener fails, serving each on its own
+    /// thread.
+    fn serve(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = try!(stream);
+            try!(stream.set_nodelay(true));
+            let proxy = self.clone();
+            thread::spawn(move || {
+                match proxy.serve_client(stream) {
+                    Ok(()) | Err(PgError::Io(_)) => {},
+                    Err(err) => eprintln!("pg-proxy: {}", err),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn serve_client(&self, mut stream: TcpStream) -> Result<()> {
+        let session = match try!(self.server.handshake(&mut stream)) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let database = session.database().unwrap_or(session.user()).to_string();
+        if database == ADMIN_DATABASE {
+            return self.server.serve_session(stream, &session);
+        }
+        if database != self.database {
+            let message = format!("database \"{}\" is not served by this proxy", database);
+            let mut err = DbError::new("3D000", &message);
+            err.severity = "FATAL".to_string();
+            try!(stream.write_all(&ServerMsg::ErrorResponse(err.fields()).to_bytes()));
+            return Ok(());
+        }
+        add(&self.stats.clients, 1);
+        let result = Client::new(self, stream).run();
+        self.stats.clients.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+
+    /// The statements prepared on a server connection.
+    fn with_prepared<F, T>(&self, conn: &Connection, f: F) -> T
+        where F: FnOnce(&mut HashSet<String>) -> T
+    {
+        let mut prepared = self.prepared.lock().unwrap();
+        f(prepared.entry(pid(conn).unwrap_or(0)).or_insert_with(HashSet::new))
+    }
+}
+
+fn pid(conn: &Connection) -> Option<u32> {
+    conn.cancel_token().map(|token| token.pid())
+}
+
+/// The name a statement is prepared under on the server, shared by every
+/// client which prepares the same query.
+fn statement_name(query: &str, param_types: &[u32]) -> String {
+    let mut hasher = DefaultHasher::new();
+    query.hash(&mut hasher);
+    param_types.hash(&mut hasher);
+    format!("pgproxy_{:016x}", hasher.finish())
+}
+
+/// Something the client is waiting for, in the order its messages were
+/// sent.
+#[derive(Debug)]
+enum Reply {
+    /// The server's reply to a message with identifier `kind`.  Replies to
+    /// the Parse messages the proxy adds aren't forwarded, and `prepares`
+    /// names the statement a Parse creates.
+    Server { kind: u8, forward: bool, prepares: Option<String> },
+    /// An answer made by the proxy, and whether a ReadyForQuery follows it.
+    Local(Vec<u8>, bool),
+}
+
+/// Whether a message from the server is the last one answering a client
+/// message with identifier `kind`.  Errors are handled separately.
+fn ends(kind: u8, id: u8) -> bool {
+    match kind {
+        b'P' => id == b'1',
+        b'B' => id == b'2',
+        b'C' => id == b'3',
+        b'D' => id == b'T' || id == b'n',
+        b'E' => id == b'C' || id == b'I' || id == b's',
+        _ => id == b'Z',
+    }
+}
+
+/// One client's connection to the proxy.
+struct Client<'a> {
+    proxy: &'a Proxy,
+    stream: TcpStream,
+    server: Option<PooledConnection>,
+    /// Messages waiting to be sent to the server.
+    pending: Vec<u8>,
+    /// Messages waiting to be sent to the client, which are flushed before
+    /// reading.
+    output: Vec<u8>,
+    replies: VecDeque<Reply>,
+    /// Whether everything sent to the server has been answered up to a
+    /// ReadyForQuery.
+    ready: bool,
+    /// After an error in an extended query, messages are dropped until the
+    /// next Sync.
+    skip_to_sync: bool,
+    /// In transaction mode, the client's statements by name, with the name
+    /// used on the server and the Parse which creates it.
+    statements: HashMap<String, (String, Vec<u8>)>,
+}
+
+impl <'a> Client<'a> {
+    fn new(proxy: &'a Proxy, stream: TcpStream) -> Client<'a> {
+        Client {
+            proxy: proxy,
+            stream: stream,
+            server: None,
+            pending: vec![],
+            output: vec![],
+            replies: VecDeque::new(),
+            ready: true,
+            skip_to_sync: false,
+            statements: HashMap::new(),
+        }
+    }
+
+    /// Serve the client until it disconnects, then return the server
+    /// connection to the pool, or close it if it was left mid-reply.
+    fn run(mut self) -> Result<()> {
+        let result = self.serve();
+        if let Err(ref err) = result {
+            let code = match *err {
+                PgError::PoolTimeout => "53300",
+                _ => err.code().unwrap_or("08006"),
+            };
+            let mut error = DbError::new(code, &err.to_string());
+            error.severity = "FATAL".to_string();
+            self.output.extend(ServerMsg::ErrorResponse(error.fields()).to_bytes());
+            let _ = self.flush();
+        }
+        if let Some(server) = self.server.take() {
+            if !self.ready || !self.replies.is_empty() {
+                self.proxy.with_prepared(&server, |prepared| prepared.clear());
+                server.discard();
+            }
+        }
+        result
+    }
+
+    fn serve(&mut self) -> Result<()> {
+        loop {
+            let bytes = match self.read() {
+                Ok(bytes) => bytes,
+                Err(PgError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            match bytes[0] {
+                b'Q' => try!(self.query(&bytes)),
+                b'P' | b'B' | b'D' | b'E' | b'C' => try!(self.extended(&bytes)),
+                b'S' => {
+                    self.skip_to_sync = false;
+                    if self.server.is_some() {
+                        try!(self.forward(&bytes, b'S'));
+                    } else {
+                        self.replies.push_back(Reply::Local(vec![], true));
+                    }
+                    try!(self.drain());
+                },
+                b'H' => {
+                    if !self.skip_to_sync && self.server.is_some() {
+                        self.pending.extend(&bytes);
+                    }
+                    try!(self.drain());
+                },
+                b'F' => {
+                    try!(self.forward(&bytes, b'F'));
+                    try!(self.drain());
+                },
+                b'X' => return Ok(()),
+                // Left over from a COPY which failed.
+                b'd' | b'c' | b'f' => {},
+                id => return Err(PgError::Error(format!("Unexpected message from client: {:?}", id as char))),
+            }
+        }
+    }
+
+    fn query(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.proxy.pool_mode == PoolMode::Transaction {
+            if let FrontendMsg::Query(query) = try!(FrontendMsg::from_slice(bytes)) {
+                let keyword = query.query.trim_start().split(|c: char| !c.is_alphabetic()).next().unwrap_or("").to_uppercase();
+                match &keyword[..] {
+                    "PREPARE" | "EXECUTE" | "DEALLOCATE" | "DISCARD" => {
+                        let message = format!("{} is not supported in transaction pooling mode", keyword);
+                        self.replies.push_back(Reply::Local(error("0A000", &message), true));
+                        return self.drain();
+                    },
+                    _ => {},
+                }
+            }
+        }
+        add(&self.proxy.stats.queries, 1);
+        try!(self.forward(bytes, b'Q'));
+        self.drain()
+    }
+
+    /// Queue a message of the extended query protocol.  Nothing is sent
+    /// until the client asks for replies with a Sync or Flush.
+    fn extended(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.skip_to_sync {
+            return Ok(());
+        }
+        if bytes[0] == b'E' {
+            add(&self.proxy.stats.queries, 1);
+        }
+        if self.proxy.pool_mode == PoolMode::Transaction && try!(self.rewrite(bytes)) {
+            return Ok(());
+        }
+        self.forward(bytes, bytes[0])
+    }
+
+    /// In transaction mode, run named statements under names shared by
+    /// every client, preparing them on the server connection as needed.
+    /// Returns false for messages which don't name a statement.
+    fn rewrite(&mut self, bytes: &[u8]) -> Result<bool> {
+        match try!(FrontendMsg::from_slice(bytes)) {
+            FrontendMsg::Parse(ref parse) if !parse.name.is_empty() => {
+                if self.statements.contains_key(parse.name) {
+                    self.fail("42P05", &format!("prepared statement \"{}\" already exists", parse.name));
+                    return Ok(true);
+                }
+                let server_name = statement_name(parse.query, &parse.param_types);
+                let server_parse = Parse {
+                    name: &server_name,
+                    query: parse.query,
+                    param_types: parse.param_types.clone(),
+                }.to_bytes();
+                self.statements.insert(parse.name.to_string(), (server_name.clone(), server_parse));
+                try!(self.prepare(parse.name, true));
+            },
+            FrontendMsg::Bind(ref bind) if !bind.statement.is_empty() => {
+                let server_name = match try!(self.prepare(bind.statement, false)) {
+                    Some(server_name) => server_name,
+                    None => return Ok(true),
+                };
+                let bytes = Bind {
+                    portal: bind.portal,
+                    statement: &server_name,
+                    param_formats: bind.param_formats.clone(),
+                    params: bind.params.clone(),
+                    result_formats: bind.result_formats.clone(),
+                }.to_bytes();
+                try!(self.forward(&bytes, b'B'));
+            },
+            FrontendMsg::Describe(ref describe) if describe.target == Target::Statement && !describe.name.is_empty() => {
+                let server_name = match try!(self.prepare(describe.name, false)) {
+                    Some(server_name) => server_name,
+                    None => return Ok(true),
+                };
+                let bytes = Describe { target: Target::Statement, name: &server_name }.to_bytes();
+                try!(self.forward(&bytes, b'D'));
+            },
+            FrontendMsg::Close(ref close) if close.target == Target::Statement && !close.name.is_empty() => {
+                // The statement stays prepared on the server for others.
+                self.statements.remove(close.name);
+                self.replies.push_back(Reply::Local(ServerMsg::CloseComplete.to_bytes(), false));
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Make sure a statement the client named is prepared on the server
+    /// connection, queueing its Parse if it isn't.  `forward` says whether
+    /// the client is waiting for a ParseComplete.  Returns the statement's
+    /// name on the server, or None if the client never prepared it.
+    fn prepare(&mut self, name: &str, forward: bool) -> Result<Option<String>> {
+        let (server_name, parse) = match self.statements.get(name) {
+            Some(statement) => statement.clone(),
+            None => {
+                self.fail("26000", &format!("prepared statement \"{}\" does not exist", name));
+                return Ok(None);
+            },
+        };
+        let proxy = self.proxy;
+        let server = try!(self.checkout());
+        if proxy.with_prepared(server, |prepared| prepared.insert(server_name.clone())) {
+            self.pending.extend(parse);
+            self.replies.push_back(Reply::Server { kind: b'P', forward: forward, prepares: Some(server_name.clone()) });
+        } else if forward {
+            self.replies.push_back(Reply::Local(ServerMsg::ParseComplete.to_bytes(), false));
+        }
+        Ok(Some(server_name))
+    }
+
+    /// Answer with an error as the server would, skipping the rest of the
+    /// extended query.
+    fn fail(&mut self, code: &str, message: &str) {
+        self.replies.push_back(Reply::Local(error(code, message), false));
+        self.skip_to_sync = true;
+    }
+
+    /// Queue a message for the server, and the reply the client expects.
+    fn forward(&mut self, bytes: &[u8], kind: u8) -> Result<()> {
+        try!(self.checkout());
+        self.pending.extend(bytes);
+        self.replies.push_back(Reply::Server { kind: kind, forward: true, prepares: None });
+        Ok(())
+    }
+
+    /// The client's server connection, checking one out of the pool if it
+    /// has none.
+    fn checkout(&mut self) -> Result<&mut PooledConnection> {
+        if self.server.is_none() {
+            let started = Instant::now();
+            let server = try!(self.proxy.pool.get());
+            let waited = started.elapsed();
+            add(&self.proxy.stats.wait_time, (waited.as_secs() * 1000000) as usize + waited.subsec_micros() as usize);
+            self.server = Some(server);
+        }
+        Ok(self.server.as_mut().unwrap())
+    }
+
+    /// Send what is queued for the server, and pass on its replies until
+    /// the client has every reply it is waiting for.  In transaction mode
+    /// the server connection goes back to the pool once it is idle.
+    fn drain(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            let pending = mem::replace(&mut self.pending, vec![]);
+            try!(try!(self.checkout()).send_message(&pending));
+            self.ready = false;
+        }
+        while let Some(reply) = self.replies.pop_front() {
+            match reply {
+                Reply::Local(bytes, ready) => {
+                    self.output.extend(bytes);
+                    if ready {
+                        let status = self.server.as_ref().map_or(TransactionStatus::Idle, |server| server.transaction_status());
+                        self.output.extend(ServerMsg::ReadyForQuery(status).to_bytes());
+                    }
+                },
+                Reply::Server { kind, forward, prepares } => try!(self.server_reply(kind, forward, prepares)),
+            }
+        }
+        try!(self.flush());
+        if self.proxy.pool_mode == PoolMode::Transaction && self.ready && !self.skip_to_sync {
+            if self.server.as_ref().map_or(false, |server| server.transaction_status() == TransactionStatus::Idle) {
+                self.server = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn server_reply(&mut self, kind: u8, forward: bool, prepares: Option<String>) -> Result<()> {
+        loop {
+            let message = try!(self.server.as_mut().unwrap().read_message());
+            match message[0] {
+                b'E' if !ends(kind, b'Z') => {
+                    self.output.extend(&message);
+                    if let Some(name) = prepares {
+                        if forward {
+                            self.statements.retain(|_, statement| statement.0 != name);
+                        }
+                        self.unprepare(&name);
+                    }
+                    self.skip_failed();
+                    return Ok(());
+                },
+                b'N' | b'A' | b'S' => self.output.extend(&message),
+                b'G' => {
+                    self.output.extend(&message);
+                    try!(self.copy_in());
+                },
+                id => {
+                    if id == b'Z' {
+                        self.ready = true;
+                        if self.server.as_ref().unwrap().transaction_status() == TransactionStatus::Idle {
+                            add(&self.proxy.stats.transactions, 1);
+                        }
+                    }
+                    if forward {
+                        self.output.extend(&message);
+                    }
+                    if ends(kind, id) {
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /// After an error the server skips the rest of an extended query, so
+    /// drop the replies expected up to its Sync.
+    fn skip_failed(&mut self) {
+        while let Some(reply) = self.replies.pop_front() {
+            match reply {
+                Reply::Server { kind: b'S', .. } => {
+                    self.replies.push_front(reply);
+                    return;
+                },
+                Reply::Server { prepares: Some(name), .. } => self.unprepare(&name),
+                _ => {},
+            }
+        }
+        self.skip_to_sync = true;
+    }
+
+    fn unprepare(&self, name: &str) {
+        if let Some(ref server) = self.server {
+            self.proxy.with_prepared(server, |prepared| prepared.remove(name));
+        }
+    }
+
+    /// Pass the client's data on to the server until it finishes or
+    /// abandons the copy.
+    fn copy_in(&mut self) -> Result<()> {
+        try!(self.flush());
+        loop {
+            let bytes = try!(self.read());
+            try!(self.server.as_mut().unwrap().send_message(&bytes));
+            match bytes[0] {
+                b'c' | b'f' => return Ok(()),
+                b'd' | b'H' | b'S' => {},
+                id => return Err(PgError::Error(format!("Unexpected message from client during COPY: {:?}", id as char))),
+            }
+        }
+    }
+
+    fn read(&mut self) -> Result<Vec<u8>> {
+        try!(self.flush());
+        let bytes = try!(read_frontend_message(&mut self.stream, false));
+        add(&self.proxy.stats.received, bytes.len());
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.output.is_empty() {
+            try!(self.stream.write_all(&self.output));
+            add(&self.proxy.stats.sent, self.output.len());
+            self.output.clear();
+        }
+        Ok(())
+    }
+}
+
+fn error(code: &str, message: &str) -> Vec<u8> {
+    ServerMsg::ErrorResponse(DbError::new(code, message).fields()).to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use pg::{Config, Connection, TransactionStatus};
+    use pg::error::DbError;
+    use pg::message::*;
+    use pg::server::Description;
+    use pg::servermsg::ServerMsg;
+    use super::*;
+
+    fn args(args: &[&str]) -> std::result::Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// The server behind the proxy.  It keeps each session's
+    /// application_name, and knows a few statements, some with parameters.
+    struct Upstream {
+        application_names: Mutex<HashMap<u32, String>>,
+    }
+
+    impl QueryHandler for Upstream {
+        fn query(&self, session: &Session, sql: &str) -> Result<Vec<QueryResult>> {
+            let mut results = vec![];
+            for statement in sql.split(';').map(str::trim).filter(|statement| !statement.is_empty()) {
+                results.push(try!(self.execute(session, statement, &[])));
+            }
+            Ok(results)
+        }
+
+        fn describe(&self, _session: &Session, sql: &str, param_types: &[u32]) -> Result<Description> {
+            match sql {
+                "SELECT nonsense" => Err(nonsense()),
+                _ => Ok(Description { params: param_types.to_vec(), columns: None }),
+            }
+        }
+
+        fn execute(&self, session: &Session, sql: &str, params: &[Option<String>]) -> Result<QueryResult> {
+            let mut application_names = self.application_names.lock().unwrap();
+            let param = || params.get(0).and_then(|param| param.as_ref()).and_then(|param| param.parse::<i64>().ok());
+            let value = match sql {
+                "BEGIN" | "COMMIT" | "ROLLBACK" => return Ok(QueryResult::Command(sql.to_string())),
+                "DISCARD ALL" => {
+                    application_names.remove(&session.pid());
+                    return Ok(QueryResult::Command(sql.to_string()));
+                },
+                "SHOW application_name" => application_names.get(&session.pid()).cloned().unwrap_or_default(),
+                "SELECT pg_backend_pid()" => session.pid().to_string(),
+                "SELECT $1::int + 1" => (param().unwrap_or(0) + 1).to_string(),
+                "SELECT $1::int * 2" => (param().unwrap_or(0) * 2).to_string(),
+                "SELECT nonsense" => return Err(nonsense()),
+                _ if sql.starts_with("SET application_name = ") => {
+                    let name = sql["SET application_name = ".len()..].trim_matches('\'');
+                    application_names.insert(session.pid(), name.to_string());
+                    return Ok(QueryResult::Command("SET".to_string()));
+                },
+                _ if sql.starts_with("SELECT ") && sql[7..].parse::<i64>().is_ok() => sql[7..].to_string(),
+                _ => return Err(PgError::Db(DbError::new("42601", &format!("syntax error in {}", sql)))),
+            };
+            Ok(QueryResult::Rows(vec![Column::text("?column?")], vec![vec![Some(value)]]))
+        }
+    }
+
+    fn nonsense() -> PgError {
+        PgError::Db(DbError::new("42703", "column \"nonsense\" does not exist"))
+    }
+
+    /// Start a proxy in front of its own upstream server.
+    fn start(pool_mode: &str, pool_size: &str) -> u16 {
+        let upstream = Server::new(Arc::new(Upstream { application_names: Mutex::new(HashMap::new()) }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_port = listener.local_addr().unwrap().port().to_string();
+        thread::spawn(move || upstream.serve(listener));
+
+        let options = args(&[
+            "--user", "cliff",
+            "--port", &upstream_port,
+            "--pool-mode", pool_mode,
+            "--pool-size", pool_size,
+        ]).unwrap();
+        let proxy = Proxy::new(&options).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || proxy.serve(listener));
+        port
+    }
+
+    fn connect(port: u16, database: &str) -> Connection {
+        let config = Config::new("cliff").host("127.0.0.1").port(port).database(database);
+        Connection::connect(&config).unwrap()
+    }
+
+    fn startup(port: u16) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&StartupMessage { user: "cliff", database: None, params: vec![] }.to_bytes()).unwrap();
+        replies(&mut stream);
+        stream
+    }
+
+    /// Send messages and read the replies up to the next ReadyForQuery.
+    fn exchange(stream: &mut TcpStream, messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        stream.write_all(&messages.concat()).unwrap();
+        replies(stream)
+    }
+
+    fn replies(stream: &mut TcpStream) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        loop {
+            let mut bytes = vec![0; 5];
+            stream.read_exact(&mut bytes).unwrap();
+            let length = bytes[1..].iter().fold(0, |length, &byte| length << 8 | byte as usize);
+            bytes.resize(length + 1, 0);
+            stream.read_exact(&mut bytes[5..]).unwrap();
+            let id = bytes[0];
+            frames.push(bytes);
+            if id == b'Z' {
+                return frames;
+            }
+        }
+    }
+
+    fn ids(frames: &[Vec<u8>]) -> Vec<u8> {
+        frames.iter().map(|frame| frame[0]).collect()
+    }
+
+    fn error_code(frame: &[u8]) -> String {
+        match ServerMsg::from_slice(frame).unwrap() {
+            ServerMsg::ErrorResponse(fields) => DbError::from_fields(&fields).code,
+            msg => panic!("Expected an error, got {:?}", msg),
+        }
+    }
+
+    fn parse(name: &str, query: &str) -> Vec<u8> {
+        Parse { name: name, query: query, param_types: vec![] }.to_bytes()
+    }
+
+    fn bind(statement: &str, params: Vec<Option<&[u8]>>) -> Vec<u8> {
+        Bind { portal: "", statement: statement, param_formats: vec![], params: params, result_formats: vec![] }.to_bytes()
+    }
+
+    fn execute() -> Vec<u8> {
+        Execute { portal: "", max_rows: 0 }.to_bytes()
+    }
+
+    #[test]
+    fn test_options() {
+        assert!(args(&[]).is_err());
+        assert!(args(&["--user"]).is_err());
+        assert!(args(&["--user", "cliff", "--pool-size", "many"]).is_err());
+        assert!(args(&["--user", "cliff", "--pool-mode", "statement"]).is_err());
+        assert!(args(&["--user", "cliff", "--auth", "md5"]).is_err());
+        assert!(args(&["--user", "cliff", "--verbose", "yes"]).is_err());
+        let options = args(&["--user", "cliff", "--pool-mode", "transaction", "--port", "5433"]).unwrap();
+        assert_eq!(options.pool_mode, PoolMode::Transaction);
+        assert_eq!(options.port, 5433);
+        assert_eq!(options.listen, "127.0.0.1:6432");
+    }
+
+    #[test]
+    fn test_session_mode() {
+        let port = start("session", "1");
+        let pid = {
+            let mut conn = connect(port, "cliff");
+            conn.query("SET application_name = 'proxy test'").unwrap();
+            assert_eq!(conn.query("SHOW application_name").unwrap(), vec![vec!["proxy test".to_string()]]);
+            conn.query("SELECT pg_backend_pid()").unwrap()
+        };
+        // The next client gets the same server connection, reset.
+        let mut conn = connect(port, "cliff");
+        assert_eq!(conn.query("SELECT pg_backend_pid()").unwrap(), pid);
+        assert_eq!(conn.query("SHOW application_name").unwrap(), vec![vec!["".to_string()]]);
+        assert!(conn.parameter("server_version").is_some());
+    }
+
+    #[test]
+    fn test_transaction_mode() {
+        let port = start("transaction", "1");
+        let mut first = connect(port, "cliff");
+        let mut second = connect(port, "cliff");
+        let pid = first.query("SELECT pg_backend_pid()").unwrap();
+        assert_eq!(second.query("SELECT pg_backend_pid()").unwrap(), pid);
+
+        first.query("BEGIN").unwrap();
+        assert!(first.query("SELECT nonsense").is_err());
+        assert_eq!(first.transaction_status(), TransactionStatus::Failed);
+        // The second client waits until the first transaction ends.
+        let handle = thread::spawn(move || second.query("SELECT 2").unwrap());
+        thread::sleep(Duration::from_millis(100));
+        first.query("ROLLBACK").unwrap();
+        assert_eq!(first.transaction_status(), TransactionStatus::Idle);
+        assert_eq!(handle.join().unwrap(), vec![vec!["2".to_string()]]);
+
+        match first.query("PREPARE two AS SELECT 2") {
+            Err(err) => assert_eq!(err.code(), Some("0A000")),
+            Ok(_) => panic!("Expected PREPARE to be refused"),
+        }
+        assert!(connect(port, "cliff").query("SELECT 1").is_ok());
+        let config = Config::new("cliff").host("127.0.0.1").port(port).database("elsewhere");
+        assert!(Connection::connect(&config).and_then(|mut conn| conn.query("SELECT 1")).is_err());
+    }
+
+    #[test]
+    fn test_prepared_statements() {
+        let port = start("transaction", "2");
+        let mut first = startup(port);
+        let mut second = startup(port);
+
+        let frames = exchange(&mut first, &[parse("add", "SELECT $1::int + 1"), bind("add", vec![Some(b"41")]), execute(), Sync.to_bytes()]);
+        assert_eq!(ids(&frames), b"12DCZ".to_vec());
+        assert!(frames[2].ends_with(b"42"));
+
+        // Holding the first server connection, so the second client gets
+        // the other one to prepare on.
+        assert_eq!(ids(&exchange(&mut first, &[Query { query: "BEGIN".to_string() }.to_bytes()])), b"CZ".to_vec());
+        assert_eq!(ids(&exchange(&mut second, &[parse("double", "SELECT $1::int * 2"), Sync.to_bytes()])), b"1Z".to_vec());
+        assert_eq!(ids(&exchange(&mut first, &[Query { query: "COMMIT".to_string() }.to_bytes()])), b"CZ".to_vec());
+        // Now the second client is given the first connection.  Its first
+        // statement is prepared there by the first client's name, and the
+        // other is prepared again without the client seeing it.
+        let frames = exchange(&mut second, &[
+            parse("add", "SELECT $1::int + 1"),
+            bind("add", vec![Some(b"1")]),
+            execute(),
+            bind("double", vec![Some(b"21")]),
+            execute(),
+            Sync.to_bytes(),
+        ]);
+        assert_eq!(ids(&frames), b"12DC2DCZ".to_vec());
+        assert!(frames[2].ends_with(b"2"));
+        assert!(frames[5].ends_with(b"42"));
+        let frames = exchange(&mut second, &[parse("double", "SELECT 1"), Sync.to_bytes()]);
+        assert_eq!(error_code(&frames[0]), "42P05");
+
+        // A Parse the server refuses leaves no statement behind, and
+        // replies queued after an error are skipped.
+        let frames = exchange(&mut first, &[parse("bad", "SELECT nonsense"), bind("bad", vec![]), execute(), Sync.to_bytes()]);
+        assert_eq!(ids(&frames), b"EZ".to_vec());
+        assert_eq!(error_code(&frames[0]), "42703");
+        assert_eq!(ids(&exchange(&mut first, &[parse("bad", "SELECT 1"), Sync.to_bytes()])), b"1Z".to_vec());
+
+        let close = Close { target: Target::Statement, name: "add" }.to_bytes();
+        assert_eq!(ids(&exchange(&mut first, &[close, Sync.to_bytes()])), b"3Z".to_vec());
+        let frames = exchange(&mut first, &[bind("add", vec![Some(b"1")]), execute(), Sync.to_bytes()]);
+        assert_eq!(error_code(&frames[0]), "26000");
+        assert_eq!(ids(&frames), b"EZ".to_vec());
+    }
+
+    #[test]
+    fn test_admin_console() {
+        let port = start("transaction", "1");
+        connect(port, "cliff").query("SELECT 1").unwrap();
+        let mut admin = connect(port, ADMIN_DATABASE);
+        let stats = admin.query("show stats;").unwrap();
+        assert_eq!(stats[0][0], "cliff");
+        assert!(stats[0][2].parse::<u64>().unwrap() >= 1);
+        assert_eq!(stats[0][8], "1");
+        assert!(admin.query("SHOW TABLES").is_err());
+    }
+}
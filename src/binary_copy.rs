@@ -0,0 +1,321 @@
+use std::io;
+use std::io::{Read, Write};
+use Result;
+use error::PgError;
+use types::{FromSql, IsNull, ToSql};
+
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+const HAS_OIDS: i32 = 1 << 16;
+
+/// Writes rows in the binary COPY format.
+///
+/// Wrap the writer returned by `Connection::copy_in` for a
+/// `COPY ... FROM STDIN WITH (FORMAT binary)` statement.  `finish` writes the
+/// trailer and hands back the inner writer, which must then be finished too.
+pub struct BinaryCopyWriter<W: Write> {
+    writer: W,
+    header_written: bool,
+    buf: Vec<u8>,
+}
+
+impl <W: Write> BinaryCopyWriter<W> {
+    pub fn new(writer: W) -> BinaryCopyWriter<W> {
+        BinaryCopyWriter {
+            writer: writer,
+            header_written: false,
+            buf: Vec::with_capacity(1024),
+        }
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if !self.header_written {
+            try!(self.writer.write_all(SIGNATURE));
+            try!(self.writer.write_all(&0i32.to_be_bytes()));  // flags
+            try!(self.writer.write_all(&0i32.to_be_bytes()));  // header extension length
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Write one row.  Every row must have a value for each copied column.
+    pub fn write_row(&mut self, values: &[&dyn ToSql]) -> Result<()> {
+        try!(self.write_header());
+        if values.len() > i16::max_value() as usize {
+            return Err(PgError::Error(format!("Too many fields in row: {}", values.len())));
+        }
+        self.buf.clear();
+        self.buf.extend(&(values.len() as i16).to_be_bytes());
+        for value in values {
+            let start = self.buf.len();
+            self.buf.extend(&[0; 4]);
+            let length = match try!(value.to_sql(&mut self.buf)) {
+                IsNull::Yes => -1,
+                IsNull::No => {
+                    let length = self.buf.len() - start - 4;
+                    if length > i32::max_value() as usize {
+                        return Err(PgError::Error(format!("Field too large: {} bytes", length)));
+                    }
+                    length as i32
+                },
+            };
+            self.buf[start..start + 4].copy_from_slice(&length.to_be_bytes());
+        }
+        try!(self.writer.write_all(&self.buf));
+        Ok(())
+    }
+
+    /// Write the trailer and return the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        try!(self.write_header());
+        try!(self.writer.write_all(&(-1i16).to_be_bytes()));
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
+}
+
+/// Reads rows in the binary COPY format.
+///
+/// Wrap the reader returned by `Connection::copy_out` for a
+/// `COPY ... TO STDOUT WITH (FORMAT binary)` statement.
+pub struct BinaryCopyReader<R: Read> {
+    reader: R,
+    has_oids: bool,
+    done: bool,
+}
+
+impl <R: Read> BinaryCopyReader<R> {
+    /// Read and validate the header.
+    pub fn new(mut reader: R) -> Result<BinaryCopyReader<R>> {
+        let mut signature = [0; 11];
+        try!(reader.read_exact(&mut signature));
+        if &signature[..] != SIGNATURE {
+            return Err(PgError::Error(format!("Invalid binary copy signature: {:?}", signature)));
+        }
+        let flags = try!(read_i32(&mut reader));
+        if flags & !HAS_OIDS & !0xffff != 0 {
+            return Err(PgError::Error(format!("Unsupported binary copy flags: {:#x}", flags)));
+        }
+        let extension_length = try!(read_i32(&mut reader));
+        if extension_length < 0 {
+            return Err(PgError::Error(format!("Invalid header extension length: {}", extension_length)));
+        }
+        let skipped = try!(io::copy(&mut reader.by_ref().take(extension_length as u64), &mut io::sink()));
+        if skipped != extension_length as u64 {
+            return Err(PgError::Error("Truncated binary copy header".to_string()));
+        }
+        Ok(BinaryCopyReader {
+            reader: reader,
+            has_oids: flags & HAS_OIDS != 0,
+            done: false,
+        })
+    }
+
+    /// Read the next row, or `None` once the trailer has been read.
+    pub fn next_row(&mut self) -> Result<Option<BinaryCopyRow>> {
+        if self.done {
+            return Ok(None);
+        }
+        let field_count = try!(read_i16(&mut self.reader));
+        if field_count == -1 {
+            self.done = true;
+            return Ok(None);
+        } else if field_count < 0 {
+            return Err(PgError::Error(format!("Invalid field count: {}", field_count)));
+        }
+        if self.has_oids {
+            // The OID is sent before the fields, and not included in the count.
+            try!(self.read_field());
+        }
+        let mut fields = Vec::with_capacity(field_count as usize);
+        for _ in 0..field_count {
+            fields.push(try!(self.read_field()));
+        }
+        Ok(Some(BinaryCopyRow { fields: fields }))
+    }
+
+    fn read_field(&mut self) -> Result<Option<Vec<u8>>> {
+        let length = try!(read_i32(&mut self.reader));
+        if length == -1 {
+            return Ok(None);
+        } else if length < 0 {
+            return Err(PgError::Error(format!("Invalid field length: {}", length)));
+        }
+        let mut field = vec![];
+        try!(self.reader.by_ref().take(length as u64).read_to_end(&mut field));
+        if field.len() != length as usize {
+            return Err(PgError::Error("Truncated binary copy field".to_string()));
+        }
+        Ok(Some(field))
+    }
+
+    /// Return the inner reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+fn read_i16<R: Read>(reader: &mut R) -> Result<i16> {
+    let mut bytes = [0; 2];
+    try!(reader.read_exact(&mut bytes));
+    Ok(i16::from_be_bytes(bytes))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> Result<i32> {
+    let mut bytes = [0; 4];
+    try!(reader.read_exact(&mut bytes));
+    Ok(i32::from_be_bytes(bytes))
+}
+
+/// A row read from binary COPY data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BinaryCopyRow {
+    fields: Vec<Option<Vec<u8>>>,
+}
+
+impl BinaryCopyRow {
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Decode the field at `index`.
+    pub fn get<T: FromSql>(&self, index: usize) -> Result<T> {
+        match self.fields.get(index) {
+            Some(field) => T::from_sql_nullable(field.as_ref().map(|field| &field[..])),
+            None => Err(PgError::Error(format!("No field at index {}", index))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connection::Connection;
+    use servermsg::FieldFormat;
+    use testing::{MockBackend, Received, Response};
+    use types::ToSql;
+
+    #[test]
+    fn test_write_rows() {
+        let mut writer = BinaryCopyWriter::new(vec![]);
+        writer.write_row(&[&1i32, &"a"]).unwrap();
+        writer.write_row(&[&None::<i32>, &"bc"]).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            bytes,
+            b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0\
+              \0\x02\0\0\0\x04\0\0\0\x01\0\0\0\x01a\
+              \0\x02\xff\xff\xff\xff\0\0\0\x02bc\
+              \xff\xff".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_empty_copy() {
+        let bytes = BinaryCopyWriter::new(vec![]).finish().unwrap();
+        let mut reader = BinaryCopyReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.next_row().unwrap(), None);
+        assert_eq!(reader.next_row().unwrap(), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = BinaryCopyWriter::new(vec![]);
+        writer.write_row(&[&42i64, &0.1f64, &vec![0u8, 1, 255], &Some("text")]).unwrap();
+        writer.write_row(&[&-1i64, &-0.0f64, &vec![], &None::<String>]).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut reader = BinaryCopyReader::new(&bytes[..]).unwrap();
+        let row = reader.next_row().unwrap().unwrap();
+        assert_eq!(row.len(), 4);
+        assert_eq!(row.get::<i64>(0).unwrap(), 42);
+        assert_eq!(row.get::<f64>(1).unwrap(), 0.1);
+        assert_eq!(row.get::<Vec<u8>>(2).unwrap(), vec![0, 1, 255]);
+        assert_eq!(row.get::<Option<String>>(3).unwrap(), Some("text".to_string()));
+        assert!(row.get::<String>(4).is_err());
+
+        let row = reader.next_row().unwrap().unwrap();
+        assert_eq!(row.get::<i64>(0).unwrap(), -1);
+        assert_eq!(row.get::<Vec<u8>>(2).unwrap(), vec![]);
+        assert_eq!(row.get::<Option<String>>(3).unwrap(), None);
+        assert!(row.get::<String>(3).is_err());
+
+        assert_eq!(reader.next_row().unwrap(), None);
+    }
+
+    #[test]
+    fn test_header_extension_and_oids() {
+        let bytes = b"PGCOPY\n\xff\r\n\0\0\x01\0\0\0\0\0\x03xyz\
+                      \0\x01\0\0\0\x04\0\0\x30\x39\0\0\0\x02hi\
+                      \xff\xff";
+        let mut reader = BinaryCopyReader::new(&bytes[..]).unwrap();
+        let row = reader.next_row().unwrap().unwrap();
+        assert_eq!(row.len(), 1);
+        assert_eq!(row.get::<String>(0).unwrap(), "hi");
+        assert_eq!(reader.next_row().unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(BinaryCopyReader::new(&b"PGCOPY\n\xff\r\n\x01\0\0\0\0\0\0\0"[..]).is_err());
+        assert!(BinaryCopyReader::new(&b"PGCOPY\n\xff\r\n\0\0\x02\0\0\0\0\0\0"[..]).is_err());
+        assert!(BinaryCopyReader::new(&b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\x05ab"[..]).is_err());
+
+        let truncated = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0\0\x01\0\0\0\x04\0\0";
+        let mut reader = BinaryCopyReader::new(&truncated[..]).unwrap();
+        assert!(reader.next_row().is_err());
+    }
+
+    #[test]
+    fn test_binary_copy_with_server() {
+        fn write_samples<W: Write>(writer: &mut BinaryCopyWriter<W>) {
+            for id in 0..1000i32 {
+                let label = format!("sample {}", id);
+                let note: Option<&str> = if id % 2 == 0 { Some("even") } else { None };
+                let values: [&dyn ToSql; 5] = [&id, &label, &(id as f64 / 3.0), &vec![id as u8, 0, 255], &note];
+                writer.write_row(&values).unwrap();
+            }
+        }
+        let mut samples = BinaryCopyWriter::new(vec![]);
+        write_samples(&mut samples);
+        let samples = samples.finish().unwrap();
+        let server = MockBackend::new()
+            .on_query("COPY samples FROM STDIN WITH (FORMAT binary);", Response::CopyIn(FieldFormat::Binary))
+            .on_query(
+                "COPY (SELECT * FROM samples ORDER BY id) TO STDOUT WITH (FORMAT binary);",
+                Response::CopyOut(FieldFormat::Binary, vec![samples.clone()]),
+            )
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        let count = {
+            let copy_in = conn.copy_in("COPY samples FROM STDIN WITH (FORMAT binary);").unwrap();
+            let mut writer = BinaryCopyWriter::new(copy_in);
+            write_samples(&mut writer);
+            writer.finish().unwrap().finish().unwrap()
+        };
+        assert_eq!(count, 1000);
+        let received: Vec<u8> = server.received().into_iter().filter_map(|msg| match msg {
+            Received::CopyData(data) => Some(data),
+            _ => None,
+        }).flat_map(|data| data).collect();
+        assert_eq!(received, samples);
+
+        let copy_out = conn.copy_out("COPY (SELECT * FROM samples ORDER BY id) TO STDOUT WITH (FORMAT binary);").unwrap();
+        let mut reader = BinaryCopyReader::new(copy_out).unwrap();
+        let mut id = 0;
+        while let Some(row) = reader.next_row().unwrap() {
+            assert_eq!(row.get::<i32>(0).unwrap(), id);
+            assert_eq!(row.get::<String>(1).unwrap(), format!("sample {}", id));
+            assert_eq!(row.get::<f64>(2).unwrap(), id as f64 / 3.0);
+            assert_eq!(row.get::<Vec<u8>>(3).unwrap(), vec![id as u8, 0, 255]);
+            assert_eq!(row.get::<Option<String>>(4).unwrap().is_some(), id % 2 == 0);
+            id += 1;
+        }
+        assert_eq!(id, 1000);
+        assert_eq!(reader.into_inner().finish().unwrap(), 1000);
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net;
+use Result;
+use error::PgError;
+use message::{Message, CancelRequest, SslRequest};
+
+/// A handle for cancelling the query currently running on a `Connection`.
+///
+/// The token holds the backend process id and secret key sent by the server
+/// during startup.  Cancelling opens a new socket to the server, so a token
+/// can be cloned and sent to another thread while the connection is busy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CancelToken {
+    host: String,
+    port: u16,
+    pid: u32,
+    key: Vec<u8>,
+}
+
+impl CancelToken {
+    pub fn new(host: &str, port: u16, pid: u32, key: &[u8]) -> CancelToken {
+        CancelToken {
+            host: host.to_string(),
+            port: port,
+            pid: pid,
+            key: key.to_vec(),
+        }
+    }
+
+    /// The process id of the backend serving the connection.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Send a cancel request over a plain TCP socket.
+    pub fn cancel(&self) -> Result<()> {
+        let mut socket = try!(net::TcpStream::connect((self.host.as_str(), self.port)));
+        self.send_cancel(&mut socket)
+    }
+
+    /// Send a cancel request over TLS.
+    ///
+    /// The token negotiates SSL with the server, then hands the socket to
+    /// `handshake`, which should wrap it in the TLS stream of your choice.
+    pub fn cancel_tls<F, S>(&self, handshake: F) -> Result<()>
+        where F: FnOnce(net::TcpStream) -> io::Result<S>, S: Write
+    {
+        let mut socket = try!(net::TcpStream::connect((self.host.as_str(), self.port)));
+        try!(socket.write_all(&SslRequest.to_bytes()));
+        let mut response = [0; 1];
+        try!(socket.read_exact(&mut response));
+        match response[0] {
+            b'S' => {
+                let mut stream = try!(handshake(socket));
+                self.send_cancel(&mut stream)
+            },
+            b'N' => Err(PgError::Error("Server does not support SSL".to_string())),
+            other => Err(PgError::Error(format!("Unexpected response to SSL request: {:?}", other))),
+        }
+    }
+
+    fn send_cancel<W: Write>(&self, stream: &mut W) -> Result<()> {
+        let msg = CancelRequest { pid: self.pid, key: &self.key };
+        try!(stream.write_all(&msg.to_bytes()));
+        try!(stream.flush());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use super::CancelToken;
+
+    fn listen() -> (net::TcpListener, u16) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[test]
+    fn test_token_is_send_and_clone() {
+        fn assert_send_clone<T: Send + Clone>() {}
+        assert_send_clone::<CancelToken>();
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (listener, port) = listen();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut received = vec![];
+            socket.read_to_end(&mut received).unwrap();
+            received
+        });
+        let token = CancelToken::new("127.0.0.1", port, 0x17bb, b"\x15b\xfb1");
+        token.clone().cancel().unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            b"\0\0\0\x10\x04\xd2\x16\x2e\0\0\x17\xbb\x15b\xfb1".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_cancel_tls() {
+        let (listener, port) = listen();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut ssl_request = [0; 8];
+            socket.read_exact(&mut ssl_request).unwrap();
+            assert_eq!(&ssl_request, b"\0\0\0\x08\x04\xd2\x16\x2f");
+            socket.write_all(b"S").unwrap();
+            let mut received = vec![];
+            socket.read_to_end(&mut received).unwrap();
+            received
+        });
+        let key = [9u8; 32];
+        let token = CancelToken::new("127.0.0.1", port, 42, &key);
+        token.cancel_tls(|socket| Ok(socket)).unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(received.len(), 44);
+        assert_eq!(&received[12..], &key[..]);
+    }
+
+    #[test]
+    fn test_cancel_tls_refused() {
+        let (listener, port) = listen();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut ssl_request = [0; 8];
+            socket.read_exact(&mut ssl_request).unwrap();
+            socket.write_all(b"N").unwrap();
+        });
+        let token = CancelToken::new("127.0.0.1", port, 42, b"abcd");
+        assert!(token.cancel_tls(|socket| Ok(socket)).is_err());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::sync::Arc;
+use Result;
+use connection::Connection;
+use metrics::{Hooks, Instrumentation};
+use secret::Secret;
+
+/// Connection settings, built up with chained setters.
+#[derive(Clone)]
+pub struct Config {
+    pub(crate) user: String,
+    password: Option<Password>,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) database: Option<String>,
+    pub(crate) max_message_size: usize,
+    pub(crate) max_result_size: Option<usize>,
+    pub(crate) hooks: Hooks,
+}
+
+impl Config {
+    /// Settings for connecting as `user` to the database of the same name
+    /// on localhost, port 5432.
+    pub fn new(user: &str) -> Config {
+        Config {
+            user: user.to_string(),
+            password: None,
+            host: "localhost".to_string(),
+            port: 5432,
+            database: None,
+            max_message_size: 1 << 30,
+            max_result_size: None,
+            hooks: Hooks::none(),
+        }
+    }
+
+    pub fn password<S: Into<Secret>>(mut self, password: S) -> Config {
+        self.password = Some(Password::Fixed(password.into()));
+        self
+    }
+
+    /// Call `provider` for the password each time a connection is opened,
+    /// instead of keeping one in the config.  Suits short-lived tokens.
+    pub fn password_provider<F>(mut self, provider: F) -> Config
+        where F: Fn() -> Result<Secret> + Send + Sync + 'static
+    {
+        self.password = Some(Password::Provider(Arc::new(provider)));
+        self
+    }
+
+    pub fn host(mut self, host: &str) -> Config {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Config {
+        self.port = port;
+        self
+    }
+
+    pub fn database(mut self, database: &str) -> Config {
+        self.database = Some(database.to_string());
+        self
+    }
+
+    /// Refuse messages from the server larger than `size` bytes.  A corrupt
+    /// length would otherwise have us buffer whatever follows it.  The
+    /// default is 1GB.
+    pub fn max_message_size(mut self, size: usize) -> Config {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Limit the total size of the values `query` collects.  Unlimited by
+    /// default.
+    pub fn max_result_size(mut self, size: Option<usize>) -> Config {
+        self.max_result_size = size;
+        self
+    }
+
+    /// Report connection and query metrics to `instrumentation`.
+    pub fn instrumentation(mut self, instrumentation: Arc<dyn Instrumentation>) -> Config {
+        self.hooks = Hooks::new(instrumentation);
+        self
+    }
+
+    pub fn connect(&self) -> Result<Connection> {
+        Connection::connect(self)
+    }
+
+    /// The password for a new connection.
+    pub(crate) fn fetch_password(&self) -> Result<Option<Secret>> {
+        match self.password {
+            Some(Password::Fixed(ref secret)) => Ok(Some(secret.clone())),
+            Some(Password::Provider(ref provider)) => provider().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Password {
+    Fixed(Secret),
+    Provider(Arc<dyn Fn() -> Result<Secret> + Send + Sync>),
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Password::Fixed(ref secret) => write!(f, "{:?}", secret),
+            Password::Provider(_) => write!(f, "Provider(..)"),
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("user", &self.user)
+            .field("password", &self.password)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("database", &self.database)
+            .field("max_message_size", &self.max_message_size)
+            .field("max_result_size", &self.max_result_size)
+            .field("hooks", &self.hooks)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use error::PgError;
+    use secret::Secret;
+    use testing::{Auth, MockBackend, Received, Response};
+    use super::Config;
+
+    #[test]
+    fn test_debug_hides_password() {
+        let config = Config::new("cliff").password("open sesame");
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("cliff"));
+        assert!(!debug.contains("open sesame"));
+        let debug = format!("{:?}", Config::new("cliff").password_provider(|| Ok(Secret::new("open sesame"))));
+        assert!(debug.contains("Provider"));
+        assert!(!debug.contains("open sesame"));
+    }
+
+    #[test]
+    fn test_password_provider() {
+        let server = MockBackend::new().auth(Auth::Md5("token".to_string())).start().unwrap();
+        let path = env::temp_dir().join(format!("pg-test-token-{}", ::std::process::id()));
+        fs::File::create(&path).unwrap().write_all(b"token").unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let token_file = path.clone();
+        let config = server.config("cliff")
+            .password_provider(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let token = try!(fs::read_to_string(&token_file).map_err(PgError::Io));
+                Ok(Secret::from(token))
+            });
+        config.connect().unwrap();
+        config.connect().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        fs::remove_file(&path).unwrap();
+        match config.connect() {
+            Err(PgError::Io(_)) => {},
+            other => panic!("Expected the provider's error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = MockBackend::new()
+            .auth(Auth::Md5("open sesame".to_string()))
+            .on_query("SELECT current_user;", Response::rows(&["current_user"], &[&["cliff"]]))
+            .start()
+            .unwrap();
+        let mut conn = Config::new("cliff")
+            .password("open sesame")
+            .host("127.0.0.1")
+            .port(server.port())
+            .database("app")
+            .connect()
+            .expect("Could not establish connection");
+        assert_eq!(conn.query("SELECT current_user;").unwrap(), vec![vec!["cliff".to_string()]]);
+        assert_eq!(server.received()[0], Received::Startup {
+            user: "cliff".to_string(),
+            database: Some("app".to_string()),
+            params: vec![],
+        });
+    }
+}
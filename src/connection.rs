@@ -1,268 +1,1388 @@
+use std::collections::HashMap;
 use std::collections::vec_deque::VecDeque;
+use std::fmt;
+use std::io;
 use std::io::{Read, Write};
 use std::net;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::Span;
 use Result;
-use auth;
+use cancel::CancelToken;
+use config::Config;
 use error::PgError;
-use message::{Message, StartupMessage, Query, PasswordMessage, Terminate};
-use servermsg::{take_msg, ServerMsg, AuthMsg};
-
-#[derive(Copy, Debug, Eq, PartialEq, Clone)]
-enum ConnectionState {
-    New, 
-    AwaitingAuthResponse, 
-    Authenticated,
-    AuthenticationRejected,
-    ReadyForQuery,
-    AwaitingQueryResponse,
-    AwaitingDataRows,
-    Disconnected,
-}
-    
+use metrics::{ConnectEvent, Hooks, QueryEvent, connect_span, copy_span, query_span};
+use protocol::{ConnectionState, Event, Protocol};
+use servermsg::{FieldFormat, TransactionStatus};
+use transport::Transport;
 
-#[derive(Debug)]
 pub struct Connection {
-    user: String,
-    database: String,
-    password: Option<String>,
-    host: String,
-    port: u16,
-    socket: net::TcpStream,
-    state: ConnectionState,
+    /// Where cancel requests go, if the server's address is known.
+    cancel_address: Option<(String, u16)>,
+    socket: Box<dyn Transport>,
+    protocol: Protocol,
+    statement_timeout: Option<Duration>,
+    notifications: VecDeque<Notification>,
+    parameters: HashMap<String, String>,
+    max_result_size: Option<usize>,
+    /// Set once the socket has failed or the session has ended, after which
+    /// nothing more is written to it.
+    broken: bool,
+    hooks: Hooks,
 }
 
 impl Connection {
-    fn initiate_connection(&mut self) -> Result<()> {
-        let startup = StartupMessage {
-            user: &self.user,
-            database: Some(&self.database),
-            params: vec!(),
+    pub fn new(user: &str, password: Option<&str>, host: &str, database: Option<&str>) -> Result<Connection> {
+        let mut config = Config::new(user).host(host);
+        if let Some(password) = password {
+            config = config.password(password);
+        }
+        if let Some(database) = database {
+            config = config.database(database);
+        }
+        Connection::connect(&config)
+    }
+
+    pub fn connect(config: &Config) -> Result<Connection> {
+        let span = connect_span(config);
+        let _enter = span.enter();
+        let started = Instant::now();
+        let result = net::TcpStream::connect((config.host.as_str(), config.port))
+            .and_then(|socket| socket.set_nodelay(true).map(|_| socket))
+            .map_err(PgError::Io)
+            .and_then(|socket| {
+                let cancel_address = Some((config.host.clone(), config.port));
+                Connection::start(Box::new(socket), cancel_address, config, started)
+            });
+        Connection::report_connect(config, &span, started, result)
+    }
+
+    /// Run the connection over a stream which is already open to the
+    /// server.  The host and port in `config` are not used: cancel requests
+    /// go to the stream's `peer_addr`, and without one there are no cancel
+    /// tokens or query timeouts.
+    pub fn connect_with_stream<S: Transport + 'static>(stream: S, config: &Config) -> Result<Connection> {
+        let span = connect_span(config);
+        let _enter = span.enter();
+        let started = Instant::now();
+        let cancel_address = stream.peer_addr().map(|addr| (addr.ip().to_string(), addr.port()));
+        let result = Connection::start(Box::new(stream), cancel_address, config, started);
+        Connection::report_connect(config, &span, started, result)
+    }
+
+    fn report_connect(config: &Config, span: &Span, started: Instant, result: Result<(Connection, Option<Duration>)>) -> Result<Connection> {
+        config.hooks.connected(span, &ConnectEvent {
+            duration: started.elapsed(),
+            auth_duration: result.as_ref().ok().and_then(|&(_, auth_duration)| auth_duration),
+            error: result.as_ref().err(),
+        });
+        result.map(|(conn, _)| conn)
+    }
+
+    /// Run the startup sequence, returning how long authentication took.
+    fn start(socket: Box<dyn Transport>, cancel_address: Option<(String, u16)>, config: &Config, started: Instant) -> Result<(Connection, Option<Duration>)> {
+        let database = match config.database {
+            Some(ref db) => db.clone(),
+            None => config.user.clone(),
         };
-        let bytes_to_send = startup.to_bytes();
-        try!(self.socket.write_all(&bytes_to_send)); 
-        self.state = ConnectionState::AwaitingAuthResponse;
+        let password = try!(config.fetch_password());
+        let mut protocol = Protocol::new(&config.user, password, &database);
+        protocol.set_max_message_size(config.max_message_size);
+        let mut conn = Connection {
+            cancel_address: cancel_address,
+            socket: socket,
+            protocol: protocol,
+            statement_timeout: None,
+            notifications: VecDeque::new(),
+            parameters: HashMap::new(),
+            max_result_size: config.max_result_size,
+            broken: false,
+            hooks: config.hooks.clone(),
+        };
+        let mut auth_duration = None;
+        let mut auth_span = Some(debug_span!("auth").entered());
+        loop {
+            match try!(conn.next_event(None)) {
+                Event::Authenticated => {
+                    debug!("authenticated");
+                    auth_duration = Some(started.elapsed());
+                    auth_span.take();
+                },
+                Event::ParameterStatus(name, value) => { conn.parameters.insert(name, value); },
+                Event::ReadyForQuery(_) => return Ok((conn, auth_duration)),
+                Event::Error(err) => return Err(PgError::Db(err)),
+                _ => {},
+            }
+        }
+    }
+
+    /// Get a token that can cancel queries running on this connection from
+    /// another thread.  Returns `None` if the server did not send its
+    /// backend key data during startup, or its address is not known.
+    pub fn cancel_token(&self) -> Option<CancelToken> {
+        match (self.protocol.backend_key(), self.cancel_address.as_ref()) {
+            (Some((pid, key)), Some(&(ref host, port))) => Some(CancelToken::new(host, port, pid, key)),
+            _ => None,
+        }
+    }
+
+    /// A setting the server reported, such as `server_version` or
+    /// `client_encoding`.
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters.get(name).map(|value| &value[..])
+    }
+
+    /// The deadline applied to queries run with `query`, if any.
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.statement_timeout
+    }
+
+    /// Set a deadline for every query run with `query`.  Queries that run
+    /// longer are cancelled on the server and return `PgError::Timeout`.
+    pub fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.statement_timeout = timeout;
+    }
+
+    /// Get the notifications sent to channels this connection is listening
+    /// on.  Notifications that arrive while a query is running are buffered
+    /// until they are read.
+    pub fn notifications(&mut self) -> Notifications<'_> {
+        Notifications { conn: self }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Err(err) = self.socket.write_all(bytes) {
+            self.broken = true;
+            return Err(err);
+        }
+        self.hooks.bytes_sent(bytes.len());
         Ok(())
     }
 
-    fn handle_startup(&mut self) -> Result<()> {
-        while match self.state {
-            ConnectionState::ReadyForQuery => false,
-            ConnectionState::AuthenticationRejected => false,
-            _ => true,
-        } {
-            let mut buf = Vec::with_capacity(1024);
-            let mut message_queue = VecDeque::new();
-            try!(self.read_from_socket(&mut buf));
-            let mut remainder = &buf[..];
-            while remainder.len() > 0 {
-                let (bytes, excess) = try!(take_msg(remainder));
-                let msg = try!(ServerMsg::from_slice(bytes));
-                message_queue.push_back(msg);
-                remainder = excess;
-            }
-            println!("VecDeque: {:?}", message_queue);
-            while message_queue.len() > 0 {
-                match self.state.clone() {
-                    ConnectionState::AwaitingAuthResponse => try!(self.handle_auth(&mut message_queue)),
-                    ConnectionState::AuthenticationRejected => false,
-                    ConnectionState::Authenticated => try!(self.handle_server_info(&mut message_queue)),
-                    ConnectionState::ReadyForQuery => {
-                        try!(self.handle_ready_for_query(&mut message_queue));
-                        break;
-                    },
-                    state => return Err(PgError::Error(format!("Invalid startup state: {:?}", state))),
-                };
-            } 
+    /// Send everything the protocol has queued for the server.
+    fn send_output(&mut self) -> io::Result<()> {
+        if self.protocol.has_output() {
+            let output = self.protocol.take_output();
+            try!(self.send(&output));
         }
         Ok(())
     }
 
-    fn handle_auth<'a>(&mut self, message_queue: &mut VecDeque<ServerMsg<'a>>) -> Result<bool> {
-        let msg = message_queue.pop_front();
-        match msg {
-            Some(ServerMsg::Auth(AuthMsg::Ok)) => {
-                self.state = ConnectionState::Authenticated;
-                Ok(false)
-            },
-            Some(ServerMsg::Auth(AuthMsg::Md5(salt))) => {
-                let password = &self.password.clone().unwrap_or(String::new());
-                let passhash = auth::build_md5_hash(&self.user, password, salt);
-                let password_message = PasswordMessage { hash: &passhash };
-                try!(self.socket.write_all(&password_message.to_bytes()[..])); 
-                Ok(true)
-            },
-            Some(ServerMsg::Auth(method)) => {
-                Err(PgError::Error(format!("Unimplemented authentication method, {:?}", method)))
+    /// Wait for the next event from the server, sending anything queued
+    /// first.  Notifications are buffered rather than returned.
+    fn next_event(&mut self, deadline: Option<Instant>) -> Result<Event> {
+        loop {
+            match try!(self.parse_event()) {
+                Some(Event::Notification(notification)) => self.notifications.push_back(notification),
+                Some(event) => return Ok(event),
+                None => {
+                    try!(self.send_output());
+                    try!(self.fill_buffer(deadline));
+                },
+            }
+        }
+    }
+
+    fn parse_event(&mut self) -> Result<Option<Event>> {
+        match self.protocol.next_event() {
+            Err(err) => Err(self.close_broken(err)),
+            result => result,
+        }
+    }
+
+    /// End the session if `err` means the server's messages can no longer
+    /// be followed, so the connection isn't used again.
+    fn close_broken(&mut self, err: PgError) -> PgError {
+        match err {
+            PgError::Protocol(_) | PgError::MessageTooLarge(_) | PgError::ResultTooLarge(_) => {
+                self.protocol.terminate();
+                let _ = self.send_output();
+                self.broken = true;
             },
-            Some(ServerMsg::ErrorResponse(err)) => try!(self.handle_error(err)),
-            Some(msg) => Err(PgError::Error(format!("Unexpected non-auth message: {:?}", msg))),
-            None => Err(PgError::Error("No message received".to_string())),
+            _ => {},
         }
+        err
     }
 
-    fn handle_server_info<'a>(&mut self, message_queue: &mut VecDeque<ServerMsg<'a>>) -> Result<bool> {
-        match message_queue.pop_front() {
-            Some(ServerMsg::ReadyForQuery) => {
-                self.state = ConnectionState::ReadyForQuery;
-                Ok(false)
+    fn fill_buffer(&mut self, deadline: Option<Instant>) -> Result<()> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(PgError::Timeout);
+                }
+                Some(deadline - now)
             },
-            Some(ServerMsg::ErrorResponse(err)) => try!(self.handle_error(err)),
-            Some(_) => Ok(false),
-            None => Ok(true)
+            None => None,
+        };
+        if let Err(err) = self.socket.set_read_timeout(timeout) {
+            self.broken = true;
+            return Err(PgError::Io(err));
         }
+        self.read_into_buffer()
     }
 
-    fn handle_error<T>(&mut self, err: Vec<&str>) -> Result<T> {
-        let message = err.get(3).unwrap();
-        Err(PgError::Error(message.to_string()))
+    /// Read whatever the server has already sent, without blocking.
+    fn poll_buffer(&mut self) -> Result<()> {
+        try!(self.socket.set_nonblocking(true));
+        let result = self.read_into_buffer();
+        try!(self.socket.set_nonblocking(false));
+        result
     }
 
-    fn handle_ready_for_query<'a>(&mut self, message_queue: &mut VecDeque<ServerMsg<'a>>) -> Result<bool> {
-        match message_queue.pop_front() {
-            Some(msg) => Err(PgError::Error(format!("Unexpected message after ReadyForQuery: {:?}", msg))),
-            None => Ok(false),
+    fn read_into_buffer(&mut self) -> Result<()> {
+        let mut chunk = [0; 8192];
+        match self.socket.read(&mut chunk) {
+            Ok(0) => {
+                self.broken = true;
+                Err(PgError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed by server",
+                )))
+            },
+            Ok(count) => {
+                self.protocol.receive(&chunk[..count]);
+                self.hooks.bytes_received(count);
+                Ok(())
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => Ok(()),
+            Err(err) => {
+                self.broken = true;
+                Err(PgError::Io(err))
+            },
         }
     }
 
-    pub fn new(user: &str, password: Option<&str>, host: &str, database: Option<&str>) -> Result<Connection> {
-        let database = match database {
-            Some(db) => db.to_string(),
-            None => user.to_string(),
-        };
-        let password = match password {
-            Some(pass) => Some(pass.to_string()),
-            None => None,
-        };
-        let user = user.to_string();
-        let host = host.to_string();
-        let port = 5432;
-        let socket = try!(net::TcpStream::connect((host.as_str(), port)));
-        try!(socket.set_read_timeout(Some(Duration::new(0, 1))));
-        try!(socket.set_nodelay(true));
-        let mut conn = Connection {
-            user: user.clone(),
-            password: password,
-            database: database.clone(),
-            host: host,
-            port: port,
-            socket: socket,
-            state: ConnectionState::New,
-        };
-        try!(conn.initiate_connection());
-        try!(conn.handle_startup());
-        match conn.state {
-            ConnectionState::ReadyForQuery => Ok(conn),
-            ConnectionState::AuthenticationRejected => Err(PgError::Unauthenticated),
-            state => Err(PgError::Error(format!("Unexpected state: {:?}", state))),
-        }
-    }
-
-    fn read_from_socket(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
-        while buf.len() == 0 {
-            match self.socket.read_to_end(buf) {
-                Ok(_) => continue,
-                Err(ioerr) => if let Some(11) = ioerr.raw_os_error() {
-                    continue;
-                } else {
-                    try!(Err(ioerr));
-                },
+    /// Handle what the server has sent while no query is running.
+    fn handle_idle_events(&mut self) -> Result<()> {
+        while let Some(event) = try!(self.parse_event()) {
+            match event {
+                Event::Notification(notification) => self.notifications.push_back(notification),
+                Event::Notice(_) => {},
+                Event::ParameterStatus(name, value) => { self.parameters.insert(name, value); },
+                Event::Error(err) => return Err(PgError::Db(err)),
+                other => return Err(PgError::Error(format!("Unexpected message while idle: {:?}", other))),
             }
         }
-        Ok(buf.len())
+        Ok(())
+    }
+
+    /// Send already encoded frontend messages, such as those a proxy
+    /// forwards from its clients.  The replies are read with
+    /// `read_message`, and must all be read before the next `query`.
+    pub fn send_message(&mut self, bytes: &[u8]) -> Result<()> {
+        try!(self.check_open());
+        try!(self.protocol.send_message(bytes));
+        try!(self.send_output());
+        Ok(())
+    }
+
+    /// Wait for the next message from the server, returned as it was sent.
+    pub fn read_message(&mut self) -> Result<Vec<u8>> {
+        loop {
+            match self.protocol.next_message() {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {},
+                Err(err) => return Err(self.close_broken(err)),
+            }
+            try!(self.fill_buffer(None));
+        }
+    }
+
+    /// End the session: send Terminate and shut the socket down, reporting
+    /// any failure.  Dropping the connection does the same, silently.
+    pub fn close(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        if self.broken {
+            return Ok(());
+        }
+        self.protocol.terminate();
+        let sent = self.send_output();
+        self.broken = true;
+        try!(sent);
+        match self.socket.shutdown() {
+            // The server may close its end as soon as it reads Terminate.
+            Err(ref err) if err.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result.map_err(PgError::Io),
+        }
+    }
+
+    /// The transaction status reported by the server after the last query.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.protocol.transaction_status()
+    }
+
+    /// Whether the connection has been lost or closed after an error.  It
+    /// can't be used again.
+    pub fn is_closed(&self) -> bool {
+        self.broken
+    }
+
+    /// Refuse to use a connection whose session may be out of step with the
+    /// server, since a stale reply could be taken for the next one's.
+    fn check_open(&self) -> Result<()> {
+        if self.broken {
+            return Err(PgError::Error("Connection is closed".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The server ignores everything but the end of the transaction once a
+    /// transaction has failed, so refuse other statements up front.
+    fn check_transaction_status(&self, sql: &str) -> Result<()> {
+        if self.transaction_status() != TransactionStatus::Failed {
+            return Ok(());
+        }
+        let keyword = sql.trim_start().split(|c: char| !c.is_alphabetic()).next().unwrap_or("");
+        match &keyword.to_uppercase()[..] {
+            "ROLLBACK" | "ABORT" | "COMMIT" | "END" => Ok(()),
+            _ => Err(PgError::FailedTransaction),
+        }
     }
 
     pub fn query(&mut self, sql: &str) -> Result<Vec<Vec<String>>> {
-        let query = Query { query: sql.to_string() };
-        try!(self.socket.write_all(&query.to_bytes()));
-        self.state = ConnectionState::AwaitingQueryResponse;
-        let mut buf: Vec<u8> = vec!();
-        try!(self.read_from_socket(&mut buf));
-        let mut remainder = &buf[..];
+        let timeout = self.statement_timeout;
+        self.run_query(sql, timeout)
+    }
+
+    /// Run a query, cancelling it if it has not finished within `timeout`.
+    ///
+    /// On timeout the connection waits for the server to abandon the query,
+    /// so it can be used again once `PgError::Timeout` is returned.
+    pub fn query_with_timeout(&mut self, sql: &str, timeout: Duration) -> Result<Vec<Vec<String>>> {
+        self.run_query(sql, Some(timeout))
+    }
+
+    fn run_query(&mut self, sql: &str, timeout: Option<Duration>) -> Result<Vec<Vec<String>>> {
+        let span = query_span(sql);
+        let _enter = span.enter();
+        let started = Instant::now();
+        let result = self.execute_query(sql, timeout);
+        let rows = result.as_ref().map(|data| data.len() as u64).unwrap_or(0);
+        self.report_query(&span, sql, started, rows, result.as_ref().err());
+        result
+    }
+
+    fn report_query(&self, span: &Span, sql: &str, started: Instant, rows: u64, error: Option<&PgError>) {
+        self.hooks.query_finished(span, &QueryEvent {
+            sql: sql,
+            duration: started.elapsed(),
+            rows: rows,
+            error: error,
+        });
+    }
+
+    fn execute_query(&mut self, sql: &str, timeout: Option<Duration>) -> Result<Vec<Vec<String>>> {
+        try!(self.check_open());
+        if let Some(timeout) = timeout {
+            // Find out whether the transport can time reads out before the
+            // query is sent, rather than after, when its reply is pending.
+            try!(self.socket.set_read_timeout(Some(timeout)));
+            if self.cancel_token().is_none() {
+                return Err(PgError::Error("Query timeouts need the server's cancel key and address".to_string()));
+            }
+        }
+        try!(self.check_transaction_status(sql));
+        try!(self.protocol.query(sql));
+        try!(self.send_output());
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
         let mut data = vec![];
-            
-        while remainder.len() > 0 {
-            let (bytes, excess) = try!(take_msg(remainder));
-            let msg = try!(ServerMsg::from_slice(bytes));
-            remainder = excess;
-            match msg {
-                ServerMsg::DataRow(vec) => {
-                    let mut row = vec![];
-                    for each in vec {
-                        row.push(each.to_string());
+        let mut size = 0;
+        let mut error = None;
+
+        loop {
+            let event = match self.next_event(deadline) {
+                Ok(event) => event,
+                Err(PgError::Timeout) => {
+                    try!(self.cancel_and_drain());
+                    return Err(PgError::Timeout);
+                },
+                Err(err) => return Err(err),
+            };
+            match event {
+                Event::DataRow(row) => {
+                    size += row.iter().map(|value| value.len()).sum::<usize>();
+                    if let Some(limit) = self.max_result_size {
+                        if size > limit {
+                            return Err(self.close_broken(PgError::ResultTooLarge(limit)));
+                        }
                     }
                     data.push(row);
                 },
-                ServerMsg::RowDescription(_) => {
-                    self.state = ConnectionState::AwaitingDataRows;
+                Event::Error(err) => error = Some(PgError::Db(err)),
+                Event::CopyIn(..) => {
+                    try!(self.protocol.copy_fail("Use copy_in for COPY FROM STDIN"));
                 },
-                ServerMsg::CommandComplete(_) => {},
-                ServerMsg::ReadyForQuery => {
-                    if remainder.len() > 0 {
-                        return Err(PgError::Error(
-                            format!("Received data after ReadyForQuery: {:?}", remainder)
-                        ));
-                    };
-                    self.state = ConnectionState::ReadyForQuery;
+                Event::CopyOut(..) => {
+                    error = Some(PgError::Error(format!("Use copy_out for COPY TO STDOUT: {}", sql)));
                 },
-                other => return Err(PgError::Error(format!("unexpected data: {:?}", other))),
+                Event::ReadyForQuery(_) => break,
+                _ => {},
             }
         }
-        Ok(data)
+        match error {
+            Some(err) => Err(err),
+            None => Ok(data),
+        }
+    }
+
+    /// Start a `COPY ... FROM STDIN` statement.  Data written to the returned
+    /// writer is streamed to the server.
+    pub fn copy_in(&mut self, sql: &str) -> Result<CopyInWriter<'_>> {
+        let span = copy_span(sql, "in");
+        let started = Instant::now();
+        if let Err(err) = span.in_scope(|| self.start_copy(sql, ConnectionState::CopyIn)) {
+            self.report_query(&span, sql, started, 0, Some(&err));
+            return Err(err);
+        }
+        Ok(CopyInWriter {
+            conn: self,
+            span: span,
+            sql: sql.to_string(),
+            started: started,
+            buffer: Vec::with_capacity(COPY_BUFFER_SIZE),
+            done: false,
+        })
+    }
+
+    /// Start a `COPY ... TO STDOUT` statement.  The returned reader yields
+    /// the data sent by the server.
+    pub fn copy_out(&mut self, sql: &str) -> Result<CopyOutReader<'_>> {
+        let span = copy_span(sql, "out");
+        let started = Instant::now();
+        let (format, column_formats) = match span.in_scope(|| self.start_copy(sql, ConnectionState::CopyOut)) {
+            Ok(formats) => formats,
+            Err(err) => {
+                self.report_query(&span, sql, started, 0, Some(&err));
+                return Err(err);
+            },
+        };
+        Ok(CopyOutReader {
+            conn: self,
+            span: span,
+            sql: sql.to_string(),
+            started: started,
+            format: format,
+            column_formats: column_formats,
+            chunk: vec![],
+            position: 0,
+            rows: None,
+        })
+    }
+
+    /// Send a COPY statement and wait for the server to enter `direction`,
+    /// either `CopyIn` or `CopyOut`.
+    fn start_copy(&mut self, sql: &str, direction: ConnectionState) -> Result<(FieldFormat, Vec<FieldFormat>)> {
+        try!(self.check_open());
+        try!(self.check_transaction_status(sql));
+        try!(self.protocol.query(sql));
+        try!(self.send_output());
+        loop {
+            match try!(self.next_event(None)) {
+                Event::CopyIn(format, column_formats) |
+                Event::CopyOut(format, column_formats) if self.protocol.state() == direction => {
+                    return Ok((format, column_formats));
+                },
+                Event::Error(err) => {
+                    try!(self.finish_command());
+                    return Err(PgError::Db(err));
+                },
+                Event::Notice(_) => {},
+                Event::ParameterStatus(..) => {},
+                _ => {
+                    try!(self.finish_command());
+                    return Err(PgError::Error(match direction {
+                        ConnectionState::CopyIn => format!("Not a COPY FROM STDIN statement: {}", sql),
+                        _ => format!("Not a COPY TO STDOUT statement: {}", sql),
+                    }));
+                },
+            }
+        }
+    }
+
+    /// Read the rest of the server's response to a command, up to
+    /// ReadyForQuery, and return the command tag.  An unfinished
+    /// `COPY ... FROM STDIN` is aborted.
+    fn finish_command(&mut self) -> Result<Option<String>> {
+        if self.protocol.state() == ConnectionState::CopyIn {
+            try!(self.protocol.copy_fail("COPY aborted by client"));
+        }
+        let mut tag = None;
+        let mut error = None;
+        while self.protocol.state() != ConnectionState::ReadyForQuery {
+            match try!(self.next_event(None)) {
+                Event::CommandComplete(command_tag) => tag = Some(command_tag),
+                Event::Error(err) => error = Some(PgError::Db(err)),
+                _ => {},
+            }
+        }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(tag),
+        }
+    }
+
+    /// Ask the server to cancel the running query, then discard everything
+    /// it sends until it is ready for the next query.  If either step fails
+    /// the query's reply may still arrive, so the connection is closed.
+    fn cancel_and_drain(&mut self) -> Result<()> {
+        let result = self.cancel_token()
+            .ok_or_else(|| PgError::Error("No cancel key for the connection".to_string()))
+            .and_then(|token| token.cancel());
+        if let Err(err) = result {
+            self.broken = true;
+            return Err(err);
+        }
+        while self.protocol.state() != ConnectionState::ReadyForQuery {
+            if let Err(err) = self.next_event(None) {
+                self.broken = true;
+                return Err(err);
+            }
+        }
+        Ok(())
     }
 }
 
-impl Drop for Connection {
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A writer streaming data to a `COPY ... FROM STDIN` statement.
+///
+/// Call `finish` to complete the copy.  If the writer is dropped first, the
+/// copy is aborted and nothing is loaded.
+pub struct CopyInWriter<'a> {
+    conn: &'a mut Connection,
+    span: Span,
+    sql: String,
+    started: Instant,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl <'a> CopyInWriter<'a> {
+    /// Complete the copy, and return the number of rows loaded.
+    pub fn finish(mut self) -> Result<u64> {
+        self.done = true;
+        if let Err(err) = self.flush() {
+            let _ = self.abort("COPY aborted by client");
+            return Err(PgError::Io(err));
+        }
+        let result = self.complete();
+        let rows = *result.as_ref().unwrap_or(&0);
+        self.conn.report_query(&self.span, &self.sql, self.started, rows, result.as_ref().err());
+        result
+    }
+
+    /// Abort the copy, discarding everything sent so far.
+    pub fn fail(mut self, message: &str) -> Result<()> {
+        self.done = true;
+        match self.abort(message) {
+            Err(PgError::Io(err)) => Err(PgError::Io(err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn complete(&mut self) -> Result<u64> {
+        try!(self.conn.protocol.copy_done());
+        try!(self.conn.send_output());
+        let tag = try!(self.conn.finish_command());
+        copy_row_count(tag)
+    }
+
+    fn abort(&mut self, message: &str) -> Result<()> {
+        self.buffer.clear();
+        try!(self.conn.protocol.copy_fail(message));
+        try!(self.conn.send_output());
+        let result = self.conn.finish_command().map(|_| ());
+        self.conn.report_query(&self.span, &self.sql, self.started, 0, result.as_ref().err());
+        result
+    }
+}
+
+impl <'a> Write for CopyInWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend(data);
+        if self.buffer.len() >= COPY_BUFFER_SIZE {
+            try!(self.flush());
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.len() > 0 {
+            if let Err(err) = self.conn.protocol.copy_data(&self.buffer) {
+                return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+            }
+            try!(self.conn.send_output());
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl <'a> Drop for CopyInWriter<'a> {
     fn drop(&mut self) {
-        let msg = Terminate;
-        let bytes_to_send = msg.to_bytes();
-        println!("{:?}", msg);
-        match self.socket.write_all(&bytes_to_send) {
-            Ok(_) => {},
-            error => {
-                println!("WARNING: An error occurred ending the session with the server: {:?}", error);
+        if !self.done {
+            let _ = self.abort("COPY aborted by client");
+        }
+    }
+}
+
+/// A reader over the data sent by a `COPY ... TO STDOUT` statement.
+///
+/// If the reader is dropped before the end of the data, the rest is read
+/// and discarded so the connection can be used again.
+pub struct CopyOutReader<'a> {
+    conn: &'a mut Connection,
+    span: Span,
+    sql: String,
+    started: Instant,
+    format: FieldFormat,
+    column_formats: Vec<FieldFormat>,
+    chunk: Vec<u8>,
+    position: usize,
+    rows: Option<u64>,
+}
+
+impl <'a> CopyOutReader<'a> {
+    /// The overall format of the copy data.
+    pub fn format(&self) -> FieldFormat {
+        self.format
+    }
+
+    /// The format of each column in the copy data.
+    pub fn column_formats(&self) -> &[FieldFormat] {
+        &self.column_formats
+    }
+
+    /// Discard any unread data, and return the number of rows copied.
+    pub fn finish(mut self) -> Result<u64> {
+        while self.rows.is_none() {
+            try!(self.next_chunk());
+        }
+        Ok(self.rows.unwrap_or(0))
+    }
+
+    /// Replace the current chunk with the next CopyData message.  At the end
+    /// of the data, the chunk is left empty and the row count is recorded.
+    fn next_chunk(&mut self) -> Result<()> {
+        self.chunk.clear();
+        self.position = 0;
+        match try!(self.conn.next_event(None)) {
+            Event::CopyData(data) => {
+                self.chunk = data;
+                Ok(())
             },
-        };
-        self.state = ConnectionState::Disconnected;
+            Event::CopyDone => {
+                let result = self.conn.finish_command().and_then(copy_row_count);
+                self.rows = Some(*result.as_ref().unwrap_or(&0));
+                self.conn.report_query(&self.span, &self.sql, self.started, self.rows.unwrap_or(0), result.as_ref().err());
+                result.map(|_| ())
+            },
+            Event::Error(err) => {
+                let error = PgError::Db(err);
+                self.rows = Some(0);
+                self.conn.report_query(&self.span, &self.sql, self.started, 0, Some(&error));
+                try!(self.conn.finish_command());
+                Err(error)
+            },
+            Event::Notice(_) => Ok(()),
+            Event::ParameterStatus(..) => Ok(()),
+            other => Err(PgError::Error(format!("Unexpected message during COPY: {:?}", other))),
+        }
+    }
+}
+
+impl <'a> Read for CopyOutReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.rows.is_some() {
+                return Ok(0);
+            }
+            if let Err(err) = self.next_chunk() {
+                return Err(match err {
+                    PgError::Io(err) => err,
+                    err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+                });
+            }
+        }
+        let count = (&self.chunk[self.position..]).read(buf).unwrap_or(0);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+impl <'a> Drop for CopyOutReader<'a> {
+    fn drop(&mut self) {
+        while self.rows.is_none() {
+            if self.next_chunk().is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn copy_row_count(tag: Option<String>) -> Result<u64> {
+    match tag.as_ref().and_then(|tag| tag.split(' ').last()) {
+        Some(count) => count.parse().map_err(|_| {
+            PgError::Error(format!("Invalid command tag for COPY: {:?}", tag))
+        }),
+        None => Err(PgError::Error("No command tag received for COPY".to_string())),
+    }
+}
+
+/// An asynchronous notification sent with `NOTIFY` or `pg_notify`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Notification {
+    pub pid: u32,
+    pub channel: String,
+    pub payload: String,
+}
+
+impl Notification {
+    pub(crate) fn new(pid: u32, channel: &str, payload: &str) -> Notification {
+        Notification {
+            pid: pid,
+            channel: channel.to_string(),
+            payload: payload.to_string(),
+        }
+    }
+}
+
+/// The notifications received by a `Connection`.
+///
+/// Iterating blocks until the next notification arrives.
+pub struct Notifications<'a> {
+    conn: &'a mut Connection,
+}
+
+impl <'a> Notifications<'a> {
+    /// Return a buffered notification, or one the server has already sent,
+    /// without waiting.
+    pub fn try_next(&mut self) -> Result<Option<Notification>> {
+        if self.conn.notifications.is_empty() {
+            try!(self.conn.poll_buffer());
+            try!(self.conn.handle_idle_events());
+        }
+        Ok(self.conn.notifications.pop_front())
+    }
+
+    /// Wait up to `timeout` for a notification.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Notification>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            try!(self.conn.handle_idle_events());
+            if !self.conn.notifications.is_empty() {
+                break;
+            }
+            match self.conn.fill_buffer(Some(deadline)) {
+                Ok(()) => {},
+                Err(PgError::Timeout) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.conn.notifications.pop_front())
+    }
+}
+
+impl <'a> Iterator for Notifications<'a> {
+    type Item = Result<Notification>;
+
+    fn next(&mut self) -> Option<Result<Notification>> {
+        loop {
+            if let Err(err) = self.conn.handle_idle_events() {
+                return Some(Err(err));
+            }
+            if !self.conn.notifications.is_empty() {
+                break;
+            }
+            if let Err(err) = self.conn.fill_buffer(None) {
+                return Some(Err(err));
+            }
+        }
+        self.conn.notifications.pop_front().map(Ok)
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("cancel_address", &self.cancel_address)
+            .field("protocol", &self.protocol)
+            .field("statement_timeout", &self.statement_timeout)
+            .field("notifications", &self.notifications)
+            .field("broken", &self.broken)
+            .field("hooks", &self.hooks)
+            .finish()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            debug!(error = %err, "error ending the session with the server");
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
+    use std::fs::File;
+    use std::io;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use binary_copy::BinaryCopyWriter;
+    use config::Config;
+    use error::PgError;
+    use message::{Message, Query, Terminate};
+    use servermsg::{FieldFormat, TransactionStatus};
+    use testing::{Auth, MockBackend, Received, Response};
+    use transport::Transport;
     use super::Connection;
 
+    /// A stream which replays canned server messages, and records what the
+    /// client sends.
+    struct Replay {
+        input: io::Cursor<Vec<u8>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Replay {}
+
+    /// The messages a server sends to start a session without a password.
+    fn startup() -> Vec<u8> {
+        let mut server = vec![];
+        server.extend(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0]);
+        server.extend(&[b'Z', 0, 0, 0, 5, b'I']);
+        server
+    }
+
+    #[test]
+    fn test_connect_with_stream() {
+        let mut server = startup();
+        server.extend(select(b'7'));
+        server.extend(select(b'9'));
+        let sent = Arc::new(Mutex::new(vec![]));
+        let stream = Replay { input: io::Cursor::new(server), output: sent.clone() };
+
+        let mut conn = Connection::connect_with_stream(stream, &Config::new("cliff")).unwrap();
+        assert_eq!(conn.query("SELECT 7;").unwrap(), vec![vec!["7".to_string()]]);
+        let query = Query { query: "SELECT 7;".to_string() }.to_bytes();
+        assert!(sent.lock().unwrap().ends_with(&query));
+        match conn.query_with_timeout("SELECT 8;", Duration::from_secs(1)) {
+            Err(PgError::Io(_)) => {},
+            other => panic!("Expected timeouts to be unsupported, got {:?}", other),
+        }
+        // The refused query was never sent, so the next one gets its own reply.
+        assert_eq!(conn.query("SELECT 9;").unwrap(), vec![vec!["9".to_string()]]);
+        let query = Query { query: "SELECT 9;".to_string() }.to_bytes();
+        let sent = sent.lock().unwrap();
+        assert!(sent.ends_with(&query));
+        assert!(!sent.windows(8).any(|window| window == b"SELECT 8"));
+    }
+
+    /// The reply to a query returning the single digit `value`.
+    fn select(value: u8) -> Vec<u8> {
+        let mut server = vec![];
+        server.extend(&[b'T', 0, 0, 0, 26, 0, 1, b'n', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 23, 0, 4, 255, 255, 255, 255, 0, 0]);
+        server.extend(&[b'D', 0, 0, 0, 11, 0, 1, 0, 0, 0, 1, value]);
+        server.extend(&[b'C', 0, 0, 0, 13]);
+        server.extend(b"SELECT 1\0");
+        server.extend(&[b'Z', 0, 0, 0, 5, b'I']);
+        server
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeouts_need_cancel_address() {
+        let (stream, mut server) = UnixStream::pair().unwrap();
+        server.write_all(&startup()).unwrap();
+        server.write_all(&select(b'7')).unwrap();
+        let mut conn = Connection::connect_with_stream(stream, &Config::new("cliff")).unwrap();
+        assert!(conn.cancel_token().is_none());
+        match conn.query_with_timeout("SELECT 1;", Duration::from_secs(1)) {
+            Err(PgError::Error(_)) => {},
+            other => panic!("Expected timeouts to be refused, got {:?}", other),
+        }
+        assert_eq!(conn.query("SELECT 7;").unwrap(), vec![vec!["7".to_string()]]);
+
+        // Only the startup message and the second query reached the server.
+        let mut received = vec![];
+        server.set_nonblocking(true).unwrap();
+        let _ = server.read_to_end(&mut received);
+        let startup_length = (received[3] as usize) | (received[2] as usize) << 8;
+        assert_eq!(&received[startup_length..], &Query { query: "SELECT 7;".to_string() }.to_bytes()[..]);
+    }
+
+    #[test]
+    fn test_failed_cancel_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            // Cancel requests will be refused once the listener is gone.
+            drop(listener);
+            socket.write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0]).unwrap();
+            socket.write_all(&[b'K', 0, 0, 0, 12, 0, 0, 0, 42, 1, 2, 3, 4]).unwrap();
+            socket.write_all(&[b'Z', 0, 0, 0, 5, b'I']).unwrap();
+            // Never answer the query.
+            let mut buf = [0; 1024];
+            while socket.read(&mut buf).map(|count| count > 0).unwrap_or(false) {}
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut conn = Connection::connect_with_stream(stream, &Config::new("cliff")).unwrap();
+        assert_eq!(conn.cancel_token().unwrap().pid(), 42);
+        match conn.query_with_timeout("SELECT pg_sleep(10);", Duration::from_millis(100)) {
+            Err(PgError::Io(_)) => {},
+            other => panic!("Expected the cancel request to fail, got {:?}", other),
+        }
+        match conn.query("SELECT 1;") {
+            Err(PgError::Error(ref message)) if message == "Connection is closed" => {},
+            other => panic!("Expected the connection to be closed, got {:?}", other),
+        }
+        drop(conn);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_sends_terminate() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let stream = Replay { input: io::Cursor::new(startup()), output: sent.clone() };
+        let conn = Connection::connect_with_stream(stream, &Config::new("cliff")).unwrap();
+        conn.close().unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(sent.ends_with(&Terminate.to_bytes()));
+        assert_eq!(sent.windows(5).filter(|window| *window == &Terminate.to_bytes()[..]).count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_utf8_closes_connection() {
+        let mut server = startup();
+        let mut rows = select(0xff);
+        // A valid row follows the bad one, before CommandComplete.
+        rows.splice(rows.len() - 20..rows.len() - 20, vec![b'D', 0, 0, 0, 11, 0, 1, 0, 0, 0, 1, b'A']);
+        server.extend(rows);
+        server.extend(select(b'B'));
+        let sent = Arc::new(Mutex::new(vec![]));
+        let stream = Replay { input: io::Cursor::new(server), output: sent.clone() };
+        let mut conn = Connection::connect_with_stream(stream, &Config::new("cliff")).unwrap();
+        match conn.query("SELECT a;") {
+            Err(PgError::Protocol(_)) => {},
+            other => panic!("Expected a protocol error, got {:?}", other),
+        }
+        assert!(conn.is_closed());
+        assert!(sent.lock().unwrap().ends_with(&Terminate.to_bytes()));
+        assert!(conn.query("SELECT b;").is_err());
+    }
+
+    #[test]
+    fn test_drop_skips_broken_connection() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let stream = Replay { input: io::Cursor::new(startup()), output: sent.clone() };
+        let mut conn = Connection::connect_with_stream(stream, &Config::new("cliff")).unwrap();
+        match conn.query("SELECT 1;") {
+            Err(PgError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {},
+            other => panic!("Expected the connection to be closed, got {:?}", other),
+        }
+        drop(conn);
+        let query = Query { query: "SELECT 1;".to_string() }.to_bytes();
+        assert!(sent.lock().unwrap().ends_with(&query));
+    }
+
+    #[test]
+    fn test_close() {
+        let server = MockBackend::new().start().unwrap();
+        let conn = Connection::connect(&server.config("cliff")).unwrap();
+        conn.close().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !server.received().contains(&Received::Terminate) {
+            assert!(Instant::now() < deadline, "Terminate was never received");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_connect_with_tcp_stream() {
+        let server = MockBackend::new().on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]])).start().unwrap();
+        let stream = TcpStream::connect(server.addr()).unwrap();
+        let mut conn = Connection::connect_with_stream(stream, &server.config("cliff")).expect("Could not establish connection");
+        assert_eq!(conn.query("SELECT 1;").unwrap(), vec![vec!["1".to_string()]]);
+        assert!(conn.cancel_token().is_some());
+    }
+
+    #[test]
+    fn test_pass_through() {
+        let server = MockBackend::new().on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]])).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        assert!(conn.parameter("server_version").is_some());
+        conn.send_message(&Query { query: "BEGIN; SELECT 1;".to_string() }.to_bytes()).unwrap();
+        let mut kinds = vec![];
+        loop {
+            let message = conn.read_message().unwrap();
+            kinds.push(message[0]);
+            if message[0] == b'Z' {
+                break;
+            }
+        }
+        assert_eq!(kinds, b"CTDCZ".to_vec());
+        assert_eq!(conn.transaction_status(), TransactionStatus::InTransaction);
+        conn.query("ROLLBACK;").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+    }
+
     #[test]
     fn test_connect() {
-        let user_string = env::var("USER").unwrap();
-        let user = user_string.as_ref();
-        let pass = Some(user);
-        let host = "127.0.0.1";
-        let database = Some(user);
-        let conn = Connection::new(user, pass, host, database);
-        assert!(conn.is_ok());
+        let server = MockBackend::new().auth(Auth::Md5("open sesame".to_string())).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff").password("open sesame").database("app")).unwrap();
+        assert_eq!(conn.parameter("server_version"), Some("16.0"));
+        assert_eq!(server.received()[0], Received::Startup {
+            user: "cliff".to_string(),
+            database: Some("app".to_string()),
+            params: vec![],
+        });
+        // The session is counted once the server is waiting for queries.
+        conn.query("BEGIN;").unwrap();
+        assert_eq!(server.sessions(), 1);
     }
 
     #[test]
     fn test_query_with_bad_creds() {
-        let user = "notauser";
-        let pass = Some(user);
-        let host = "127.0.0.1";
-        let database = Some("notadb");
-        let conn = Connection::new(user, pass, host, database);
-        assert!(conn.is_err());
+        let server = MockBackend::new().auth(Auth::Md5("open sesame".to_string())).start().unwrap();
+        match Connection::connect(&server.config("notauser").password("wrong")) {
+            Err(err) => assert_eq!(err.code(), Some("28P01")),
+            Ok(_) => panic!("Expected authentication to fail"),
+        }
+        assert_eq!(server.sessions(), 0);
     }
 
     #[test]
     fn test_query() {
-        let user_string = env::var("USER").unwrap();
-        let user = user_string.as_ref();
-        let pass = Some(user);
-        let host = "127.0.0.1";
-        let mut conn = Connection::new(user, pass, host, Some(user)).expect("Could not establish connection");
+        let server = MockBackend::new()
+            .on_query("SELECT VERSION();", Response::rows(&["version"], &[&["PostgreSQL 16.0"]]))
+            .on_query("SELECT 1, NULL;", Response::nullable_rows(&["a", "b"], &[&[Some("1"), None]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
         let data = conn.query("SELECT VERSION();").unwrap();
         assert_eq!(data.len(), 1);
         let ref result = data[0][0];
         assert_eq!(&result[..10], "PostgreSQL");
+        assert_eq!(conn.query("SELECT 1, NULL;").unwrap(), vec![vec!["1".to_string(), "".to_string()]]);
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let sleep = Response::delay(Duration::from_secs(10), Response::rows(&["pg_sleep"], &[&[""]]));
+        let server = MockBackend::new().on_query("SELECT pg_sleep(10);", sleep).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        let token = conn.cancel_token().expect("No backend key data received");
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            token.cancel()
+        });
+        match conn.query("SELECT pg_sleep(10);") {
+            Err(err) => assert_eq!(err.code(), Some("57014")),
+            Ok(rows) => panic!("Expected the query to be cancelled, got {:?}", rows),
+        }
+        assert!(canceller.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_query_with_timeout() {
+        let sleep = Response::delay(Duration::from_secs(10), Response::rows(&["pg_sleep"], &[&[""]]));
+        let server = MockBackend::new()
+            .on_query("SELECT pg_sleep(10);", sleep)
+            .on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        match conn.query_with_timeout("SELECT pg_sleep(10);", Duration::from_millis(200)) {
+            Err(PgError::Timeout) => {},
+            other => panic!("Expected timeout, got {:?}", other),
+        }
+        let data = conn.query_with_timeout("SELECT 1;", Duration::from_secs(10)).unwrap();
+        assert_eq!(data, vec![vec!["1".to_string()]]);
+    }
+
+    #[test]
+    fn test_statement_timeout() {
+        let sleep = Response::delay(Duration::from_secs(10), Response::rows(&["pg_sleep"], &[&[""]]));
+        let server = MockBackend::new()
+            .on_query("SELECT pg_sleep(10);", sleep)
+            .on_query("SELECT 2;", Response::rows(&["n"], &[&["2"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        conn.set_statement_timeout(Some(Duration::from_millis(200)));
+        assert_eq!(conn.statement_timeout(), Some(Duration::from_millis(200)));
+        assert!(conn.query("SELECT pg_sleep(10);").is_err());
+        conn.set_statement_timeout(None);
+        let data = conn.query("SELECT 2;").unwrap();
+        assert_eq!(data, vec![vec!["2".to_string()]]);
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let short = "x".repeat(100);
+        let long = "x".repeat(1000);
+        let server = MockBackend::new()
+            .on_query("SELECT short", Response::rows(&["x"], &[&[&short]]))
+            .on_query("SELECT long", Response::rows(&["x"], &[&[&long]]))
+            .on_query("SELECT 1", Response::rows(&["n"], &[&["1"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff").max_message_size(200)).unwrap();
+        assert!(conn.query("SELECT short").is_ok());
+        match conn.query("SELECT long") {
+            Err(PgError::MessageTooLarge(size)) => assert!(size > 1000),
+            other => panic!("Expected the message to be refused, got {:?}", other),
+        }
+        assert!(conn.query("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn test_max_result_size() {
+        let value = "x".repeat(60);
+        let server = MockBackend::new()
+            .on_query("SELECT one", Response::rows(&["x"], &[&[&value]]))
+            .on_query("SELECT two", Response::rows(&["x"], &[&[&value], &[&value]]))
+            .on_query("SELECT 1", Response::rows(&["n"], &[&["1"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff").max_result_size(Some(100))).unwrap();
+        assert!(conn.query("SELECT one").is_ok());
+        match conn.query("SELECT two") {
+            Err(PgError::ResultTooLarge(100)) => {},
+            other => panic!("Expected the result to be refused, got {:?}", other),
+        }
+        assert!(conn.query("SELECT 1").is_err());
+    }
+
+    #[test]
+    fn test_query_after_error() {
+        let server = MockBackend::new()
+            .on_query("SELECT * FROM no_such_table;", Response::error("42P01", "relation \"no_such_table\" does not exist"))
+            .on_query("SELECT 3;", Response::rows(&["n"], &[&["3"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        match conn.query("SELECT * FROM no_such_table;") {
+            Err(err) => assert_eq!(err.code(), Some("42P01")),
+            Ok(rows) => panic!("Expected an error, got {:?}", rows),
+        }
+        let data = conn.query("SELECT 3;").unwrap();
+        assert_eq!(data, vec![vec!["3".to_string()]]);
+    }
+
+    #[test]
+    fn test_notifications() {
+        let server = MockBackend::new().start().unwrap();
+        let mut listener = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        let mut notifier = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        listener.query("LISTEN test_notifications;").unwrap();
+        assert_eq!(listener.notifications().try_next().unwrap(), None);
+        assert_eq!(listener.notifications().next_timeout(Duration::from_millis(50)).unwrap(), None);
+
+        notifier.query("NOTIFY test_notifications, 'first';").unwrap();
+        let notification = listener.notifications().next_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(notification.channel, "test_notifications");
+        assert_eq!(notification.payload, "first");
+
+        notifier.query("NOTIFY test_notifications, 'second';").unwrap();
+        let notification = listener.notifications().next().unwrap().unwrap();
+        assert_eq!(notification.payload, "second");
+    }
+
+    #[test]
+    fn test_notifications_buffered_during_query() {
+        let server = MockBackend::new().on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]])).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        conn.query("LISTEN test_buffered;").unwrap();
+        conn.query("NOTIFY test_buffered, 'to myself';").unwrap();
+        let data = conn.query("SELECT 1;").unwrap();
+        assert_eq!(data, vec![vec!["1".to_string()]]);
+        let notification = conn.notifications().try_next().unwrap().unwrap();
+        assert_eq!(notification.payload, "to myself");
+        assert_eq!(conn.notifications().try_next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_copy_in() {
+        let server = MockBackend::new().on_query("COPY numbers FROM STDIN;", Response::CopyIn(FieldFormat::Text)).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let count = {
+            let mut writer = conn.copy_in("COPY numbers FROM STDIN;").unwrap();
+            writer.write_all(b"1\n2\n3\n").unwrap();
+            writer.finish().unwrap()
+        };
+        assert_eq!(count, 3);
+        let received = server.received();
+        assert!(received.contains(&Received::CopyData(b"1\n2\n3\n".to_vec())));
+        assert!(received.contains(&Received::CopyDone));
+    }
+
+    #[test]
+    fn test_copy_in_csv() {
+        let sql = "COPY presidents FROM STDIN WITH (FORMAT csv, HEADER, DELIMITER '|');";
+        let server = MockBackend::new().on_query(sql, Response::CopyIn(FieldFormat::Text)).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        let count = {
+            let mut writer = conn.copy_in(sql).unwrap();
+            let mut csv = File::open("test/data/presidents.csv").unwrap();
+            io::copy(&mut csv, &mut writer).unwrap();
+            writer.finish().unwrap()
+        };
+        assert_eq!(count, 44);
+        let mut expected = vec![];
+        File::open("test/data/presidents.csv").unwrap().read_to_end(&mut expected).unwrap();
+        let received: Vec<u8> = server.received().into_iter().filter_map(|msg| match msg {
+            Received::CopyData(data) => Some(data),
+            _ => None,
+        }).flat_map(|data| data).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_copy_in_aborted_on_drop() {
+        let server = MockBackend::new()
+            .on_query("COPY numbers FROM STDIN;", Response::CopyIn(FieldFormat::Text))
+            .on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut writer = conn.copy_in("COPY numbers FROM STDIN;").unwrap();
+            writer.write_all(b"1\n2\n3\n").unwrap();
+        }
+        assert!(server.received().contains(&Received::CopyFail("COPY aborted by client".to_string())));
+        assert!(!server.received().contains(&Received::CopyDone));
+        assert_eq!(conn.query("SELECT 1;").unwrap(), vec![vec!["1".to_string()]]);
+    }
+
+    #[test]
+    fn test_copy_in_bad_data() {
+        let server = MockBackend::new()
+            .on_query("COPY numbers FROM STDIN;", Response::CopyInError("22P02".to_string(), "invalid input syntax for type integer: \"two\"".to_string()))
+            .on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]]))
+            .on_query("SELECT count(*) FROM numbers;", Response::rows(&["count"], &[&["0"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        {
+            let mut writer = conn.copy_in("COPY numbers FROM STDIN;").unwrap();
+            writer.write_all(b"1\ntwo\n").unwrap();
+            match writer.finish() {
+                Err(err) => assert_eq!(err.code(), Some("22P02")),
+                Ok(count) => panic!("Expected the data to be refused, got {}", count),
+            }
+        }
+        assert!(conn.copy_in("SELECT 1;").is_err());
+        let data = conn.query("SELECT count(*) FROM numbers;").unwrap();
+        assert_eq!(data, vec![vec!["0".to_string()]]);
+    }
+
+    #[test]
+    fn test_copy_out() {
+        let chunks = vec![b"1\t1\n".to_vec(), b"2\t4\n".to_vec(), b"3\t9\n".to_vec()];
+        let server = MockBackend::new()
+            .on_query("COPY squares TO STDOUT;", Response::CopyOut(FieldFormat::Text, chunks))
+            .on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let mut output = String::new();
+        let rows = {
+            let mut reader = conn.copy_out("COPY squares TO STDOUT;").unwrap();
+            assert_eq!(reader.format(), FieldFormat::Text);
+            reader.read_to_string(&mut output).unwrap();
+            reader.finish().unwrap()
+        };
+        assert_eq!(output, "1\t1\n2\t4\n3\t9\n");
+        assert_eq!(rows, 3);
+        let data = conn.query("SELECT 1;").unwrap();
+        assert_eq!(data, vec![vec!["1".to_string()]]);
+    }
+
+    #[test]
+    fn test_copy_out_binary() {
+        let mut rows = BinaryCopyWriter::new(vec![]);
+        rows.write_row(&[&1i32]).unwrap();
+        let data = rows.finish().unwrap();
+        let sql = "COPY (SELECT 1) TO STDOUT WITH (FORMAT binary);";
+        let server = MockBackend::new().on_query(sql, Response::CopyOut(FieldFormat::Binary, vec![data.clone()])).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).expect("Could not establish connection");
+        let mut output = vec![];
+        let count = {
+            let mut reader = conn.copy_out(sql).unwrap();
+            assert_eq!(reader.format(), FieldFormat::Binary);
+            reader.read_to_end(&mut output).unwrap();
+            reader.finish().unwrap()
+        };
+        assert_eq!(&output[..11], b"PGCOPY\n\xff\r\n\0");
+        assert_eq!(output, data);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_copy_out_dropped_early() {
+        let chunks = (1..1000).map(|n| format!("{}\n", n).into_bytes()).collect();
+        let server = MockBackend::new()
+            .on_query("COPY numbers TO STDOUT;", Response::CopyOut(FieldFormat::Text, chunks))
+            .on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]]))
+            .on_query("SELECT 2;", Response::rows(&["n"], &[&["2"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut reader = conn.copy_out("COPY numbers TO STDOUT;").unwrap();
+            let mut start = [0; 2];
+            reader.read_exact(&mut start).unwrap();
+            assert_eq!(&start, b"1\n");
+        }
+        assert!(conn.copy_out("SELECT 1;").is_err());
+        let data = conn.query("SELECT 2;").unwrap();
+        assert_eq!(data, vec![vec!["2".to_string()]]);
+    }
+
+    #[test]
+    fn test_transaction_status() {
+        let server = MockBackend::new().on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]])).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        conn.query("BEGIN;").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::InTransaction);
+        assert!(conn.query("SELECT * FROM no_such_table;").is_err());
+        assert_eq!(conn.transaction_status(), TransactionStatus::Failed);
+        match conn.query("SELECT 1;") {
+            Err(PgError::FailedTransaction) => {},
+            other => panic!("Expected failed transaction error, got {:?}", other),
+        }
+        assert!(conn.copy_out("COPY (SELECT 1) TO STDOUT;").is_err());
+        conn.query("  rollback;").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        assert_eq!(conn.query("SELECT 1;").unwrap(), vec![vec!["1".to_string()]]);
+        assert_eq!(server.queries(), vec!["BEGIN;", "SELECT * FROM no_such_table;", "  rollback;", "SELECT 1;"]);
     }
 }
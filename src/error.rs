@@ -3,12 +3,85 @@ use std::io;
 use std::str::Utf8Error;
 use std::error::Error;
 
+/// An error reported by the server in an ErrorResponse message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DbError {
+    pub severity: String,
+    pub code: String,
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+}
+
+impl DbError {
+    /// An error with severity ERROR, as a server would report it.
+    pub fn new(code: &str, message: &str) -> DbError {
+        DbError {
+            severity: "ERROR".to_string(),
+            code: code.to_string(),
+            message: message.to_string(),
+            detail: None,
+            hint: None,
+        }
+    }
+
+    /// Build an error from the (field type, value) pairs of an
+    /// ErrorResponse.
+    pub fn from_fields(fields: &[(u8, &str)]) -> DbError {
+        let field = |code: u8| {
+            fields.iter().find(|&&(field_code, _)| field_code == code).map(|&(_, value)| value.to_string())
+        };
+        DbError {
+            severity: field(b'V').or_else(|| field(b'S')).unwrap_or_default(),
+            code: field(b'C').unwrap_or_default(),
+            message: field(b'M').unwrap_or_default(),
+            detail: field(b'D'),
+            hint: field(b'H'),
+        }
+    }
+
+    /// The (field type, value) pairs to send in an ErrorResponse.
+    pub fn fields(&self) -> Vec<(u8, &str)> {
+        let mut fields = vec![
+            (b'S', &self.severity[..]),
+            (b'V', &self.severity[..]),
+            (b'C', &self.code[..]),
+            (b'M', &self.message[..]),
+        ];
+        if let Some(ref detail) = self.detail {
+            fields.push((b'D', detail));
+        }
+        if let Some(ref hint) = self.hint {
+            fields.push((b'H', hint));
+        }
+        fields
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} (SQLSTATE {})", self.severity, self.message, self.code)
+    }
+}
+
 #[derive(Debug)]
 pub enum PgError {
     Io(io::Error),
     Utf8(Utf8Error),
+    Db(DbError),
     Error(String),
+    /// The server sent something which breaks the protocol.
+    Protocol(String),
+    /// The server sent a message of this many bytes, more than the
+    /// connection's `max_message_size`.
+    MessageTooLarge(usize),
+    /// A query's rows came to more than this many bytes, the connection's
+    /// `max_result_size`.
+    ResultTooLarge(usize),
     Unauthenticated,
+    Timeout,
+    FailedTransaction,
+    PoolTimeout,
     Other,
 }
 
@@ -17,8 +90,15 @@ impl fmt::Display for PgError {
         match *self {
             PgError::Io(ref err) => err.fmt(f),
             PgError::Utf8(ref err) => err.fmt(f),
+            PgError::Db(ref err) => err.fmt(f),
             PgError::Error(ref string) => write!(f, "Error: {:?}", string),
+            PgError::Protocol(ref string) => write!(f, "Protocol error: {}", string),
+            PgError::MessageTooLarge(size) => write!(f, "Server sent a message of {} bytes, more than the limit", size),
+            PgError::ResultTooLarge(limit) => write!(f, "Query result is larger than the limit of {} bytes", limit),
             PgError::Unauthenticated => write!(f, "Unauthenticated"),
+            PgError::Timeout => write!(f, "Query timed out"),
+            PgError::FailedTransaction => write!(f, "Current transaction is aborted; roll it back before running more queries"),
+            PgError::PoolTimeout => write!(f, "Timed out waiting for a connection from the pool"),
             PgError::Other => write!(f, "An unknown error occured"),
         }
     }
@@ -29,8 +109,15 @@ impl Error for PgError {
         match *self {
             PgError::Io(ref err) => err.description(),
             PgError::Utf8(ref err) => err.description(),
+            PgError::Db(ref err) => &err.message,
             PgError::Error(ref string) => string,
+            PgError::Protocol(ref string) => string,
+            PgError::MessageTooLarge(_) => "Server sent a message larger than the limit",
+            PgError::ResultTooLarge(_) => "Query result is larger than the limit",
             PgError::Unauthenticated => "Unauthenticated",
+            PgError::Timeout => "Query timed out",
+            PgError::FailedTransaction => "Current transaction is aborted",
+            PgError::PoolTimeout => "Timed out waiting for a connection from the pool",
             PgError::Other => "An error occurred",
         }
     }
@@ -39,13 +126,30 @@ impl Error for PgError {
         match *self {
             PgError::Io(ref err) => Some(err),
             PgError::Utf8(ref err) => Some(err),
+            PgError::Db(..) => None,
             PgError::Error(..) => None,
+            PgError::Protocol(..) => None,
+            PgError::MessageTooLarge(..) => None,
+            PgError::ResultTooLarge(..) => None,
             PgError::Unauthenticated => None,
+            PgError::Timeout => None,
+            PgError::FailedTransaction => None,
+            PgError::PoolTimeout => None,
             PgError::Other => None,
         }
     }
 }
 
+impl PgError {
+    /// The SQLSTATE code of an error reported by the server.
+    pub fn code(&self) -> Option<&str> {
+        match *self {
+            PgError::Db(ref err) => Some(&err.code),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for PgError {
     fn from(err: io::Error) -> PgError {
         PgError::Io(err)
@@ -57,3 +161,32 @@ impl From<Utf8Error> for PgError {
         PgError::Utf8(err)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_error_from_fields() {
+        let err = DbError::from_fields(&[
+            (b'S', "ERREUR"),
+            (b'V', "ERROR"),
+            (b'C', "42P01"),
+            (b'M', "relation \"nope\" does not exist"),
+            (b'P', "15"),
+        ]);
+        assert_eq!(err.severity, "ERROR");
+        assert_eq!(err.code, "42P01");
+        assert_eq!(err.message, "relation \"nope\" does not exist");
+        assert_eq!(err.detail, None);
+        assert_eq!(PgError::Db(err).code(), Some("42P01"));
+        assert_eq!(PgError::Timeout.code(), None);
+    }
+
+    #[test]
+    fn test_db_error_fields_round_trip() {
+        let mut err = DbError::new("42P01", "relation \"nope\" does not exist");
+        err.hint = Some("Check the spelling.".to_string());
+        assert_eq!(DbError::from_fields(&err.fields()), err);
+    }
+}
@@ -1,11 +1,38 @@
 extern crate crypto;
+extern crate rand;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[macro_use]
+extern crate tracing;
 use std::result;
-pub use connection::Connection;
+pub use connection::{Connection, CopyInWriter, CopyOutReader, Notification};
+pub use cancel::CancelToken;
+pub use config::Config;
+pub use metrics::{Instrumentation, Metrics, MetricsCollector};
+pub use pool::{Pool, PoolBuilder, PooledConnection};
+pub use secret::Secret;
+pub use servermsg::TransactionStatus;
+pub use transaction::{IsolationLevel, PreparedTransaction, RetryOptions, Transaction, TransactionBuilder};
+pub use transport::Transport;
 
 pub mod connection;
 pub mod error;
 pub mod message;
 pub mod servermsg;
 pub mod auth;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub mod binary_copy;
+pub mod cancel;
+pub mod config;
+pub mod metrics;
+pub mod pool;
+pub mod protocol;
+pub mod secret;
+pub mod server;
+pub mod testing;
+pub mod transaction;
+pub mod transport;
+pub mod types;
 
 pub type Result<T> = result::Result<T, error::PgError>;
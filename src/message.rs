@@ -1,4 +1,10 @@
+use std::io;
+use std::io::Read;
 use std::mem::transmute;
+use std::str::from_utf8;
+use Result;
+use error::PgError;
+use servermsg::FieldFormat;
 
 pub trait Message {
     fn get_id(&self) -> Option<u8>;
@@ -29,12 +35,59 @@ pub struct StartupMessage<'a> {
     pub params: Vec<(String, String)>,
 }
 
-fn extend_string(body: &mut Vec<u8>, s: &str) {
+pub(crate) fn extend_string(body: &mut Vec<u8>, s: &str) {
     body.extend(s.as_bytes());
     body.push(0);
 
 }
 
+pub(crate) fn extend_u32(body: &mut Vec<u8>, value: u32) {
+    let bytes: [u8; 4] = unsafe { transmute(value.to_be()) };
+    body.extend(bytes.iter());
+}
+
+pub(crate) fn extend_i32(body: &mut Vec<u8>, value: i32) {
+    extend_u32(body, value as u32);
+}
+
+pub(crate) fn extend_u16(body: &mut Vec<u8>, value: u16) {
+    body.push((value >> 8) as u8);
+    body.push(value as u8);
+}
+
+pub(crate) fn extend_i16(body: &mut Vec<u8>, value: i16) {
+    extend_u16(body, value as u16);
+}
+
+/// Prefix a message body with its identifier and length.
+pub(crate) fn frame(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(5 + body.len());
+    bytes.push(id);
+    extend_u32(&mut bytes, body.len() as u32 + 4);
+    bytes.extend(body);
+    bytes
+}
+
+fn extend_formats(body: &mut Vec<u8>, formats: &[FieldFormat]) {
+    extend_u16(body, formats.len() as u16);
+    for format in formats {
+        extend_u16(body, format.code());
+    }
+}
+
+fn extend_values(body: &mut Vec<u8>, values: &[Option<&[u8]>]) {
+    extend_u16(body, values.len() as u16);
+    for value in values {
+        match *value {
+            Some(value) => {
+                extend_u32(body, value.len() as u32);
+                body.extend(value);
+            },
+            None => extend_i32(body, -1),
+        }
+    }
+}
+
 impl <'a> Message for StartupMessage<'a> {
     fn get_id(&self) -> Option<u8> {
         None
@@ -73,6 +126,75 @@ impl <'a> Message for PasswordMessage<'a> {
         body
     }
 }
+
+impl <'a> PasswordMessage<'a> {
+    /// Read the body of a password message sent for cleartext or MD5
+    /// authentication.
+    pub fn from_body(body: &'a [u8]) -> Result<PasswordMessage<'a>> {
+        let mut reader = Reader::new(body);
+        let hash = try!(reader.cstring());
+        try!(reader.finish("password message"));
+        Ok(PasswordMessage { hash: hash })
+    }
+}
+
+/// The first message of SASL authentication, naming the mechanism chosen.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SaslInitialResponse<'a> {
+    pub mechanism: &'a str,
+    pub data: Option<&'a [u8]>,
+}
+
+impl <'a> Message for SaslInitialResponse<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x70) // 'p'
+    }
+
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec!();
+        extend_string(&mut body, self.mechanism);
+        match self.data {
+            Some(data) => {
+                extend_u32(&mut body, data.len() as u32);
+                body.extend(data);
+            },
+            None => extend_i32(&mut body, -1),
+        }
+        body
+    }
+}
+
+impl <'a> SaslInitialResponse<'a> {
+    pub fn from_body(body: &'a [u8]) -> Result<SaslInitialResponse<'a>> {
+        let mut reader = Reader::new(body);
+        let mechanism = try!(reader.cstring());
+        let data = try!(reader.value());
+        try!(reader.finish("SASL initial response"));
+        Ok(SaslInitialResponse { mechanism: mechanism, data: data })
+    }
+}
+
+/// A later message of SASL authentication.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SaslResponse<'a> {
+    pub data: &'a [u8],
+}
+
+impl <'a> Message for SaslResponse<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x70) // 'p'
+    }
+
+    fn get_body(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+}
+
+impl <'a> SaslResponse<'a> {
+    pub fn from_body(body: &'a [u8]) -> Result<SaslResponse<'a>> {
+        Ok(SaslResponse { data: body })
+    }
+}
         
 #[derive(Debug, Eq, PartialEq)]
 pub struct Terminate;
@@ -99,6 +221,552 @@ impl Message for Query {
     }
 }
 
+#[derive(Debug, Eq, PartialEq)]
+pub struct CopyData<'a> {
+    pub data: &'a [u8],
+}
+
+impl <'a> Message for CopyData<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x64)  // 'd'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        self.data.to_vec()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct CopyDone;
+
+impl Message for CopyDone {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x63)  // 'c'
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct CopyFail<'a> {
+    pub message: &'a str,
+}
+
+impl <'a> Message for CopyFail<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x66)  // 'f'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.message.len() + 1);
+        extend_string(&mut body, self.message);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct CancelRequest<'a> {
+    pub pid: u32,
+    pub key: &'a [u8],
+}
+
+impl <'a> Message for CancelRequest<'a> {
+    fn get_id(&self) -> Option<u8> {
+        None
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(8 + self.key.len());
+        extend_u32(&mut body, 80877102);
+        extend_u32(&mut body, self.pid);
+        body.extend(self.key);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SslRequest;
+
+impl Message for SslRequest {
+    fn get_id(&self) -> Option<u8> {
+        None
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(4);
+        extend_u32(&mut body, 80877103);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct GssEncRequest;
+
+impl Message for GssEncRequest {
+    fn get_id(&self) -> Option<u8> {
+        None
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(4);
+        extend_u32(&mut body, 80877104);
+        body
+    }
+}
+
+/// Whether a Describe or Close message refers to a prepared statement or a
+/// portal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Target {
+    Statement,
+    Portal,
+}
+
+impl Target {
+    fn from_code(code: u8) -> Result<Target> {
+        match code {
+            b'S' => Ok(Target::Statement),
+            b'P' => Ok(Target::Portal),
+            _ => Err(PgError::Protocol(format!("Invalid target: {:?}", code))),
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match *self {
+            Target::Statement => b'S',
+            Target::Portal => b'P',
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Parse<'a> {
+    pub name: &'a str,
+    pub query: &'a str,
+    pub param_types: Vec<u32>,
+}
+
+impl <'a> Message for Parse<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x50)  // 'P'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec!();
+        extend_string(&mut body, self.name);
+        extend_string(&mut body, self.query);
+        extend_u16(&mut body, self.param_types.len() as u16);
+        for &oid in &self.param_types {
+            extend_u32(&mut body, oid);
+        }
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Bind<'a> {
+    pub portal: &'a str,
+    pub statement: &'a str,
+    pub param_formats: Vec<FieldFormat>,
+    pub params: Vec<Option<&'a [u8]>>,
+    pub result_formats: Vec<FieldFormat>,
+}
+
+impl <'a> Message for Bind<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x42)  // 'B'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec!();
+        extend_string(&mut body, self.portal);
+        extend_string(&mut body, self.statement);
+        extend_formats(&mut body, &self.param_formats);
+        extend_values(&mut body, &self.params);
+        extend_formats(&mut body, &self.result_formats);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Describe<'a> {
+    pub target: Target,
+    pub name: &'a str,
+}
+
+impl <'a> Message for Describe<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x44)  // 'D'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec![self.target.code()];
+        extend_string(&mut body, self.name);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Execute<'a> {
+    pub portal: &'a str,
+    /// The most rows to return, or zero for no limit.
+    pub max_rows: u32,
+}
+
+impl <'a> Message for Execute<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x45)  // 'E'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec!();
+        extend_string(&mut body, self.portal);
+        extend_u32(&mut body, self.max_rows);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Close<'a> {
+    pub target: Target,
+    pub name: &'a str,
+}
+
+impl <'a> Message for Close<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x43)  // 'C'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec![self.target.code()];
+        extend_string(&mut body, self.name);
+        body
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Sync;
+
+impl Message for Sync {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x53)  // 'S'
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Flush;
+
+impl Message for Flush {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x48)  // 'H'
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct FunctionCall<'a> {
+    pub oid: u32,
+    pub arg_formats: Vec<FieldFormat>,
+    pub args: Vec<Option<&'a [u8]>>,
+    pub result_format: FieldFormat,
+}
+
+impl <'a> Message for FunctionCall<'a> {
+    fn get_id(&self) -> Option<u8> {
+        Some(0x46)  // 'F'
+    }
+    fn get_body(&self) -> Vec<u8> {
+        let mut body = vec!();
+        extend_u32(&mut body, self.oid);
+        extend_formats(&mut body, &self.arg_formats);
+        extend_values(&mut body, &self.args);
+        extend_u16(&mut body, self.result_format.code());
+        body
+    }
+}
+
+/// A message sent by a client, as a server or proxy sees it.
+#[derive(Debug, Eq, PartialEq)]
+pub enum FrontendMsg<'a> {
+    Startup(StartupMessage<'a>),
+    SslRequest,
+    GssEncRequest,
+    CancelRequest(CancelRequest<'a>),
+    /// A password, GSS or SASL response.  Which one depends on the
+    /// authentication the server asked for, so the body is left for
+    /// `PasswordMessage::from_body` and friends.
+    Password(&'a [u8]),
+    Query(Query),
+    Parse(Parse<'a>),
+    Bind(Bind<'a>),
+    Describe(Describe<'a>),
+    Execute(Execute<'a>),
+    Close(Close<'a>),
+    Sync,
+    Flush,
+    CopyData(CopyData<'a>),
+    CopyDone,
+    CopyFail(CopyFail<'a>),
+    FunctionCall(FunctionCall<'a>),
+    Terminate,
+    Unknown(u8, &'a [u8]),
+}
+
+impl <'a> FrontendMsg<'a> {
+    /// Parse one complete message.  The messages which open a connection
+    /// have no identifier, and are recognised by their leading zero byte.
+    pub fn from_slice(message: &'a [u8]) -> Result<FrontendMsg<'a>> {
+        if message.get(0) == Some(&0) {
+            return FrontendMsg::from_startup_slice(message);
+        }
+        if message.len() < 5 || message.len() != 1 + read_u32(&message[1..5]) as usize {
+            return Err(PgError::Protocol(format!("Wrong length for message: {:?}", message)))
+        }
+        let id = message[0];
+        let body = &message[5..];
+        let mut reader = Reader::new(body);
+        let msg = match id {
+            b'p' => return Ok(FrontendMsg::Password(body)),
+            b'Q' => FrontendMsg::Query(Query { query: try!(reader.cstring()).to_string() }),
+            b'P' => {
+                let name = try!(reader.cstring());
+                let query = try!(reader.cstring());
+                let count = try!(reader.u16());
+                let mut param_types = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    param_types.push(try!(reader.u32()));
+                }
+                FrontendMsg::Parse(Parse { name: name, query: query, param_types: param_types })
+            },
+            b'B' => {
+                let portal = try!(reader.cstring());
+                let statement = try!(reader.cstring());
+                let param_formats = try!(reader.formats());
+                let params = try!(reader.values());
+                let result_formats = try!(reader.formats());
+                FrontendMsg::Bind(Bind {
+                    portal: portal,
+                    statement: statement,
+                    param_formats: param_formats,
+                    params: params,
+                    result_formats: result_formats,
+                })
+            },
+            b'D' => {
+                let target = try!(Target::from_code(try!(reader.u8())));
+                FrontendMsg::Describe(Describe { target: target, name: try!(reader.cstring()) })
+            },
+            b'E' => {
+                let portal = try!(reader.cstring());
+                FrontendMsg::Execute(Execute { portal: portal, max_rows: try!(reader.u32()) })
+            },
+            b'C' => {
+                let target = try!(Target::from_code(try!(reader.u8())));
+                FrontendMsg::Close(Close { target: target, name: try!(reader.cstring()) })
+            },
+            b'S' => FrontendMsg::Sync,
+            b'H' => FrontendMsg::Flush,
+            b'd' => return Ok(FrontendMsg::CopyData(CopyData { data: body })),
+            b'c' => FrontendMsg::CopyDone,
+            b'f' => FrontendMsg::CopyFail(CopyFail { message: try!(reader.cstring()) }),
+            b'F' => {
+                let oid = try!(reader.u32());
+                let arg_formats = try!(reader.formats());
+                let args = try!(reader.values());
+                let result_format = try!(FieldFormat::from_code(try!(reader.u16())));
+                FrontendMsg::FunctionCall(FunctionCall {
+                    oid: oid,
+                    arg_formats: arg_formats,
+                    args: args,
+                    result_format: result_format,
+                })
+            },
+            b'X' => FrontendMsg::Terminate,
+            _ => return Ok(FrontendMsg::Unknown(id, body)),
+        };
+        try!(reader.finish("message"));
+        Ok(msg)
+    }
+
+    fn from_startup_slice(message: &'a [u8]) -> Result<FrontendMsg<'a>> {
+        if message.len() < 8 || message.len() != read_u32(&message[..4]) as usize {
+            return Err(PgError::Protocol(format!("Wrong length for startup message: {:?}", message)))
+        }
+        let mut reader = Reader::new(&message[8..]);
+        let msg = match read_u32(&message[4..8]) {
+            196608 => {
+                let mut user = None;
+                let mut database = None;
+                let mut params = vec![];
+                loop {
+                    let name = try!(reader.cstring());
+                    if name.is_empty() {
+                        break;
+                    }
+                    let value = try!(reader.cstring());
+                    match name {
+                        "user" => user = Some(value),
+                        "database" => database = Some(value),
+                        _ => params.push((name.to_string(), value.to_string())),
+                    }
+                }
+                match user {
+                    Some(user) => FrontendMsg::Startup(StartupMessage {
+                        user: user,
+                        database: database,
+                        params: params,
+                    }),
+                    None => return Err(PgError::Protocol("No user in startup message".to_string())),
+                }
+            },
+            80877102 => {
+                let pid = try!(reader.u32());
+                return Ok(FrontendMsg::CancelRequest(CancelRequest { pid: pid, key: reader.rest() }))
+            },
+            80877103 => FrontendMsg::SslRequest,
+            80877104 => FrontendMsg::GssEncRequest,
+            version => {
+                return Err(PgError::Protocol(format!("Unsupported protocol version: {}", version)))
+            },
+        };
+        try!(reader.finish("startup message"));
+        Ok(msg)
+    }
+
+    /// Encode the message as the client would send it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match *self {
+            FrontendMsg::Startup(ref msg) => msg.to_bytes(),
+            FrontendMsg::SslRequest => SslRequest.to_bytes(),
+            FrontendMsg::GssEncRequest => GssEncRequest.to_bytes(),
+            FrontendMsg::CancelRequest(ref msg) => msg.to_bytes(),
+            FrontendMsg::Password(body) => frame(b'p', body),
+            FrontendMsg::Query(ref msg) => msg.to_bytes(),
+            FrontendMsg::Parse(ref msg) => msg.to_bytes(),
+            FrontendMsg::Bind(ref msg) => msg.to_bytes(),
+            FrontendMsg::Describe(ref msg) => msg.to_bytes(),
+            FrontendMsg::Execute(ref msg) => msg.to_bytes(),
+            FrontendMsg::Close(ref msg) => msg.to_bytes(),
+            FrontendMsg::Sync => Sync.to_bytes(),
+            FrontendMsg::Flush => Flush.to_bytes(),
+            FrontendMsg::CopyData(ref msg) => msg.to_bytes(),
+            FrontendMsg::CopyDone => CopyDone.to_bytes(),
+            FrontendMsg::CopyFail(ref msg) => msg.to_bytes(),
+            FrontendMsg::FunctionCall(ref msg) => msg.to_bytes(),
+            FrontendMsg::Terminate => Terminate.to_bytes(),
+            FrontendMsg::Unknown(id, body) => frame(id, body),
+        }
+    }
+}
+
+/// Split the first complete message sent by a client off `input`.  Before
+/// startup, messages have no identifier byte.
+pub fn take_frontend_msg(input: &[u8], startup: bool) -> Result<(&[u8], &[u8])> {
+    let offset = if startup { 0 } else { 1 };
+    if input.len() < offset + 4 {
+        return Err(PgError::Protocol(format!("Input too short: {:?}", input)))
+    }
+    let length = offset + read_u32(&input[offset..offset + 4]) as usize;
+    if length < offset + 4 || input.len() < length {
+        Err(PgError::Protocol(format!("Message too short: {:?}", input)))
+    } else {
+        Ok(input.split_at(length))
+    }
+}
+
+/// Read one complete message sent by a client.  Before startup, messages
+/// have no identifier byte.
+pub fn read_frontend_message<R: Read>(reader: &mut R, startup: bool) -> io::Result<Vec<u8>> {
+    let offset = if startup { 0 } else { 1 };
+    let mut bytes = vec![0; offset + 4];
+    try!(reader.read_exact(&mut bytes));
+    let length = read_u32(&bytes[offset..]) as usize;
+    let limit = if startup { 10000 } else { 1 << 30 };
+    if length < 4 || length > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid message length: {}", length)));
+    }
+    bytes.resize(offset + length, 0);
+    try!(reader.read_exact(&mut bytes[offset + 4..]));
+    Ok(bytes)
+}
+
+fn read_u32(input: &[u8]) -> u32 {
+    input.iter().fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+/// Reads the fields of a message body, failing rather than panicking when
+/// the body is too short.
+struct Reader<'a> {
+    input: &'a [u8],
+}
+
+impl <'a> Reader<'a> {
+    fn new(input: &'a [u8]) -> Reader<'a> {
+        Reader { input: input }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.input.len() < count {
+            return Err(PgError::Protocol(format!("Message ended early: expected {} more bytes", count)))
+        }
+        let (taken, rest) = self.input.split_at(count);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.take(2).map(|bytes| read_u32(bytes) as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.take(4).map(read_u32)
+    }
+
+    fn cstring(&mut self) -> Result<&'a str> {
+        match self.input.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                let string = try!(from_utf8(&self.input[..end]).map_err(|err| {
+                    PgError::Protocol(format!("Invalid UTF-8 in string: {}", err))
+                }));
+                self.input = &self.input[end + 1..];
+                Ok(string)
+            },
+            None => Err(PgError::Protocol("null byte not found".to_string())),
+        }
+    }
+
+    /// A length-prefixed value, where a length of -1 means NULL.
+    fn value(&mut self) -> Result<Option<&'a [u8]>> {
+        match try!(self.u32()) {
+            0xffffffff => Ok(None),
+            length => self.take(length as usize).map(Some),
+        }
+    }
+
+    fn values(&mut self) -> Result<Vec<Option<&'a [u8]>>> {
+        let count = try!(self.u16());
+        let mut values = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            values.push(try!(self.value()));
+        }
+        Ok(values)
+    }
+
+    fn formats(&mut self) -> Result<Vec<FieldFormat>> {
+        let count = try!(self.u16());
+        let mut formats = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            formats.push(try!(FieldFormat::from_code(try!(self.u16()))));
+        }
+        Ok(formats)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.input;
+        self.input = &[];
+        rest
+    }
+
+    fn finish(&self, name: &str) -> Result<()> {
+        if self.input.is_empty() {
+            Ok(())
+        } else {
+            Err(PgError::Protocol(format!("Unexpected extra data in {}: {:?}", name, self.input)))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -148,4 +816,184 @@ mod tests {
             b"Q\0\0\0\x0dSELECT 1\0".to_vec()
         );
     }
+
+    #[test]
+    fn test_copy_data() {
+        let msg = CopyData {
+            data: b"a|b\n",
+        };
+        assert_eq!(
+            msg.to_bytes(),
+            b"d\0\0\0\x08a|b\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_copy_done() {
+        assert_eq!(
+            CopyDone.to_bytes(),
+            b"c\0\0\0\x04".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_copy_fail() {
+        let msg = CopyFail {
+            message: "oops",
+        };
+        assert_eq!(
+            msg.to_bytes(),
+            b"f\0\0\0\x09oops\0".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let msg = CancelRequest {
+            pid: 0x17bb,
+            key: b"\x15b\xfb1",
+        };
+        assert_eq!(
+            msg.to_bytes(),
+            b"\0\0\0\x10\x04\xd2\x16\x2e\0\0\x17\xbb\x15b\xfb1".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_cancel_request_with_long_key() {
+        let key = [7u8; 32];
+        let msg = CancelRequest {
+            pid: 1,
+            key: &key,
+        };
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes.len(), 44);
+        assert_eq!(&bytes[..12], b"\0\0\0\x2c\x04\xd2\x16\x2e\0\0\0\x01");
+        assert_eq!(&bytes[12..], &key[..]);
+    }
+
+    #[test]
+    fn test_ssl_request() {
+        assert_eq!(
+            SslRequest.to_bytes(),
+            b"\0\0\0\x08\x04\xd2\x16\x2f".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_frontend_round_trip() {
+        let messages = vec![
+            FrontendMsg::Startup(StartupMessage {
+                user: "cliff",
+                database: Some("app"),
+                params: vec![("application_name".to_string(), "test".to_string())],
+            }),
+            FrontendMsg::SslRequest,
+            FrontendMsg::GssEncRequest,
+            FrontendMsg::CancelRequest(CancelRequest { pid: 1234, key: b"\x15b\xfb1" }),
+            FrontendMsg::Password(b"md5abc\0"),
+            FrontendMsg::Query(Query { query: "SELECT 1".to_string() }),
+            FrontendMsg::Parse(Parse { name: "s1", query: "SELECT $1", param_types: vec![23] }),
+            FrontendMsg::Bind(Bind {
+                portal: "",
+                statement: "s1",
+                param_formats: vec![FieldFormat::Binary],
+                params: vec![Some(b"\0\0\0\x05"), None],
+                result_formats: vec![FieldFormat::Text],
+            }),
+            FrontendMsg::Describe(Describe { target: Target::Statement, name: "s1" }),
+            FrontendMsg::Execute(Execute { portal: "", max_rows: 10 }),
+            FrontendMsg::Close(Close { target: Target::Portal, name: "" }),
+            FrontendMsg::Sync,
+            FrontendMsg::Flush,
+            FrontendMsg::CopyData(CopyData { data: b"a|b\n" }),
+            FrontendMsg::CopyDone,
+            FrontendMsg::CopyFail(CopyFail { message: "oops" }),
+            FrontendMsg::FunctionCall(FunctionCall {
+                oid: 1598,
+                arg_formats: vec![],
+                args: vec![Some(b"x")],
+                result_format: FieldFormat::Text,
+            }),
+            FrontendMsg::Terminate,
+            FrontendMsg::Unknown(b'?', b"data"),
+        ];
+        for msg in messages {
+            let bytes = msg.to_bytes();
+            let startup = bytes[0] == 0;
+            let (next, rest) = take_frontend_msg(&bytes, startup).unwrap();
+            assert_eq!(rest.len(), 0);
+            assert_eq!(FrontendMsg::from_slice(next).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_frontend_from_client_encoding() {
+        let bytes = Query { query: "SELECT 1".to_string() }.to_bytes();
+        assert_eq!(
+            FrontendMsg::from_slice(&bytes).unwrap(),
+            FrontendMsg::Query(Query { query: "SELECT 1".to_string() })
+        );
+        let bytes = Terminate.to_bytes();
+        assert_eq!(FrontendMsg::from_slice(&bytes).unwrap(), FrontendMsg::Terminate);
+    }
+
+    #[test]
+    fn test_password_bodies() {
+        let bytes = PasswordMessage { hash: "open sesame" }.to_bytes();
+        match FrontendMsg::from_slice(&bytes).unwrap() {
+            FrontendMsg::Password(body) => {
+                assert_eq!(PasswordMessage::from_body(body).unwrap(), PasswordMessage { hash: "open sesame" });
+            },
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+
+        let initial = SaslInitialResponse { mechanism: "SCRAM-SHA-256", data: Some(b"n,,n=,r=abc") };
+        let bytes = initial.to_bytes();
+        match FrontendMsg::from_slice(&bytes).unwrap() {
+            FrontendMsg::Password(body) => assert_eq!(SaslInitialResponse::from_body(body).unwrap(), initial),
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+
+        let response = SaslResponse { data: b"c=biws,r=abc,p=xyz" };
+        let bytes = response.to_bytes();
+        match FrontendMsg::from_slice(&bytes).unwrap() {
+            FrontendMsg::Password(body) => assert_eq!(SaslResponse::from_body(body).unwrap(), response),
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_frontend_truncated() {
+        let bytes = Bind {
+            portal: "",
+            statement: "",
+            param_formats: vec![],
+            params: vec![Some(b"12345")],
+            result_formats: vec![],
+        }.to_bytes();
+        for end in 0..bytes.len() {
+            match FrontendMsg::from_slice(&bytes[..end]) {
+                Err(PgError::Protocol(_)) => {},
+                other => panic!("Expected a protocol error for {:?}, got {:?}", &bytes[..end], other),
+            }
+        }
+        // Lengths which overstate the data that follows, and a query which
+        // is not UTF-8.
+        let invalid: &[&[u8]] = &[
+            b"B\0\0\0\x0c\0\0\0\0\0\x01\0\0\0\x09",
+            b"\0\0\0\x08\0\x02\0\0",
+            b"Q\0\0\0\x07\xff\xfe\0",
+        ];
+        for bytes in invalid {
+            match FrontendMsg::from_slice(bytes) {
+                Err(PgError::Protocol(_)) => {},
+                other => panic!("Expected a protocol error for {:?}, got {:?}", bytes, other),
+            }
+        }
+        match take_frontend_msg(b"Q\0\0\0\x09SELE", false) {
+            Err(PgError::Protocol(_)) => {},
+            other => panic!("Expected a protocol error, got {:?}", other),
+        }
+    }
 }
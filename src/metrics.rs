@@ -0,0 +1,480 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::Span;
+use tracing::field::Empty;
+use config::Config;
+use error::PgError;
+
+/// Reported once for every attempt to open a connection.
+#[derive(Debug)]
+pub struct ConnectEvent<'a> {
+    /// Time from opening the socket until the server was ready for queries,
+    /// or until the attempt failed.
+    pub duration: Duration,
+    /// Time spent authenticating, if authentication finished.
+    pub auth_duration: Option<Duration>,
+    pub error: Option<&'a PgError>,
+}
+
+/// Reported once for every statement run, including COPY statements.
+#[derive(Debug)]
+pub struct QueryEvent<'a> {
+    pub sql: &'a str,
+    pub duration: Duration,
+    /// Rows returned by a query, or copied by a COPY statement.
+    pub rows: u64,
+    pub error: Option<&'a PgError>,
+}
+
+/// Callbacks for monitoring connections.
+///
+/// Implementations are shared between connections and threads, and are
+/// called synchronously, so they should return quickly.
+pub trait Instrumentation: Send + Sync {
+    fn connected(&self, _event: &ConnectEvent) {}
+    fn query_finished(&self, _event: &QueryEvent) {}
+    fn bytes_sent(&self, _count: usize) {}
+    fn bytes_received(&self, _count: usize) {}
+}
+
+/// The instrumentation attached to a connection, if any.
+#[derive(Clone)]
+pub(crate) struct Hooks(Option<Arc<dyn Instrumentation>>);
+
+impl Hooks {
+    pub(crate) fn none() -> Hooks {
+        Hooks(None)
+    }
+
+    pub(crate) fn new(instrumentation: Arc<dyn Instrumentation>) -> Hooks {
+        Hooks(Some(instrumentation))
+    }
+
+    /// Report a connection attempt, and record it in `span`.
+    pub(crate) fn connected(&self, span: &Span, event: &ConnectEvent) {
+        span.record("duration_ms", millis(event.duration));
+        match event.error {
+            Some(err) => warn!(parent: span, error = %err, "connection failed"),
+            None => debug!(parent: span, auth_ms = event.auth_duration.map(millis), "connected"),
+        }
+        if let Some(ref hooks) = self.0 {
+            hooks.connected(event);
+        }
+    }
+
+    /// Report a finished statement, and record it in `span`.
+    pub(crate) fn query_finished(&self, span: &Span, event: &QueryEvent) {
+        span.record("duration_ms", millis(event.duration));
+        span.record("rows", event.rows);
+        match event.error {
+            Some(err) => debug!(parent: span, error = %err, "statement failed"),
+            None => debug!(parent: span, "statement finished"),
+        }
+        if let Some(ref hooks) = self.0 {
+            hooks.query_finished(event);
+        }
+    }
+
+    pub(crate) fn bytes_sent(&self, count: usize) {
+        if let Some(ref hooks) = self.0 {
+            hooks.bytes_sent(count);
+        }
+    }
+
+    pub(crate) fn bytes_received(&self, count: usize) {
+        if let Some(ref hooks) = self.0 {
+            hooks.bytes_received(count);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Hooks(Some(..))"),
+            None => write!(f, "Hooks(None)"),
+        }
+    }
+}
+
+/// Statements longer than this are truncated in spans.
+const MAX_LOGGED_SQL: usize = 200;
+
+/// The span covering a connection attempt, including authentication.  The
+/// password is never recorded.
+pub(crate) fn connect_span(config: &Config) -> Span {
+    info_span!(
+        "connect",
+        host = %config.host,
+        port = config.port,
+        user = %config.user,
+        database = config.database.as_ref().map(|db| &db[..]),
+        duration_ms = Empty,
+    )
+}
+
+/// The span covering one query.
+pub(crate) fn query_span(sql: &str) -> Span {
+    info_span!("query", sql = %loggable_sql(sql), rows = Empty, duration_ms = Empty)
+}
+
+/// The span covering a COPY statement, from sending it until the last of
+/// the data has been transferred.
+pub(crate) fn copy_span(sql: &str, direction: &'static str) -> Span {
+    info_span!("copy", direction = direction, sql = %loggable_sql(sql), rows = Empty, duration_ms = Empty)
+}
+
+/// `sql` shortened for logging.  Statements which mention a password, such
+/// as `ALTER ROLE ... PASSWORD '...'`, are not logged at all.
+fn loggable_sql(sql: &str) -> String {
+    if sql.to_lowercase().contains("password") {
+        return "<hidden>".to_string();
+    }
+    if sql.len() <= MAX_LOGGED_SQL {
+        return sql.to_string();
+    }
+    let mut end = MAX_LOGGED_SQL;
+    while !sql.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &sql[..end])
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+/// Counts of durations falling into fixed buckets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<Duration>,
+    counts: Vec<u64>,
+    sum: Duration,
+}
+
+impl Histogram {
+    /// A histogram with a bucket for each upper bound, plus one for larger
+    /// values.  `bounds` must be sorted.
+    pub fn new(bounds: Vec<Duration>) -> Histogram {
+        Histogram {
+            counts: vec![0; bounds.len() + 1],
+            bounds: bounds,
+            sum: Duration::from_secs(0),
+        }
+    }
+
+    pub fn record(&mut self, value: Duration) {
+        let bucket = self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Each bucket's upper bound, and the number of values in it.  The last
+    /// bucket has no upper bound.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = self.bounds.iter().map(|&bound| Some(bound)).chain(Some(None));
+        bounds.zip(self.counts.iter().cloned()).collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
+
+impl Default for Histogram {
+    /// Buckets from one millisecond to ten seconds.
+    fn default() -> Histogram {
+        Histogram::new(
+            [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000]
+                .iter()
+                .map(|&millis| Duration::from_millis(millis))
+                .collect()
+        )
+    }
+}
+
+/// A snapshot of the totals kept by a `MetricsCollector`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Metrics {
+    pub connects: u64,
+    pub connect_errors: u64,
+    pub auth_duration: Duration,
+    pub queries: u64,
+    pub rows_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Errors keyed by SQLSTATE class, the first two characters of the
+    /// code.  Errors that did not come from the server are counted under
+    /// "client".
+    pub errors: BTreeMap<String, u64>,
+    pub query_latency: Histogram,
+}
+
+impl Metrics {
+    fn count_error(&mut self, error: &PgError) {
+        let class = match error.code() {
+            Some(code) => code.chars().take(2).collect(),
+            None => "client".to_string(),
+        };
+        *self.errors.entry(class).or_insert(0) += 1;
+    }
+}
+
+/// Instrumentation which keeps running totals in memory.
+#[derive(Debug, Default)]
+pub struct MetricsCollector {
+    metrics: Mutex<Metrics>,
+}
+
+impl MetricsCollector {
+    pub fn new() -> MetricsCollector {
+        MetricsCollector::default()
+    }
+
+    /// A copy of the current totals.
+    pub fn snapshot(&self) -> Metrics {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Metrics> {
+        self.metrics.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Instrumentation for MetricsCollector {
+    fn connected(&self, event: &ConnectEvent) {
+        let mut metrics = self.lock();
+        metrics.connects += 1;
+        if let Some(auth_duration) = event.auth_duration {
+            metrics.auth_duration += auth_duration;
+        }
+        if let Some(error) = event.error {
+            metrics.connect_errors += 1;
+            metrics.count_error(error);
+        }
+    }
+
+    fn query_finished(&self, event: &QueryEvent) {
+        let mut metrics = self.lock();
+        metrics.queries += 1;
+        metrics.rows_received += event.rows;
+        metrics.query_latency.record(event.duration);
+        if let Some(error) = event.error {
+            metrics.count_error(error);
+        }
+    }
+
+    fn bytes_sent(&self, count: usize) {
+        self.lock().bytes_sent += count as u64;
+    }
+
+    fn bytes_received(&self, count: usize) {
+        self.lock().bytes_received += count as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tracing;
+    use tracing::{Event, Id, Metadata, Subscriber};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Record};
+    use connection::Connection;
+    use error::{DbError, PgError};
+    use servermsg::FieldFormat;
+    use testing::{Auth, MockBackend, Response};
+    use super::*;
+
+    /// Keeps a line for each span, event and recorded value: its name
+    /// followed by its fields.
+    struct Recorder {
+        lines: Arc<Mutex<Vec<String>>>,
+        next_id: AtomicUsize,
+    }
+
+    struct Line(String);
+
+    impl Visit for Line {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut line = Line(span.metadata().name().to_string());
+            span.record(&mut line);
+            self.lines.lock().unwrap().push(line.0);
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+        }
+
+        fn record(&self, _span: &Id, values: &Record) {
+            let mut line = Line("record".to_string());
+            values.record(&mut line);
+            self.lines.lock().unwrap().push(line.0);
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut line = Line("event".to_string());
+            event.record(&mut line);
+            self.lines.lock().unwrap().push(line.0);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(vec![Duration::from_millis(10), Duration::from_millis(100)]);
+        histogram.record(Duration::from_millis(1));
+        histogram.record(Duration::from_millis(10));
+        histogram.record(Duration::from_millis(50));
+        histogram.record(Duration::from_secs(3));
+        assert_eq!(
+            histogram.buckets(),
+            vec![
+                (Some(Duration::from_millis(10)), 2),
+                (Some(Duration::from_millis(100)), 1),
+                (None, 1),
+            ]
+        );
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(3061));
+    }
+
+    #[test]
+    fn test_collector() {
+        let collector = MetricsCollector::new();
+        let db_error = PgError::Db(DbError::from_fields(&[(b'C', "40001")]));
+        collector.connected(&ConnectEvent {
+            duration: Duration::from_millis(5),
+            auth_duration: Some(Duration::from_millis(2)),
+            error: None,
+        });
+        collector.query_finished(&QueryEvent {
+            sql: "SELECT 1",
+            duration: Duration::from_millis(3),
+            rows: 1,
+            error: None,
+        });
+        collector.query_finished(&QueryEvent {
+            sql: "UPDATE things",
+            duration: Duration::from_millis(30),
+            rows: 0,
+            error: Some(&db_error),
+        });
+        collector.query_finished(&QueryEvent {
+            sql: "SELECT pg_sleep(10)",
+            duration: Duration::from_millis(300),
+            rows: 0,
+            error: Some(&PgError::Timeout),
+        });
+        collector.bytes_sent(10);
+        collector.bytes_received(20);
+
+        let metrics = collector.snapshot();
+        assert_eq!(metrics.connects, 1);
+        assert_eq!(metrics.connect_errors, 0);
+        assert_eq!(metrics.auth_duration, Duration::from_millis(2));
+        assert_eq!(metrics.queries, 3);
+        assert_eq!(metrics.rows_received, 1);
+        assert_eq!(metrics.bytes_sent, 10);
+        assert_eq!(metrics.bytes_received, 20);
+        assert_eq!(metrics.errors.get("40"), Some(&1));
+        assert_eq!(metrics.errors.get("client"), Some(&1));
+        assert_eq!(metrics.query_latency.count(), 3);
+    }
+
+    #[test]
+    fn test_connection_metrics() {
+        let server = MockBackend::new()
+            .auth(Auth::Md5("secret".to_string()))
+            .on_query("SELECT generate_series(1, 5);", Response::rows(&["generate_series"], &[&["1"], &["2"], &["3"], &["4"], &["5"]]))
+            .on_query("SELECT * FROM no_such_table;", Response::error("42P01", "relation \"no_such_table\" does not exist"))
+            .on_query("CREATE TEMPORARY TABLE numbers (n int);", Response::command("CREATE TABLE"))
+            .on_query("COPY numbers FROM STDIN;", Response::CopyIn(FieldFormat::Text))
+            .start()
+            .unwrap();
+        let collector = Arc::new(MetricsCollector::new());
+        let config = server.config("cliff")
+            .password("secret")
+            .instrumentation(collector.clone());
+        let mut conn = config.connect().unwrap();
+        conn.query("SELECT generate_series(1, 5);").unwrap();
+        assert!(conn.query("SELECT * FROM no_such_table;").is_err());
+        conn.query("CREATE TEMPORARY TABLE numbers (n int);").unwrap();
+        let mut writer = conn.copy_in("COPY numbers FROM STDIN;").unwrap();
+        writer.write_all(b"1\n2\n").unwrap();
+        writer.finish().unwrap();
+
+        let metrics = collector.snapshot();
+        assert_eq!(metrics.connects, 1);
+        assert!(metrics.auth_duration > Duration::from_secs(0));
+        assert_eq!(metrics.queries, 4);
+        assert_eq!(metrics.rows_received, 7);
+        assert_eq!(metrics.errors.get("42"), Some(&1));
+        assert!(metrics.bytes_sent > 0);
+        assert!(metrics.bytes_received > metrics.bytes_sent);
+
+        assert!(server.config("notauser").password("wrong")
+                .instrumentation(collector.clone()).connect().is_err());
+        let metrics = collector.snapshot();
+        assert_eq!(metrics.connects, 2);
+        assert_eq!(metrics.connect_errors, 1);
+        assert_eq!(metrics.errors.get("28"), Some(&1));
+    }
+
+    #[test]
+    fn test_loggable_sql() {
+        assert_eq!(loggable_sql("SELECT 1;"), "SELECT 1;");
+        assert_eq!(loggable_sql("ALTER ROLE cliff PASSWORD 'secret';"), "<hidden>");
+        let sql = format!("SELECT '{}';", "é".repeat(200));
+        let logged = loggable_sql(&sql);
+        assert!(logged.len() <= MAX_LOGGED_SQL + 3);
+        assert!(logged.starts_with("SELECT 'éé"));
+        assert!(logged.ends_with("..."));
+    }
+
+    #[test]
+    fn test_tracing() {
+        let server = MockBackend::new()
+            .auth(Auth::Md5("open sesame".to_string()))
+            .on_query("SELECT 1;", Response::rows(&["n"], &[&["1"]]))
+            .default_response(Response::command("ALTER ROLE"))
+            .start()
+            .unwrap();
+        let lines = Arc::new(Mutex::new(vec![]));
+        let recorder = Recorder { lines: lines.clone(), next_id: AtomicUsize::new(0) };
+        tracing::subscriber::with_default(recorder, || {
+            let mut conn = Connection::connect(&server.config("cliff").password("open sesame")).unwrap();
+            conn.query("SELECT 1;").unwrap();
+            conn.query("ALTER ROLE cliff PASSWORD 'open sesame';").unwrap();
+        });
+
+        let lines = lines.lock().unwrap();
+        assert!(lines.iter().any(|line| line.starts_with("connect host=127.0.0.1") && line.contains("user=cliff")));
+        assert!(lines.contains(&"auth".to_string()));
+        assert!(lines.contains(&"query sql=SELECT 1;".to_string()));
+        assert!(lines.contains(&"record rows=1".to_string()));
+        assert!(lines.contains(&"query sql=<hidden>".to_string()));
+        assert!(!lines.iter().any(|line| line.contains("sesame")), "{:?}", lines);
+    }
+}
@@ -0,0 +1,573 @@
+use std::collections::vec_deque::VecDeque;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use Result;
+use connection::Connection;
+use error::PgError;
+use servermsg::TransactionStatus;
+
+/// Settings for a `Pool`.
+#[derive(Clone, Debug)]
+pub struct PoolBuilder {
+    min_size: usize,
+    max_size: usize,
+    checkout_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    test_on_checkout: bool,
+    discard_on_return: bool,
+}
+
+impl PoolBuilder {
+    /// The number of connections opened when the pool is built.  Idle
+    /// connections are not closed for being idle below this size.
+    pub fn min_size(mut self, min_size: usize) -> PoolBuilder {
+        self.min_size = min_size;
+        self
+    }
+
+    /// The most connections the pool will have open at once.
+    pub fn max_size(mut self, max_size: usize) -> PoolBuilder {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long `Pool::get` waits for a connection to become available.
+    pub fn checkout_timeout(mut self, checkout_timeout: Duration) -> PoolBuilder {
+        self.checkout_timeout = checkout_timeout;
+        self
+    }
+
+    /// Close connections that have been idle for longer than this.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> PoolBuilder {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Close connections that have been open for longer than this.
+    pub fn max_lifetime(mut self, max_lifetime: Option<Duration>) -> PoolBuilder {
+        self.max_lifetime = max_lifetime;
+        self
+    }
+
+    /// Run `SELECT 1` on a connection before handing it out.
+    pub fn test_on_checkout(mut self, test_on_checkout: bool) -> PoolBuilder {
+        self.test_on_checkout = test_on_checkout;
+        self
+    }
+
+    /// Run `DISCARD ALL` on connections as they are returned, so session
+    /// state like temporary tables and settings does not leak between users.
+    pub fn discard_on_return(mut self, discard_on_return: bool) -> PoolBuilder {
+        self.discard_on_return = discard_on_return;
+        self
+    }
+
+    /// Build the pool, opening `min_size` connections with `connect`.
+    pub fn build<F>(self, connect: F) -> Result<Pool>
+        where F: Fn() -> Result<Connection> + Send + Sync + 'static
+    {
+        if self.max_size == 0 {
+            return Err(PgError::Error("Pool max_size must be at least 1".to_string()));
+        }
+        if self.min_size > self.max_size {
+            return Err(PgError::Error("Pool min_size must not exceed max_size".to_string()));
+        }
+        let mut idle = VecDeque::with_capacity(self.max_size);
+        for _ in 0..self.min_size {
+            idle.push_back(IdleConnection::new(try!(connect())));
+        }
+        Ok(Pool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(PoolState {
+                    size: idle.len(),
+                    idle: idle,
+                }),
+                available: Condvar::new(),
+                connect: Box::new(connect),
+                config: self,
+            }),
+        })
+    }
+}
+
+struct IdleConnection {
+    conn: Connection,
+    created: Instant,
+    idle_since: Instant,
+}
+
+impl IdleConnection {
+    fn new(conn: Connection) -> IdleConnection {
+        let now = Instant::now();
+        IdleConnection {
+            conn: conn,
+            created: now,
+            idle_since: now,
+        }
+    }
+}
+
+struct PoolState {
+    idle: VecDeque<IdleConnection>,
+    /// Idle and checked out connections, plus connections being opened.
+    size: usize,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    available: Condvar,
+    connect: Box<dyn Fn() -> Result<Connection> + Send + Sync>,
+    config: PoolBuilder,
+}
+
+/// A thread safe pool of connections.  Cloning the pool gives another
+/// handle to the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    /// Start configuring a pool.  By default it holds up to 10 connections,
+    /// waits 30 seconds for a checkout, closes connections idle for 10
+    /// minutes or open for 30, and tests connections on checkout.
+    pub fn builder() -> PoolBuilder {
+        PoolBuilder {
+            min_size: 0,
+            max_size: 10,
+            checkout_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            test_on_checkout: true,
+            discard_on_return: false,
+        }
+    }
+
+    /// Check out a connection, waiting up to the checkout timeout for one to
+    /// be returned if the pool is at its maximum size.
+    pub fn get(&self) -> Result<PooledConnection> {
+        let config = &self.shared.config;
+        let deadline = Instant::now() + config.checkout_timeout;
+        loop {
+            let mut state = self.lock();
+            let expired = self.remove_expired(&mut state);
+            if !expired.is_empty() {
+                drop(state);
+                drop(expired);
+                self.refill();
+                continue;
+            }
+            if let Some(idle) = state.idle.pop_back() {
+                drop(state);
+                let mut conn = idle.conn;
+                if config.test_on_checkout && !is_healthy(&mut conn) {
+                    drop(conn);
+                    self.release_slot();
+                    self.refill();
+                    continue;
+                }
+                return Ok(PooledConnection::new(self.clone(), conn, idle.created));
+            }
+            if state.size < config.max_size {
+                state.size += 1;
+                drop(state);
+                return match (self.shared.connect)() {
+                    Ok(conn) => Ok(PooledConnection::new(self.clone(), conn, Instant::now())),
+                    Err(err) => {
+                        self.release_slot();
+                        Err(err)
+                    },
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(PgError::PoolTimeout);
+            }
+            let _ = self.shared.available.wait_timeout(state, deadline - now);
+        }
+    }
+
+    /// The number of open connections, both idle and checked out.
+    pub fn size(&self) -> usize {
+        self.lock().size
+    }
+
+    /// The number of connections waiting to be checked out.
+    pub fn idle_count(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // A panic while the lock is held cannot leave the state inconsistent.
+        self.shared.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Take connections past their idle timeout or lifetime out of the pool.
+    /// They are returned so they can be closed after the lock is released.
+    fn remove_expired(&self, state: &mut PoolState) -> Vec<IdleConnection> {
+        let config = &self.shared.config;
+        let now = Instant::now();
+        let mut expired = vec![];
+        let idle = mem::replace(&mut state.idle, VecDeque::new());
+        for conn in idle {
+            let too_old = config.max_lifetime.map_or(false, |max| now - conn.created >= max);
+            let too_idle = config.idle_timeout.map_or(false, |max| now - conn.idle_since >= max)
+                && state.size > config.min_size;
+            if too_old || too_idle {
+                state.size -= 1;
+                expired.push(conn);
+            } else {
+                state.idle.push_back(conn);
+            }
+        }
+        expired
+    }
+
+    fn release_slot(&self) {
+        let mut state = self.lock();
+        state.size = state.size.saturating_sub(1);
+        self.shared.available.notify_one();
+    }
+
+    /// Open connections until the pool is back up to `min_size` after some
+    /// were closed.  A failure is left for the next checkout to report.
+    fn refill(&self) {
+        let missing = {
+            let mut state = self.lock();
+            let missing = self.shared.config.min_size.saturating_sub(state.size);
+            state.size += missing;
+            missing
+        };
+        for _ in 0..missing {
+            match (self.shared.connect)() {
+                Ok(conn) => {
+                    let mut state = self.lock();
+                    state.idle.push_back(IdleConnection::new(conn));
+                    self.shared.available.notify_one();
+                },
+                Err(_) => self.release_slot(),
+            }
+        }
+    }
+
+    fn put_back(&self, mut conn: Connection, created: Instant) {
+        if conn.is_closed() || reset(&mut conn, self.shared.config.discard_on_return).is_err() {
+            drop(conn);
+            self.release_slot();
+            self.refill();
+            return;
+        }
+        let mut state = self.lock();
+        state.idle.push_back(IdleConnection {
+            conn: conn,
+            created: created,
+            idle_since: Instant::now(),
+        });
+        self.shared.available.notify_one();
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("Pool")
+            .field("size", &state.size)
+            .field("idle", &state.idle.len())
+            .field("config", &self.shared.config)
+            .finish()
+    }
+}
+
+fn is_healthy(conn: &mut Connection) -> bool {
+    !conn.is_closed() && conn.transaction_status() == TransactionStatus::Idle && conn.query("SELECT 1").is_ok()
+}
+
+/// Return a connection to a clean session state.
+fn reset(conn: &mut Connection, discard: bool) -> Result<()> {
+    if conn.transaction_status() != TransactionStatus::Idle {
+        try!(conn.query("ROLLBACK"));
+    }
+    if discard {
+        try!(conn.query("DISCARD ALL"));
+    }
+    Ok(())
+}
+
+/// A connection checked out of a `Pool`.  It is returned to the pool when
+/// dropped.
+pub struct PooledConnection {
+    pool: Pool,
+    conn: Option<Connection>,
+    created: Instant,
+}
+
+impl PooledConnection {
+    fn new(pool: Pool, conn: Connection, created: Instant) -> PooledConnection {
+        PooledConnection {
+            pool: pool,
+            conn: Some(conn),
+            created: created,
+        }
+    }
+
+    /// Close the connection rather than returning it to the pool, for when
+    /// it may be in a state `reset` can't recover from.
+    pub fn discard(mut self) {
+        if let Some(conn) = self.conn.take() {
+            drop(conn);
+            self.pool.release_slot();
+            self.pool.refill();
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection already returned to the pool")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection already returned to the pool")
+    }
+}
+
+impl fmt::Debug for PooledConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PooledConnection")
+            .field("conn", &self.conn)
+            .finish()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put_back(conn, self.created);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use connection::Connection;
+    use error::PgError;
+    use servermsg::TransactionStatus;
+    use testing::{MockBackend, MockServer, Response};
+    use super::*;
+
+    /// A mock server which answers `SELECT 0` to `SELECT 7`, and any
+    /// other statement as a command.
+    fn mock() -> MockServer {
+        let mut backend = MockBackend::new().default_response(Response::command("SET"));
+        for n in 0..8 {
+            backend = backend.on_query(&format!("SELECT {}", n), Response::rows(&["?column?"], &[&[&n.to_string()]]));
+        }
+        backend.start().unwrap()
+    }
+
+    fn connector(server: &MockServer) -> impl Fn() -> ::Result<Connection> + Send + Sync + 'static {
+        let config = server.config("cliff");
+        move || Connection::connect(&config)
+    }
+
+    fn pid(conn: &Connection) -> u32 {
+        conn.cancel_token().unwrap().pid()
+    }
+
+    #[test]
+    fn test_pool_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Pool>();
+    }
+
+    #[test]
+    fn test_invalid_sizes() {
+        let server = mock();
+        assert!(Pool::builder().max_size(0).build(connector(&server)).is_err());
+        assert!(Pool::builder().min_size(3).max_size(2).build(connector(&server)).is_err());
+    }
+
+    #[test]
+    fn test_reuse() {
+        let server = mock();
+        let pool = Pool::builder().min_size(1).max_size(2).build(connector(&server)).unwrap();
+        assert_eq!(pool.size(), 1);
+        let first = pid(&pool.get().unwrap());
+        let second = pid(&pool.get().unwrap());
+        assert_eq!(first, second);
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn test_checkout_timeout() {
+        let server = mock();
+        let pool = Pool::builder()
+            .max_size(1)
+            .checkout_timeout(Duration::from_millis(100))
+            .build(connector(&server))
+            .unwrap();
+        let conn = pool.get().unwrap();
+        match pool.get() {
+            Err(PgError::PoolTimeout) => {},
+            other => panic!("Expected pool timeout, got {:?}", other),
+        }
+        drop(conn);
+        assert!(pool.get().is_ok());
+    }
+
+    #[test]
+    fn test_rollback_on_return() {
+        let server = mock();
+        let pool = Pool::builder().max_size(1).build(connector(&server)).unwrap();
+        {
+            let mut conn = pool.get().unwrap();
+            conn.query("BEGIN").unwrap();
+            conn.query("CREATE TEMPORARY TABLE pool_items (n int)").unwrap();
+        }
+        let conn = pool.get().unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        assert!(server.queries().contains(&"ROLLBACK".to_string()));
+    }
+
+    #[test]
+    fn test_discard_on_return() {
+        let server = mock();
+        let pool = Pool::builder().max_size(1).discard_on_return(true).build(connector(&server)).unwrap();
+        let first = {
+            let mut conn = pool.get().unwrap();
+            conn.query("SET application_name = 'pool test'").unwrap();
+            pid(&conn)
+        };
+        let conn = pool.get().unwrap();
+        assert_eq!(pid(&conn), first);
+        assert!(server.queries().contains(&"DISCARD ALL".to_string()));
+    }
+
+    #[test]
+    fn test_discard() {
+        let server = mock();
+        let pool = Pool::builder().max_size(1).build(connector(&server)).unwrap();
+        let conn = pool.get().unwrap();
+        let first = pid(&conn);
+        conn.discard();
+        assert_eq!(pool.size(), 0);
+        assert!(pid(&pool.get().unwrap()) != first);
+    }
+
+    #[test]
+    fn test_max_lifetime() {
+        let server = mock();
+        let pool = Pool::builder()
+            .max_size(1)
+            .max_lifetime(Some(Duration::from_millis(50)))
+            .build(connector(&server))
+            .unwrap();
+        let first = pid(&pool.get().unwrap());
+        thread::sleep(Duration::from_millis(100));
+        let second = pid(&pool.get().unwrap());
+        assert!(first != second);
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn test_idle_timeout_keeps_min_size() {
+        let server = mock();
+        let pool = Pool::builder()
+            .min_size(1)
+            .max_size(3)
+            .idle_timeout(Some(Duration::from_millis(50)))
+            .build(connector(&server))
+            .unwrap();
+        {
+            let _a = pool.get().unwrap();
+            let _b = pool.get().unwrap();
+            let _c = pool.get().unwrap();
+        }
+        assert_eq!(pool.size(), 3);
+        thread::sleep(Duration::from_millis(100));
+        let _conn = pool.get().unwrap();
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn test_refill_to_min_size() {
+        let server = mock();
+        let pool = Pool::builder()
+            .min_size(2)
+            .max_size(3)
+            .max_lifetime(Some(Duration::from_millis(50)))
+            .build(connector(&server))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let conn = pool.get().unwrap();
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.idle_count(), 1);
+        conn.discard();
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.idle_count(), 2);
+    }
+
+    #[test]
+    fn test_closed_connection_not_returned() {
+        let server = mock();
+        let pool = Pool::builder().max_size(1).test_on_checkout(false).build(connector(&server)).unwrap();
+        let first = {
+            let mut conn = pool.get().unwrap();
+            let first = pid(&conn);
+            assert!(server.terminate(first));
+            let _ = conn.query("SELECT 1");
+            assert!(conn.is_closed());
+            assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+            first
+        };
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.size(), 0);
+        let mut conn = pool.get().unwrap();
+        assert!(pid(&conn) != first);
+        assert_eq!(conn.query("SELECT 1").unwrap(), vec![vec!["1".to_string()]]);
+    }
+
+    #[test]
+    fn test_unhealthy_connection_replaced() {
+        let server = mock();
+        let pool = Pool::builder().max_size(1).build(connector(&server)).unwrap();
+        let first = {
+            let mut conn = pool.get().unwrap();
+            let first = pid(&conn);
+            assert!(server.terminate(first));
+            let _ = conn.query("SELECT 1");
+            first
+        };
+        let mut conn = pool.get().unwrap();
+        assert!(pid(&conn) != first);
+        assert_eq!(conn.query("SELECT 1").unwrap(), vec![vec!["1".to_string()]]);
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let server = mock();
+        let pool = Pool::builder().max_size(2).build(connector(&server)).unwrap();
+        let handles: Vec<_> = (0..8).map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                let mut conn = pool.get().unwrap();
+                conn.query(&format!("SELECT {}", i)).unwrap()
+            })
+        }).collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap(), vec![vec![i.to_string()]]);
+        }
+        assert!(pool.size() <= 2);
+    }
+}
@@ -0,0 +1,528 @@
+//! The frontend side of the protocol, without any IO.
+//!
+//! A `Protocol` is fed the bytes read from the server with `receive`, and
+//! turns them into `Event`s.  Messages for the server, including replies it
+//! makes on its own such as the password during startup, are queued until
+//! they are collected with `take_output`.
+use std::fmt;
+use std::mem;
+use Result;
+use auth;
+use connection::Notification;
+use error::{DbError, PgError};
+use message::{Message, StartupMessage, Query, PasswordMessage, Terminate, CopyData, CopyDone, CopyFail};
+use servermsg::{message_length, ServerMsg, AuthMsg, FieldFormat, TransactionStatus};
+use secret::Secret;
+
+#[derive(Copy, Debug, Eq, PartialEq, Clone)]
+pub enum ConnectionState {
+    AwaitingAuthResponse,
+    Authenticated,
+    AuthenticationRejected,
+    ReadyForQuery,
+    AwaitingQueryResponse,
+    AwaitingDataRows,
+    CopyIn,
+    CopyOut,
+    Disconnected,
+}
+
+/// Something the server told us.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Event {
+    Authenticated,
+    ParameterStatus(String, String),
+    BackendKeyData(u32, Vec<u8>),
+    /// The server has finished the last query, and is ready for another.
+    ReadyForQuery(TransactionStatus),
+    /// The names of the columns in the rows that follow.
+    RowDescription(Vec<String>),
+    /// The values of a row as text, with NULL as an empty string.
+    DataRow(Vec<String>),
+    CommandComplete(String),
+    CopyIn(FieldFormat, Vec<FieldFormat>),
+    CopyOut(FieldFormat, Vec<FieldFormat>),
+    CopyData(Vec<u8>),
+    CopyDone,
+    /// The unparsed body of a NoticeResponse.
+    Notice(Vec<u8>),
+    Notification(Notification),
+    Error(DbError),
+}
+
+pub struct Protocol {
+    user: String,
+    /// Dropped once authentication succeeds.
+    password: Option<Secret>,
+    state: ConnectionState,
+    transaction_status: TransactionStatus,
+    backend_key: Option<(u32, Vec<u8>)>,
+    /// Queries sent whose ReadyForQuery has not arrived yet.
+    pending: usize,
+    max_message_size: usize,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Protocol {
+    /// Start a session, queueing the startup message.
+    pub fn new(user: &str, password: Option<Secret>, database: &str) -> Protocol {
+        let startup = StartupMessage {
+            user: user,
+            database: Some(database),
+            params: vec!(),
+        };
+        Protocol {
+            user: user.to_string(),
+            password: password,
+            state: ConnectionState::AwaitingAuthResponse,
+            transaction_status: TransactionStatus::Idle,
+            backend_key: None,
+            pending: 0,
+            max_message_size: 1 << 30,
+            input: Vec::with_capacity(1024),
+            output: startup.to_bytes(),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// The transaction status reported with the last ReadyForQuery.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    /// The process id and secret key needed to cancel queries.
+    pub fn backend_key(&self) -> Option<(u32, &[u8])> {
+        self.backend_key.as_ref().map(|&(pid, ref key)| (pid, &key[..]))
+    }
+
+    /// Refuse messages larger than `size` bytes with
+    /// `PgError::MessageTooLarge`, as soon as their length arrives.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Add bytes read from the server.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Take the bytes waiting to be sent to the server.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.output, vec![])
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.output.len() > 0
+    }
+
+    /// Queue a simple query.  Queries may be queued before earlier ones
+    /// have finished; their responses arrive in order.
+    pub fn query(&mut self, sql: &str) -> Result<()> {
+        match self.state {
+            ConnectionState::ReadyForQuery |
+            ConnectionState::AwaitingQueryResponse |
+            ConnectionState::AwaitingDataRows => {},
+            state => return Err(PgError::Error(format!("Cannot send a query while {:?}", state))),
+        }
+        let query = Query { query: sql.to_string() };
+        self.output.extend(query.to_bytes());
+        self.pending += 1;
+        if self.state == ConnectionState::ReadyForQuery {
+            self.state = ConnectionState::AwaitingQueryResponse;
+        }
+        Ok(())
+    }
+
+    /// Queue data for a `COPY ... FROM STDIN` statement.
+    pub fn copy_data(&mut self, data: &[u8]) -> Result<()> {
+        try!(self.expect_copy_in());
+        self.output.extend(CopyData { data: data }.to_bytes());
+        Ok(())
+    }
+
+    /// Finish a `COPY ... FROM STDIN` statement.
+    pub fn copy_done(&mut self) -> Result<()> {
+        try!(self.expect_copy_in());
+        self.output.extend(CopyDone.to_bytes());
+        self.state = ConnectionState::AwaitingQueryResponse;
+        Ok(())
+    }
+
+    /// Abort a `COPY ... FROM STDIN` statement with `message`.
+    pub fn copy_fail(&mut self, message: &str) -> Result<()> {
+        try!(self.expect_copy_in());
+        self.output.extend(CopyFail { message: message }.to_bytes());
+        self.state = ConnectionState::AwaitingQueryResponse;
+        Ok(())
+    }
+
+    fn expect_copy_in(&self) -> Result<()> {
+        match self.state {
+            ConnectionState::CopyIn => Ok(()),
+            state => Err(PgError::Error(format!("Not copying to the server: {:?}", state))),
+        }
+    }
+
+    /// Queue messages built elsewhere, such as those a proxy forwards from
+    /// its clients.  Their replies are read with `next_message`.
+    pub fn send_message(&mut self, bytes: &[u8]) -> Result<()> {
+        match self.state {
+            ConnectionState::ReadyForQuery => {},
+            state => return Err(PgError::Error(format!("Cannot pass messages through while {:?}", state))),
+        }
+        self.output.extend(bytes);
+        Ok(())
+    }
+
+    /// Queue the message ending the session.
+    pub fn terminate(&mut self) {
+        if self.state != ConnectionState::Disconnected {
+            self.output.extend(Terminate.to_bytes());
+            self.state = ConnectionState::Disconnected;
+        }
+    }
+
+    /// Parse the next complete message received, if there is one.
+    pub fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            let frame = match try!(self.next_frame()) {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            let msg = try!(ServerMsg::from_slice(&frame));
+            if let Some(event) = try!(self.handle(msg)) {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    /// Take the next complete message received as it was sent, only
+    /// noting the transaction status of a ReadyForQuery.
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>> {
+        let frame = match try!(self.next_frame()) {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if let Ok(ServerMsg::ReadyForQuery(status)) = ServerMsg::from_slice(&frame) {
+            self.transaction_status = status;
+        }
+        Ok(Some(frame))
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let length = match try!(message_length(&self.input)) {
+            Some(length) if length > self.max_message_size => return Err(PgError::MessageTooLarge(length)),
+            Some(length) if self.input.len() >= length => length,
+            _ => return Ok(None),
+        };
+        let rest = self.input.split_off(length);
+        Ok(Some(mem::replace(&mut self.input, rest)))
+    }
+
+    fn handle(&mut self, msg: ServerMsg) -> Result<Option<Event>> {
+        let event = match msg {
+            ServerMsg::Auth(auth) => return self.handle_auth(auth),
+            ServerMsg::ErrorResponse(fields) => {
+                match self.state {
+                    ConnectionState::AwaitingAuthResponse |
+                    ConnectionState::Authenticated => self.state = ConnectionState::AuthenticationRejected,
+                    ConnectionState::CopyIn |
+                    ConnectionState::CopyOut => self.state = ConnectionState::AwaitingQueryResponse,
+                    _ => {},
+                }
+                Event::Error(DbError::from_fields(&fields))
+            },
+            ServerMsg::NoticeResponse(body) => Event::Notice(body.to_vec()),
+            ServerMsg::ParamStatus(name, value) => Event::ParameterStatus(name.to_string(), value.to_string()),
+            ServerMsg::BackendKeyData(pid, key) => {
+                self.backend_key = Some((pid, key.to_vec()));
+                Event::BackendKeyData(pid, key.to_vec())
+            },
+            ServerMsg::NotificationResponse(pid, channel, payload) => {
+                Event::Notification(Notification::new(pid, channel, payload))
+            },
+            ServerMsg::ReadyForQuery(status) => {
+                self.transaction_status = status;
+                match self.state {
+                    ConnectionState::Authenticated => {},
+                    ConnectionState::AwaitingQueryResponse |
+                    ConnectionState::AwaitingDataRows if self.pending > 0 => self.pending -= 1,
+                    state => return Err(unexpected("ReadyForQuery", state)),
+                }
+                if self.pending == 0 {
+                    self.state = ConnectionState::ReadyForQuery;
+                } else {
+                    self.state = ConnectionState::AwaitingQueryResponse;
+                }
+                Event::ReadyForQuery(status)
+            },
+            ServerMsg::RowDescription(fields) => {
+                try!(self.expect_query_response("RowDescription"));
+                self.state = ConnectionState::AwaitingDataRows;
+                Event::RowDescription(fields.iter().map(|field| field.name().to_string()).collect())
+            },
+            ServerMsg::DataRow(values) => {
+                match self.state {
+                    ConnectionState::AwaitingDataRows => {},
+                    state => return Err(unexpected("DataRow", state)),
+                }
+                Event::DataRow(values.iter().map(|value| value.unwrap_or("").to_string()).collect())
+            },
+            ServerMsg::CommandComplete(tag) => {
+                try!(self.expect_query_response("CommandComplete"));
+                self.state = ConnectionState::AwaitingQueryResponse;
+                Event::CommandComplete(tag.to_string())
+            },
+            ServerMsg::CopyInResponse(format, column_formats) => {
+                try!(self.expect_query_response("CopyInResponse"));
+                self.state = ConnectionState::CopyIn;
+                Event::CopyIn(format, column_formats)
+            },
+            ServerMsg::CopyOutResponse(format, column_formats) => {
+                try!(self.expect_query_response("CopyOutResponse"));
+                self.state = ConnectionState::CopyOut;
+                Event::CopyOut(format, column_formats)
+            },
+            ServerMsg::CopyData(data) => {
+                match self.state {
+                    ConnectionState::CopyOut => {},
+                    state => return Err(unexpected("CopyData", state)),
+                }
+                Event::CopyData(data.to_vec())
+            },
+            ServerMsg::CopyDone => {
+                match self.state {
+                    ConnectionState::CopyOut => self.state = ConnectionState::AwaitingQueryResponse,
+                    state => return Err(unexpected("CopyDone", state)),
+                }
+                Event::CopyDone
+            },
+            ServerMsg::EmptyQueryResponse |
+            ServerMsg::ParseComplete |
+            ServerMsg::BindComplete |
+            ServerMsg::CloseComplete |
+            ServerMsg::NoData |
+            ServerMsg::PortalSuspended |
+            ServerMsg::ParameterDescription(..) |
+            ServerMsg::CopyBothResponse(..) |
+            ServerMsg::FunctionCallResponse(..) |
+            ServerMsg::NegotiateProtocolVersion(..) |
+            ServerMsg::Unknown(..) => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+
+    fn handle_auth(&mut self, auth: AuthMsg) -> Result<Option<Event>> {
+        if self.state != ConnectionState::AwaitingAuthResponse {
+            return Err(unexpected("authentication request", self.state));
+        }
+        match auth {
+            AuthMsg::Ok => {
+                self.password = None;
+                self.state = ConnectionState::Authenticated;
+                Ok(Some(Event::Authenticated))
+            },
+            AuthMsg::Md5(salt) => {
+                let password = self.password.as_ref().map_or("", Secret::expose);
+                let passhash = auth::build_md5_hash(&self.user, password, salt);
+                self.output.extend(PasswordMessage { hash: passhash.expose() }.to_bytes());
+                Ok(None)
+            },
+            method => Err(PgError::Error(format!("Unimplemented authentication method, {:?}", method))),
+        }
+    }
+
+    /// Whether the server is answering a query, rather than starting up or
+    /// copying.  A query made of several statements can produce several
+    /// results before its ReadyForQuery.
+    fn expect_query_response(&self, name: &str) -> Result<()> {
+        match self.state {
+            ConnectionState::AwaitingQueryResponse |
+            ConnectionState::AwaitingDataRows => Ok(()),
+            state => Err(unexpected(name, state)),
+        }
+    }
+}
+
+impl fmt::Debug for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Protocol")
+            .field("user", &self.user)
+            .field("password", &self.password)
+            .field("state", &self.state)
+            .field("transaction_status", &self.transaction_status)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+fn unexpected(name: &str, state: ConnectionState) -> PgError {
+    PgError::Protocol(format!("Unexpected {} while {:?}", name, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use message::{Message, Query, PasswordMessage};
+    use servermsg::TransactionStatus;
+    use super::*;
+
+    const AUTH_MD5: &'static [u8] = &[b'R', 0, 0, 0, 12, 0, 0, 0, 5, 1, 2, 3, 4];
+    const AUTH_OK: &'static [u8] = &[b'R', 0, 0, 0, 8, 0, 0, 0, 0];
+    const BACKEND_KEY: &'static [u8] = &[b'K', 0, 0, 0, 12, 0, 0, 0, 42, 9, 8, 7, 6];
+    const READY_IDLE: &'static [u8] = &[b'Z', 0, 0, 0, 5, b'I'];
+    const ROW_DESCRIPTION: &'static [u8] = &[
+        b'T', 0, 0, 0, 26, 0, 1,
+        b'n', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 23, 0, 4, 255, 255, 255, 255, 0, 0,
+    ];
+    const DATA_ROW: &'static [u8] = &[b'D', 0, 0, 0, 11, 0, 1, 0, 0, 0, 1, b'7'];
+    const COMMAND_COMPLETE: &'static [u8] = &[b'C', 0, 0, 0, 13, b'S', b'E', b'L', b'E', b'C', b'T', b' ', b'1', 0];
+
+    fn started() -> Protocol {
+        let mut protocol = Protocol::new("cliff", Some(Secret::new("secret")), "cliff");
+        protocol.take_output();
+        protocol.receive(AUTH_OK);
+        protocol.receive(READY_IDLE);
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::Authenticated));
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::ReadyForQuery(TransactionStatus::Idle)));
+        protocol
+    }
+
+    #[test]
+    fn test_startup() {
+        let mut protocol = Protocol::new("cliff", Some(Secret::new("secret")), "cliff");
+        assert_eq!(protocol.state(), ConnectionState::AwaitingAuthResponse);
+        let startup = protocol.take_output();
+        assert_eq!(&startup[4..8], &[0, 3, 0, 0]);
+        assert!(!protocol.has_output());
+
+        protocol.receive(AUTH_MD5);
+        assert_eq!(protocol.next_event().unwrap(), None);
+        let hash = auth::build_md5_hash("cliff", "secret", &[1, 2, 3, 4]);
+        assert_eq!(protocol.take_output(), PasswordMessage { hash: hash.expose() }.to_bytes());
+
+        // Messages may arrive split anywhere.
+        protocol.receive(&AUTH_OK[..3]);
+        assert_eq!(protocol.next_event().unwrap(), None);
+        protocol.receive(&AUTH_OK[3..]);
+        protocol.receive(BACKEND_KEY);
+        protocol.receive(READY_IDLE);
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::Authenticated));
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::BackendKeyData(42, vec![9, 8, 7, 6])));
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::ReadyForQuery(TransactionStatus::Idle)));
+        assert_eq!(protocol.next_event().unwrap(), None);
+        assert_eq!(protocol.state(), ConnectionState::ReadyForQuery);
+        assert_eq!(protocol.backend_key(), Some((42, &[9, 8, 7, 6][..])));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let mut protocol = Protocol::new("cliff", None, "cliff");
+        protocol.receive(&[b'E', 0, 0, 0, 12, b'C', b'2', b'8', b'P', b'0', b'1', 0, 0]);
+        match protocol.next_event().unwrap() {
+            Some(Event::Error(err)) => assert_eq!(err.code, "28P01"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert_eq!(protocol.state(), ConnectionState::AuthenticationRejected);
+        assert!(protocol.query("SELECT 1;").is_err());
+    }
+
+    #[test]
+    fn test_query() {
+        let mut protocol = started();
+        protocol.query("SELECT 7;").unwrap();
+        assert_eq!(protocol.take_output(), Query { query: "SELECT 7;".to_string() }.to_bytes());
+        assert_eq!(protocol.state(), ConnectionState::AwaitingQueryResponse);
+        protocol.receive(ROW_DESCRIPTION);
+        protocol.receive(DATA_ROW);
+        protocol.receive(COMMAND_COMPLETE);
+        protocol.receive(READY_IDLE);
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::RowDescription(vec!["n".to_string()])));
+        assert_eq!(protocol.state(), ConnectionState::AwaitingDataRows);
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::DataRow(vec!["7".to_string()])));
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::CommandComplete("SELECT 1".to_string())));
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::ReadyForQuery(TransactionStatus::Idle)));
+        assert_eq!(protocol.state(), ConnectionState::ReadyForQuery);
+    }
+
+    #[test]
+    fn test_pipelined_queries() {
+        let mut protocol = started();
+        protocol.query("SELECT 7;").unwrap();
+        protocol.query("SELECT 7;").unwrap();
+        for _ in 0..2 {
+            protocol.receive(COMMAND_COMPLETE);
+            protocol.receive(READY_IDLE);
+        }
+        assert!(protocol.next_event().unwrap().is_some());
+        assert!(protocol.next_event().unwrap().is_some());
+        assert_eq!(protocol.state(), ConnectionState::AwaitingQueryResponse);
+        assert!(protocol.next_event().unwrap().is_some());
+        assert!(protocol.next_event().unwrap().is_some());
+        assert_eq!(protocol.state(), ConnectionState::ReadyForQuery);
+    }
+
+    #[test]
+    fn test_copy_in() {
+        let mut protocol = started();
+        assert!(protocol.copy_data(b"1\n").is_err());
+        protocol.query("COPY numbers FROM STDIN;").unwrap();
+        protocol.take_output();
+        protocol.receive(&[b'G', 0, 0, 0, 9, 0, 0, 1, 0, 0]);
+        assert_eq!(protocol.next_event().unwrap(), Some(Event::CopyIn(FieldFormat::Text, vec![FieldFormat::Text])));
+        protocol.copy_data(b"1\n").unwrap();
+        protocol.copy_done().unwrap();
+        assert_eq!(protocol.take_output(), vec![b'd', 0, 0, 0, 6, b'1', b'\n', b'c', 0, 0, 0, 4]);
+        assert_eq!(protocol.state(), ConnectionState::AwaitingQueryResponse);
+    }
+
+    #[test]
+    fn test_pass_through() {
+        let mut protocol = started();
+        let query = Query { query: "BEGIN".to_string() }.to_bytes();
+        protocol.send_message(&query).unwrap();
+        assert_eq!(protocol.take_output(), query);
+        protocol.receive(&COMMAND_COMPLETE[..5]);
+        assert_eq!(protocol.next_message().unwrap(), None);
+        protocol.receive(&COMMAND_COMPLETE[5..]);
+        protocol.receive(&[b'Z', 0, 0, 0, 5, b'T']);
+        assert_eq!(protocol.next_message().unwrap(), Some(COMMAND_COMPLETE.to_vec()));
+        assert_eq!(protocol.transaction_status(), TransactionStatus::Idle);
+        assert_eq!(protocol.next_message().unwrap(), Some(vec![b'Z', 0, 0, 0, 5, b'T']));
+        assert_eq!(protocol.transaction_status(), TransactionStatus::InTransaction);
+        protocol.terminate();
+        assert!(protocol.send_message(&query).is_err());
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let mut protocol = started();
+        protocol.set_max_message_size(DATA_ROW.len());
+        protocol.receive(DATA_ROW);
+        protocol.receive(&[b'D', 0, 0, 0x10, 0]);
+        assert!(protocol.next_message().unwrap().is_some());
+        match protocol.next_message() {
+            Err(PgError::MessageTooLarge(size)) => assert_eq!(size, 0x1001),
+            other => panic!("Expected the message to be refused, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unexpected_message() {
+        let mut protocol = started();
+        protocol.receive(DATA_ROW);
+        assert!(protocol.next_event().is_err());
+    }
+
+    #[test]
+    fn test_terminate() {
+        let mut protocol = started();
+        protocol.terminate();
+        protocol.terminate();
+        assert_eq!(protocol.take_output(), vec![b'X', 0, 0, 0, 4]);
+        assert_eq!(protocol.state(), ConnectionState::Disconnected);
+    }
+}
@@ -0,0 +1,86 @@
+//! Credentials which stay out of logs and are wiped from memory.
+use std::fmt;
+use std::ptr;
+use crypto::util::secure_memset;
+
+/// A password or token.
+///
+/// `Debug` never shows the value, and the memory holding it is overwritten
+/// when it is dropped.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: &str) -> Secret {
+        Secret(value.to_string())
+    }
+
+    /// The secret value.  Avoid copying it into anything that outlives the
+    /// `Secret`.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Zero the whole buffer, including any spare capacity left over from
+    /// building the string.
+    fn wipe(&mut self) {
+        // Zero bytes are valid UTF-8, so the string stays well formed.
+        unsafe {
+            let bytes = self.0.as_mut_vec();
+            let capacity = bytes.capacity();
+            ptr::write_bytes(bytes.as_mut_ptr(), 0, capacity);
+            bytes.set_len(capacity);
+            secure_memset(bytes, 0);
+        }
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret(value)
+    }
+}
+
+impl <'a> From<&'a str> for Secret {
+    fn from(value: &'a str) -> Secret {
+        Secret::new(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(********)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Secret;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let secret = Secret::new("open sesame");
+        assert_eq!(format!("{:?}", secret), "Secret(********)");
+        assert_eq!(format!("{:?}", Some(secret.clone())), "Some(Secret(********))");
+        assert_eq!(secret.expose(), "open sesame");
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mut secret = Secret::new("open sesame");
+        secret.wipe();
+        assert_eq!(secret.expose(), "\0".repeat(11));
+
+        let mut value = String::with_capacity(32);
+        value.push_str("open sesame");
+        let mut secret = Secret::from(value);
+        secret.wipe();
+        assert_eq!(secret.expose(), "\0".repeat(32));
+    }
+}
@@ -0,0 +1,1119 @@
+//! The backend side of the protocol, for building services which Postgres
+//! clients can talk to.
+//!
+//! A `Server` handles startup, authentication and the simple and extended
+//! query flows, and hands each statement to a `QueryHandler`.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::TcpListener;
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use Result;
+use auth;
+use binary_copy::BinaryCopyReader;
+use auth::{random_bytes, secure_eq, ScramServer};
+use error::{DbError, PgError};
+use message::read_frontend_message;
+use message::{FrontendMsg, PasswordMessage, SaslInitialResponse, Target};
+use secret::Secret;
+use servermsg::{AuthMsg, FieldDescription, FieldFormat, ServerMsg, TransactionStatus};
+use transport::Transport;
+
+/// How a client proves who it is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuthMethod {
+    Trust,
+    Cleartext,
+    Md5,
+    ScramSha256,
+}
+
+/// Decides how each user authenticates, and what their password is.
+pub trait Authenticator: Send + Sync {
+    fn method(&self, user: &str) -> AuthMethod;
+
+    /// The user's password, or None to refuse them.
+    fn password(&self, user: &str) -> Option<Secret>;
+}
+
+/// Lets every client in without a password.
+#[derive(Clone, Copy, Debug)]
+pub struct Trust;
+
+impl Authenticator for Trust {
+    fn method(&self, _user: &str) -> AuthMethod {
+        AuthMethod::Trust
+    }
+
+    fn password(&self, _user: &str) -> Option<Secret> {
+        None
+    }
+}
+
+/// A fixed set of users and passwords, all checked with the same method.
+#[derive(Clone)]
+pub struct Passwords {
+    method: AuthMethod,
+    passwords: HashMap<String, Secret>,
+}
+
+impl Passwords {
+    pub fn new(method: AuthMethod) -> Passwords {
+        Passwords {
+            method: method,
+            passwords: HashMap::new(),
+        }
+    }
+
+    pub fn user<S: Into<Secret>>(mut self, user: &str, password: S) -> Passwords {
+        self.passwords.insert(user.to_string(), password.into());
+        self
+    }
+}
+
+impl Authenticator for Passwords {
+    fn method(&self, _user: &str) -> AuthMethod {
+        self.method
+    }
+
+    fn password(&self, user: &str) -> Option<Secret> {
+        self.passwords.get(user).cloned()
+    }
+}
+
+impl fmt::Debug for Passwords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Passwords")
+            .field("method", &self.method)
+            .field("users", &self.passwords.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A column of a result, with the OID of its type.  Values are always sent
+/// as text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub type_oid: u32,
+}
+
+impl Column {
+    pub fn new(name: &str, type_oid: u32) -> Column {
+        Column {
+            name: name.to_string(),
+            type_oid: type_oid,
+        }
+    }
+
+    /// A column of type text.
+    pub fn text(name: &str) -> Column {
+        Column::new(name, TEXT_OID)
+    }
+}
+
+const TEXT_OID: u32 = 25;
+
+/// The result of one statement.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum QueryResult {
+    /// Rows of text values, where None is NULL.
+    Rows(Vec<Column>, Vec<Vec<Option<String>>>),
+    /// A statement which returns no rows, with its command tag.
+    Command(String),
+    /// Take data in the given format from the client for
+    /// `COPY ... FROM STDIN`, which is handed to `QueryHandler::copy_in`.
+    /// Only allowed in simple queries.
+    CopyIn(FieldFormat),
+    /// Send each chunk as a CopyData message for `COPY ... TO STDOUT`.  Only
+    /// allowed in simple queries.
+    CopyOut(FieldFormat, Vec<Vec<u8>>),
+}
+
+/// The parameter types and result columns of a prepared statement.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Description {
+    pub params: Vec<u32>,
+    /// None if the statement returns no rows.
+    pub columns: Option<Vec<Column>>,
+}
+
+/// What a client sent during startup.
+#[derive(Clone, Debug)]
+pub struct Session {
+    user: String,
+    database: Option<String>,
+    params: Vec<(String, String)>,
+    pid: u32,
+}
+
+impl Session {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn database(&self) -> Option<&str> {
+        self.database.as_ref().map(|database| &database[..])
+    }
+
+    /// A run-time parameter sent in the startup message, such as
+    /// `application_name`.
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|&&(ref param, _)| param == name).map(|&(_, ref value)| &value[..])
+    }
+
+    /// The process ID reported to the client.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+/// Answers the queries clients send.  Errors are reported to the client;
+/// a `PgError::Db` keeps its code, a `PgError::Io` closes the connection
+/// without an answer, and anything else becomes XX000.
+pub trait QueryHandler: Send + Sync {
+    /// Run a simple query, which may hold several statements.
+    fn query(&self, session: &Session, sql: &str) -> Result<Vec<QueryResult>>;
+
+    /// Describe a statement prepared with the extended query protocol.
+    /// `param_types` holds the types the client gave, where zero means
+    /// unspecified.  This is also called when the statement is parsed, and
+    /// an error refuses it.  By default nothing is inferred, and the
+    /// statement is described as returning no rows.
+    fn describe(&self, session: &Session, sql: &str, param_types: &[u32]) -> Result<Description> {
+        let _ = (session, sql);
+        Ok(Description { params: param_types.to_vec(), columns: None })
+    }
+
+    /// Run a statement from the extended query protocol, with its
+    /// parameters as text.  By default, a statement without parameters is
+    /// run as a simple query.
+    fn execute(&self, session: &Session, sql: &str, params: &[Option<String>]) -> Result<QueryResult> {
+        if !params.is_empty() {
+            return Err(PgError::Db(DbError::new("0A000", "parameters are not supported")));
+        }
+        match try!(self.query(session, sql)).pop() {
+            Some(result) => Ok(result),
+            None => Err(PgError::Db(DbError::new("42601", "empty statement"))),
+        }
+    }
+
+    /// Take the data sent for a statement which returned
+    /// `QueryResult::CopyIn`, and return its command tag.  By default the
+    /// data is dropped after its rows are counted.
+    fn copy_in(&self, session: &Session, sql: &str, format: FieldFormat, data: &[u8]) -> Result<String> {
+        let _ = (session, sql);
+        Ok(format!("COPY {}", try!(copy_rows(format, data))))
+    }
+}
+
+/// Serves the backend side of the protocol.  Clones share their handler,
+/// authenticator and process ID counter.
+#[derive(Clone)]
+pub struct Server {
+    handler: Arc<dyn QueryHandler>,
+    authenticator: Arc<dyn Authenticator>,
+    parameters: Vec<(String, String)>,
+    next_pid: Arc<AtomicUsize>,
+}
+
+impl Server {
+    /// A server which trusts every client.
+    pub fn new(handler: Arc<dyn QueryHandler>) -> Server {
+        Server {
+            handler: handler,
+            authenticator: Arc::new(Trust),
+            parameters: vec![
+                ("server_version".to_string(), "16.0".to_string()),
+                ("server_encoding".to_string(), "UTF8".to_string()),
+                ("client_encoding".to_string(), "UTF8".to_string()),
+                ("DateStyle".to_string(), "ISO, MDY".to_string()),
+                ("integer_datetimes".to_string(), "on".to_string()),
+                ("standard_conforming_strings".to_string(), "on".to_string()),
+            ],
+            next_pid: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Server {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Report a server parameter to each client during startup, replacing
+    /// any default of the same name.
+    pub fn parameter(mut self, name: &str, value: &str) -> Server {
+        self.parameters.retain(|&(ref param, _)| param != name);
+        self.parameters.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Accept clients until the listener fails, serving each on its own
+    /// thread.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = try!(stream);
+            try!(stream.set_nodelay(true));
+            let server = self.clone();
+            thread::spawn(move || {
+                let _ = server.serve_connection(stream);
+            });
+        }
+        Ok(())
+    }
+
+    /// Serve one client until it disconnects.
+    pub fn serve_connection<S: Transport>(&self, mut stream: S) -> Result<()> {
+        match try!(self.handshake(&mut stream)) {
+            Some(session) => self.serve_session(stream, &session),
+            None => Ok(()),
+        }
+    }
+
+    /// Run startup and authentication for a client, up to its first
+    /// ReadyForQuery.  None if the client was turned away or only wanted to
+    /// cancel a query.  This lets a service such as a proxy take over the
+    /// rest of the conversation.
+    pub fn handshake<S: Transport>(&self, stream: &mut S) -> Result<Option<Session>> {
+        let pid = self.next_pid.fetch_add(1, Ordering::SeqCst) as u32;
+        let mut backend = Backend::new(stream, self);
+        let session = try!(backend.handshake(pid));
+        try!(backend.flush());
+        Ok(session)
+    }
+
+    /// Answer the queries of a client which has been through `handshake`.
+    pub fn serve_session<S: Transport>(&self, stream: S, session: &Session) -> Result<()> {
+        Backend::new(stream, self).run(session)
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("parameters", &self.parameters)
+            .finish()
+    }
+}
+
+/// One client's connection to the server.
+struct Backend<'a, S: Transport> {
+    stream: S,
+    server: &'a Server,
+    /// Messages waiting to be sent, which are flushed before reading.
+    output: Vec<u8>,
+    status: TransactionStatus,
+    statements: HashMap<String, (String, Vec<u32>)>,
+    portals: HashMap<String, (String, Vec<Option<String>>)>,
+    /// After an error in an extended query, messages are ignored until the
+    /// next Sync.
+    skip_to_sync: bool,
+}
+
+impl <'a, S: Transport> Backend<'a, S> {
+    fn new(stream: S, server: &'a Server) -> Backend<'a, S> {
+        Backend {
+            stream: stream,
+            server: server,
+            output: vec![],
+            status: TransactionStatus::Idle,
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+            skip_to_sync: false,
+        }
+    }
+
+    fn handshake(&mut self, pid: u32) -> Result<Option<Session>> {
+        let session = match try!(self.startup(pid)) {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        if !try!(self.authenticate(&session)) {
+            return Ok(None);
+        }
+        self.send(ServerMsg::Auth(AuthMsg::Ok));
+        for &(ref name, ref value) in &self.server.parameters {
+            self.output.extend(ServerMsg::ParamStatus(name, value).to_bytes());
+        }
+        self.send(ServerMsg::BackendKeyData(pid, &random_bytes(4)));
+        self.ready_for_query();
+        Ok(Some(session))
+    }
+
+    fn run(&mut self, session: &Session) -> Result<()> {
+        loop {
+            let bytes = try!(self.read_message(false));
+            let msg = try!(FrontendMsg::from_slice(&bytes));
+            if self.skip_to_sync && msg != FrontendMsg::Sync {
+                continue;
+            }
+            match msg {
+                FrontendMsg::Query(query) => try!(self.simple_query(session, &query.query)),
+                FrontendMsg::Parse(parse) => {
+                    if !parse.name.is_empty() && self.statements.contains_key(parse.name) {
+                        let message = format!("prepared statement \"{}\" already exists", parse.name);
+                        self.extended_error(&DbError::new("42P05", &message));
+                        continue;
+                    }
+                    // The statement is checked as it is parsed, as Postgres
+                    // does, so a bad one is never stored.
+                    match self.server.handler.describe(session, parse.query, &parse.param_types) {
+                        Ok(_) => {
+                            self.statements.insert(parse.name.to_string(), (parse.query.to_string(), parse.param_types));
+                            self.send(ServerMsg::ParseComplete);
+                        },
+                        Err(PgError::Io(err)) => return Err(PgError::Io(err)),
+                        Err(err) => {
+                            self.skip_to_sync = true;
+                            self.handler_error(err);
+                        },
+                    }
+                },
+                FrontendMsg::Bind(bind) => {
+                    let sql = match self.statements.get(bind.statement) {
+                        Some(&(ref sql, _)) => sql.clone(),
+                        None => {
+                            let message = format!("prepared statement \"{}\" does not exist", bind.statement);
+                            self.extended_error(&DbError::new("26000", &message));
+                            continue;
+                        },
+                    };
+                    let binary = |formats: &[FieldFormat]| formats.iter().any(|&format| format == FieldFormat::Binary);
+                    if binary(&bind.param_formats) || binary(&bind.result_formats) {
+                        self.extended_error(&DbError::new("0A000", "binary format is not supported"));
+                        continue;
+                    }
+                    let mut params = Vec::with_capacity(bind.params.len());
+                    for param in &bind.params {
+                        match *param {
+                            Some(value) => match from_utf8(value) {
+                                Ok(value) => params.push(Some(value.to_string())),
+                                Err(_) => break,
+                            },
+                            None => params.push(None),
+                        }
+                    }
+                    if params.len() != bind.params.len() {
+                        self.extended_error(&DbError::new("22021", "invalid UTF-8 in parameter"));
+                        continue;
+                    }
+                    self.portals.insert(bind.portal.to_string(), (sql, params));
+                    self.send(ServerMsg::BindComplete);
+                },
+                FrontendMsg::Describe(describe) => self.describe(session, describe.target, describe.name),
+                FrontendMsg::Execute(execute) => try!(self.execute(session, execute.portal)),
+                FrontendMsg::Close(close) => {
+                    match close.target {
+                        Target::Statement => { self.statements.remove(close.name); },
+                        Target::Portal => { self.portals.remove(close.name); },
+                    }
+                    self.send(ServerMsg::CloseComplete);
+                },
+                FrontendMsg::Sync => {
+                    self.skip_to_sync = false;
+                    self.portals.remove("");
+                    self.ready_for_query();
+                },
+                FrontendMsg::Flush => try!(self.flush()),
+                FrontendMsg::Terminate => return self.flush(),
+                // Left over from a COPY which failed.
+                FrontendMsg::CopyData(_) | FrontendMsg::CopyDone | FrontendMsg::CopyFail(_) => {},
+                FrontendMsg::FunctionCall(_) => {
+                    self.extended_error(&DbError::new("0A000", "function calls are not supported"));
+                },
+                msg => {
+                    let mut err = DbError::new("08P01", &format!("unexpected message: {:?}", msg));
+                    err.severity = "FATAL".to_string();
+                    self.error(&err);
+                    return self.flush();
+                },
+            }
+        }
+    }
+
+    /// Read the startup message, turning down SSL and GSS encryption.
+    /// None if the client only wanted to cancel a query.
+    fn startup(&mut self, pid: u32) -> Result<Option<Session>> {
+        loop {
+            let bytes = try!(self.read_message(true));
+            match FrontendMsg::from_slice(&bytes) {
+                Ok(FrontendMsg::SslRequest) | Ok(FrontendMsg::GssEncRequest) => try!(self.stream.write_all(b"N")),
+                Ok(FrontendMsg::Startup(startup)) => {
+                    return Ok(Some(Session {
+                        user: startup.user.to_string(),
+                        database: startup.database.map(|database| database.to_string()),
+                        params: startup.params,
+                        pid: pid,
+                    }));
+                },
+                Ok(_) => return Ok(None),
+                Err(err) => {
+                    let mut err = DbError::new("08P01", &err.to_string());
+                    err.severity = "FATAL".to_string();
+                    self.error(&err);
+                    return Ok(None);
+                },
+            }
+        }
+    }
+
+    fn authenticate(&mut self, session: &Session) -> Result<bool> {
+        let authenticator = self.server.authenticator.clone();
+        let method = authenticator.method(session.user());
+        let password = authenticator.password(session.user());
+        let accepted = match method {
+            AuthMethod::Trust => true,
+            AuthMethod::Cleartext => {
+                self.send(ServerMsg::Auth(AuthMsg::Cleartext));
+                let body = try!(self.read_password());
+                let given = try!(PasswordMessage::from_body(&body));
+                password.map_or(false, |password| secure_eq(given.hash.as_bytes(), password.expose().as_bytes()))
+            },
+            AuthMethod::Md5 => {
+                let salt = random_bytes(4);
+                self.send(ServerMsg::Auth(AuthMsg::Md5(&salt)));
+                let body = try!(self.read_password());
+                let given = try!(PasswordMessage::from_body(&body));
+                password.map_or(false, |password| {
+                    let expected = auth::build_md5_hash(session.user(), password.expose(), &salt);
+                    secure_eq(given.hash.as_bytes(), expected.expose().as_bytes())
+                })
+            },
+            AuthMethod::ScramSha256 => {
+                // Unknown users go through the whole exchange, so they
+                // cannot be told apart from a wrong password.
+                let known = password.is_some();
+                let password = password.unwrap_or_else(|| Secret::from(auth::base64_encode(&random_bytes(18))));
+                let verified = try!(self.scram(password.expose()));
+                known && verified
+            },
+        };
+        if !accepted {
+            let message = format!("password authentication failed for user \"{}\"", session.user());
+            let mut err = DbError::new("28P01", &message);
+            err.severity = "FATAL".to_string();
+            self.error(&err);
+        }
+        Ok(accepted)
+    }
+
+    fn scram(&mut self, password: &str) -> Result<bool> {
+        let mut scram = ScramServer::new(password);
+        self.send(ServerMsg::Auth(AuthMsg::Sasl(vec!["SCRAM-SHA-256"])));
+        let body = try!(self.read_password());
+        let initial = try!(SaslInitialResponse::from_body(&body));
+        let server_first = match scram.server_first(initial.data.unwrap_or(&[])) {
+            Some(server_first) if initial.mechanism == "SCRAM-SHA-256" => server_first,
+            _ => return Ok(false),
+        };
+        self.send(ServerMsg::Auth(AuthMsg::SaslContinue(&server_first)));
+        let client_final = try!(self.read_password());
+        match scram.server_final(&client_final) {
+            Some(server_final) => {
+                self.send(ServerMsg::Auth(AuthMsg::SaslFinal(&server_final)));
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// The body of the client's next password message.
+    fn read_password(&mut self) -> Result<Vec<u8>> {
+        let bytes = try!(self.read_message(false));
+        match try!(FrontendMsg::from_slice(&bytes)) {
+            FrontendMsg::Password(body) => Ok(body.to_vec()),
+            msg => Err(PgError::Error(format!("Expected a password message, found {:?}", msg))),
+        }
+    }
+
+    fn simple_query(&mut self, session: &Session, sql: &str) -> Result<()> {
+        if sql.trim().trim_end_matches(';').trim().is_empty() {
+            self.send(ServerMsg::EmptyQueryResponse);
+        } else if self.ignored(sql) {
+            self.error(&aborted_transaction());
+        } else {
+            match self.server.handler.query(session, sql) {
+                Ok(results) => {
+                    for result in results {
+                        match result {
+                            QueryResult::CopyIn(format) => {
+                                if !try!(self.copy_in(session, sql, format)) {
+                                    break;
+                                }
+                            },
+                            result => {
+                                if let QueryResult::Rows(ref columns, _) = result {
+                                    self.row_description(columns);
+                                }
+                                self.result(sql, result);
+                            },
+                        }
+                    }
+                },
+                Err(PgError::Io(err)) => return Err(PgError::Io(err)),
+                Err(err) => self.handler_error(err),
+            }
+        }
+        self.ready_for_query();
+        Ok(())
+    }
+
+    /// Whether `sql` must be refused because the transaction has failed.
+    /// Only the end of the transaction is allowed.
+    fn ignored(&self, sql: &str) -> bool {
+        let keyword = sql.split_whitespace().next().unwrap_or("").trim_end_matches(';').to_uppercase();
+        self.status == TransactionStatus::Failed && !["COMMIT", "END", "ROLLBACK", "ABORT"].contains(&&keyword[..])
+    }
+
+    /// Take the client's data for `COPY ... FROM STDIN`.  False if the
+    /// client gave up, or the handler refused the data.
+    fn copy_in(&mut self, session: &Session, sql: &str, format: FieldFormat) -> Result<bool> {
+        self.send(ServerMsg::CopyInResponse(format, vec![]));
+        let mut data = vec![];
+        loop {
+            let bytes = try!(self.read_message(false));
+            match try!(FrontendMsg::from_slice(&bytes)) {
+                FrontendMsg::CopyData(copy) => data.extend(copy.data),
+                FrontendMsg::CopyDone => {
+                    return match self.server.handler.copy_in(session, sql, format, &data) {
+                        Ok(tag) => {
+                            self.result(sql, QueryResult::Command(tag));
+                            Ok(true)
+                        },
+                        Err(PgError::Io(err)) => Err(PgError::Io(err)),
+                        Err(err) => {
+                            self.handler_error(err);
+                            Ok(false)
+                        },
+                    };
+                },
+                FrontendMsg::CopyFail(fail) => {
+                    let message = format!("COPY from stdin failed: {}", fail.message);
+                    self.error(&DbError::new("57014", &message));
+                    return Ok(false);
+                },
+                FrontendMsg::Flush | FrontendMsg::Sync => {},
+                msg => {
+                    let message = format!("unexpected message during COPY: {:?}", msg);
+                    self.error(&DbError::new("08P01", &message));
+                    return Ok(false);
+                },
+            }
+        }
+    }
+
+    fn describe(&mut self, session: &Session, target: Target, name: &str) {
+        let (sql, param_types) = match target {
+            Target::Statement => match self.statements.get(name) {
+                Some(&(ref sql, ref param_types)) => (sql.clone(), param_types.clone()),
+                None => {
+                    let message = format!("prepared statement \"{}\" does not exist", name);
+                    return self.extended_error(&DbError::new("26000", &message));
+                },
+            },
+            Target::Portal => match self.portals.get(name) {
+                Some(&(ref sql, ref params)) => (sql.clone(), vec![0; params.len()]),
+                None => {
+                    let message = format!("portal \"{}\" does not exist", name);
+                    return self.extended_error(&DbError::new("34000", &message));
+                },
+            },
+        };
+        let description = match self.server.handler.describe(session, &sql, &param_types) {
+            Ok(description) => description,
+            Err(err) => {
+                self.skip_to_sync = true;
+                return self.handler_error(err);
+            },
+        };
+        if target == Target::Statement {
+            self.send(ServerMsg::ParameterDescription(description.params));
+        }
+        match description.columns {
+            Some(ref columns) => self.row_description(columns),
+            None => self.send(ServerMsg::NoData),
+        }
+    }
+
+    /// Run a portal.  Every row is sent, whatever the row limit.
+    fn execute(&mut self, session: &Session, portal: &str) -> Result<()> {
+        let (sql, params) = match self.portals.get(portal) {
+            Some(&(ref sql, ref params)) => (sql.clone(), params.clone()),
+            None => {
+                let message = format!("portal \"{}\" does not exist", portal);
+                self.extended_error(&DbError::new("34000", &message));
+                return Ok(());
+            },
+        };
+        if sql.trim().is_empty() {
+            self.send(ServerMsg::EmptyQueryResponse);
+        } else if self.ignored(&sql) {
+            self.extended_error(&aborted_transaction());
+        } else {
+            match self.server.handler.execute(session, &sql, &params) {
+                Ok(QueryResult::CopyIn(_)) | Ok(QueryResult::CopyOut(..)) => {
+                    self.extended_error(&DbError::new("0A000", "COPY is not supported in extended queries"));
+                },
+                Ok(result) => self.result(&sql, result),
+                Err(PgError::Io(err)) => return Err(PgError::Io(err)),
+                Err(err) => {
+                    self.skip_to_sync = true;
+                    self.handler_error(err);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn row_description(&mut self, columns: &[Column]) {
+        let fields = columns.iter()
+            .map(|column| FieldDescription::new(&column.name, column.type_oid, FieldFormat::Text))
+            .collect();
+        self.send(ServerMsg::RowDescription(fields));
+    }
+
+    /// Send the rows of a result of `sql`, then its command tag.
+    fn result(&mut self, sql: &str, result: QueryResult) {
+        let tag = match result {
+            QueryResult::Rows(_, rows) => {
+                for row in &rows {
+                    self.send(ServerMsg::DataRow(row.iter().map(|value| value.as_ref().map(|value| &value[..])).collect()));
+                }
+                format!("SELECT {}", rows.len())
+            },
+            QueryResult::Command(tag) => tag,
+            QueryResult::CopyOut(format, chunks) => {
+                let rows = match copy_rows(format, &chunks.concat()) {
+                    Ok(rows) => rows,
+                    Err(err) => return self.handler_error(err),
+                };
+                self.send(ServerMsg::CopyOutResponse(format, vec![]));
+                for chunk in &chunks {
+                    self.send(ServerMsg::CopyData(chunk));
+                }
+                self.send(ServerMsg::CopyDone);
+                format!("COPY {}", rows)
+            },
+            QueryResult::CopyIn(_) => return self.error(&DbError::new("0A000", "COPY FROM STDIN is not supported here")),
+        };
+        match tag.split_whitespace().next().unwrap_or("") {
+            "BEGIN" => self.status = TransactionStatus::InTransaction,
+            "ROLLBACK" if rolls_back_to_savepoint(sql) => self.status = TransactionStatus::InTransaction,
+            "COMMIT" | "ROLLBACK" => self.status = TransactionStatus::Idle,
+            _ => {},
+        }
+        self.send(ServerMsg::CommandComplete(&tag));
+    }
+
+    fn handler_error(&mut self, err: PgError) {
+        match err {
+            PgError::Db(err) => self.error(&err),
+            err => self.error(&DbError::new("XX000", &err.to_string())),
+        }
+    }
+
+    fn extended_error(&mut self, err: &DbError) {
+        self.skip_to_sync = true;
+        self.error(err);
+    }
+
+    fn error(&mut self, err: &DbError) {
+        if self.status == TransactionStatus::InTransaction {
+            self.status = TransactionStatus::Failed;
+        }
+        self.send(ServerMsg::ErrorResponse(err.fields()));
+    }
+
+    fn ready_for_query(&mut self) {
+        let status = self.status;
+        self.send(ServerMsg::ReadyForQuery(status));
+    }
+
+    fn send(&mut self, msg: ServerMsg) {
+        self.output.extend(msg.to_bytes());
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.output.is_empty() {
+            try!(self.stream.write_all(&self.output));
+            try!(self.stream.flush());
+            self.output.clear();
+        }
+        Ok(())
+    }
+
+    fn read_message(&mut self, startup: bool) -> Result<Vec<u8>> {
+        try!(self.flush());
+        Ok(try!(read_frontend_message(&mut self.stream, startup)))
+    }
+}
+
+/// The number of rows in COPY data: its lines in text, or its tuples in
+/// binary.
+pub(crate) fn copy_rows(format: FieldFormat, data: &[u8]) -> Result<usize> {
+    if format == FieldFormat::Text {
+        return Ok(data.iter().filter(|&&byte| byte == b'\n').count());
+    }
+    let bad_copy = |err: PgError| PgError::Db(DbError::new("22P04", &format!("bad COPY file format: {}", err)));
+    let mut reader = try!(BinaryCopyReader::new(data).map_err(bad_copy));
+    let mut rows = 0;
+    while try!(reader.next_row().map_err(bad_copy)).is_some() {
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+/// Whether `sql` rolls back to a savepoint, which keeps the transaction
+/// open although its tag is ROLLBACK.
+fn rolls_back_to_savepoint(sql: &str) -> bool {
+    sql.split(';').any(|statement| {
+        let mut words = statement.split_whitespace().map(str::to_uppercase);
+        words.next().map_or(false, |word| word == "ROLLBACK")
+            && words.find(|word| word != "WORK" && word != "TRANSACTION").map_or(false, |word| word == "TO")
+    })
+}
+
+fn aborted_transaction() -> DbError {
+    DbError::new("25P02", "current transaction is aborted, commands ignored until end of transaction block")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use auth::scram_client_final;
+    use binary_copy::BinaryCopyWriter;
+    use config::Config;
+    use connection::Connection;
+    use error::{DbError, PgError};
+    use message::*;
+    use servermsg::{take_msg, AuthMsg, ServerMsg, TransactionStatus};
+    use Result;
+    use super::*;
+
+    /// A single table of names, which can be listed and added to.
+    struct Names {
+        names: Mutex<Vec<String>>,
+    }
+
+    impl QueryHandler for Names {
+        fn query(&self, session: &Session, sql: &str) -> Result<Vec<QueryResult>> {
+            let mut results = vec![];
+            for statement in sql.split(';').map(str::trim).filter(|statement| !statement.is_empty()) {
+                results.push(try!(self.execute(session, statement, &[])));
+            }
+            Ok(results)
+        }
+
+        fn describe(&self, _session: &Session, sql: &str, _param_types: &[u32]) -> Result<Description> {
+            if sql == "SELECT nonsense" {
+                Err(PgError::Db(DbError::new("42703", "column \"nonsense\" does not exist")))
+            } else if sql.starts_with("SELECT") {
+                Ok(Description { params: vec![25], columns: Some(vec![Column::text("name")]) })
+            } else {
+                Ok(Description { params: vec![25], columns: None })
+            }
+        }
+
+        fn execute(&self, session: &Session, sql: &str, params: &[Option<String>]) -> Result<QueryResult> {
+            let mut names = self.names.lock().unwrap();
+            match sql {
+                "BEGIN" | "COMMIT" | "ROLLBACK" => Ok(QueryResult::Command(sql.to_string())),
+                "SAVEPOINT a" => Ok(QueryResult::Command("SAVEPOINT".to_string())),
+                "ROLLBACK TO SAVEPOINT a" => Ok(QueryResult::Command("ROLLBACK".to_string())),
+                "SELECT user" => Ok(QueryResult::Rows(
+                    vec![Column::text("user")],
+                    vec![vec![Some(session.user().to_string())]],
+                )),
+                "SELECT name FROM names" => Ok(QueryResult::Rows(
+                    vec![Column::text("name")],
+                    names.iter().map(|name| vec![Some(name.clone())]).collect(),
+                )),
+                "SELECT $1" => Ok(QueryResult::Rows(vec![Column::text("name")], vec![params.to_vec()])),
+                "INSERT INTO names VALUES ($1)" => {
+                    names.push(params[0].clone().unwrap_or_default());
+                    Ok(QueryResult::Command("INSERT 0 1".to_string()))
+                },
+                "COPY names FROM STDIN" => Ok(QueryResult::CopyIn(FieldFormat::Text)),
+                "COPY names FROM STDIN WITH (FORMAT binary)" => Ok(QueryResult::CopyIn(FieldFormat::Binary)),
+                "COPY names TO STDOUT" => Ok(QueryResult::CopyOut(
+                    FieldFormat::Text,
+                    names.iter().map(|name| format!("{}\n", name).into_bytes()).collect(),
+                )),
+                _ => Err(PgError::Db(DbError::new("42601", &format!("syntax error in {}", sql)))),
+            }
+        }
+
+        fn copy_in(&self, _session: &Session, _sql: &str, format: FieldFormat, data: &[u8]) -> Result<String> {
+            if format == FieldFormat::Binary {
+                return Ok(format!("COPY {}", try!(copy_rows(format, data))));
+            }
+            let mut names = self.names.lock().unwrap();
+            let before = names.len();
+            names.extend(String::from_utf8_lossy(data).lines().map(str::to_string));
+            Ok(format!("COPY {}", names.len() - before))
+        }
+    }
+
+    fn start(server: Server) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || server.serve(listener));
+        port
+    }
+
+    fn names() -> Arc<Names> {
+        Arc::new(Names { names: Mutex::new(vec!["ann".to_string()]) })
+    }
+
+    #[test]
+    fn test_simple_query() {
+        let port = start(Server::new(names()));
+        let mut conn = Connection::connect(&Config::new("cliff").host("127.0.0.1").port(port)).unwrap();
+        assert_eq!(conn.query("SELECT user").unwrap(), vec![vec!["cliff".to_string()]]);
+        assert_eq!(
+            conn.query("BEGIN; SELECT name FROM names").unwrap(),
+            vec![vec!["ann".to_string()]]
+        );
+        assert_eq!(conn.transaction_status(), TransactionStatus::InTransaction);
+        conn.query("SAVEPOINT a").unwrap();
+        match conn.query("DROP TABLE names") {
+            Err(PgError::Db(err)) => assert_eq!(err.code, "42601"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert_eq!(conn.transaction_status(), TransactionStatus::Failed);
+        // The client refuses queries itself, so send one past it.
+        conn.send_message(&Query { query: "SELECT user".to_string() }.to_bytes()).unwrap();
+        match ServerMsg::from_slice(&conn.read_message().unwrap()).unwrap() {
+            ServerMsg::ErrorResponse(fields) => assert_eq!(DbError::from_fields(&fields).code, "25P02"),
+            msg => panic!("Expected the query to be ignored, got {:?}", msg),
+        }
+        assert_eq!(conn.read_message().unwrap(), ServerMsg::ReadyForQuery(TransactionStatus::Failed).to_bytes());
+        conn.query("ROLLBACK TO SAVEPOINT a").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::InTransaction);
+        conn.query("ROLLBACK").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+    }
+
+    #[test]
+    fn test_copy() {
+        let port = start(Server::new(names()));
+        let mut conn = Connection::connect(&Config::new("cliff").host("127.0.0.1").port(port)).unwrap();
+        let mut writer = conn.copy_in("COPY names FROM STDIN").unwrap();
+        writer.write_all(b"bob\ncy\n").unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+
+        {
+            let mut writer = conn.copy_in("COPY names FROM STDIN").unwrap();
+            writer.write_all(b"dropped\n").unwrap();
+        }
+        let mut reader = conn.copy_out("COPY names TO STDOUT").unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "ann\nbob\ncy\n");
+        assert_eq!(reader.finish().unwrap(), 3);
+
+        let mut writer = BinaryCopyWriter::new(conn.copy_in("COPY names FROM STDIN WITH (FORMAT binary)").unwrap());
+        writer.write_row(&[&"dee"]).unwrap();
+        writer.write_row(&[&"eve"]).unwrap();
+        assert_eq!(writer.finish().unwrap().finish().unwrap(), 2);
+        let mut writer = conn.copy_in("COPY names FROM STDIN WITH (FORMAT binary)").unwrap();
+        writer.write_all(b"not binary").unwrap();
+        match writer.finish() {
+            Err(err) => assert_eq!(err.code(), Some("22P04")),
+            Ok(count) => panic!("Expected the data to be refused, got {}", count),
+        }
+    }
+
+    #[test]
+    fn test_md5_auth() {
+        let passwords = Passwords::new(AuthMethod::Md5).user("cliff", "secret");
+        let port = start(Server::new(names()).authenticator(Arc::new(passwords)));
+        let config = Config::new("cliff").host("127.0.0.1").port(port);
+        assert!(Connection::connect(&config.clone().password("secret")).is_ok());
+        match Connection::connect(&config.password("wrong")) {
+            Err(err) => assert_eq!(err.code(), Some("28P01")),
+            Ok(_) => panic!("Expected authentication to fail"),
+        }
+        match Connection::connect(&Config::new("nobody").host("127.0.0.1").port(port)) {
+            Err(err) => assert_eq!(err.code(), Some("28P01")),
+            Ok(_) => panic!("Expected authentication to fail"),
+        }
+    }
+
+    #[test]
+    fn test_scram_auth() {
+        let passwords = Passwords::new(AuthMethod::ScramSha256).user("cliff", "secret");
+        let port = start(Server::new(names()).authenticator(Arc::new(passwords)));
+        // Unknown users go through the same exchange as a wrong password.
+        for &(user, password, accepted) in &[("cliff", "secret", true), ("cliff", "wrong", false), ("nobody", "secret", false)] {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(&StartupMessage { user: user, database: None, params: vec![] }.to_bytes()).unwrap();
+            let bytes = read_frame(&mut stream);
+            assert_eq!(ServerMsg::from_slice(&bytes).unwrap(), ServerMsg::Auth(AuthMsg::Sasl(vec!["SCRAM-SHA-256"])));
+            let initial = SaslInitialResponse { mechanism: "SCRAM-SHA-256", data: Some(b"n,,n=,r=nonce") };
+            stream.write_all(&initial.to_bytes()).unwrap();
+            let bytes = read_frame(&mut stream);
+            let server_first = match ServerMsg::from_slice(&bytes).unwrap() {
+                ServerMsg::Auth(AuthMsg::SaslContinue(data)) => String::from_utf8(data.to_vec()).unwrap(),
+                msg => panic!("Unexpected message {:?}", msg),
+            };
+            let (client_final, expected) = scram_client_final(password, "n=,r=nonce", &server_first);
+            stream.write_all(&SaslResponse { data: client_final.as_bytes() }.to_bytes()).unwrap();
+            let bytes = read_frame(&mut stream);
+            match ServerMsg::from_slice(&bytes).unwrap() {
+                ServerMsg::Auth(AuthMsg::SaslFinal(data)) => {
+                    assert!(accepted);
+                    assert_eq!(data, expected.as_bytes());
+                },
+                ServerMsg::ErrorResponse(fields) => {
+                    assert!(!accepted);
+                    assert_eq!(DbError::from_fields(&fields).code, "28P01");
+                },
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn test_extended_query() {
+        let port = start(Server::new(names()));
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&StartupMessage { user: "cliff", database: None, params: vec![] }.to_bytes()).unwrap();
+        while read_frame(&mut stream)[0] != b'Z' {}
+
+        let mut messages = Parse { name: "insert", query: "INSERT INTO names VALUES ($1)", param_types: vec![] }.to_bytes();
+        messages.extend(Describe { target: Target::Statement, name: "insert" }.to_bytes());
+        messages.extend(Bind {
+            portal: "",
+            statement: "insert",
+            param_formats: vec![],
+            params: vec![Some(b"bob")],
+            result_formats: vec![],
+        }.to_bytes());
+        messages.extend(Execute { portal: "", max_rows: 0 }.to_bytes());
+        messages.extend(Parse { name: "", query: "SELECT $1", param_types: vec![] }.to_bytes());
+        messages.extend(Bind {
+            portal: "",
+            statement: "",
+            param_formats: vec![],
+            params: vec![None],
+            result_formats: vec![],
+        }.to_bytes());
+        messages.extend(Describe { target: Target::Portal, name: "" }.to_bytes());
+        messages.extend(Execute { portal: "", max_rows: 0 }.to_bytes());
+        messages.extend(Sync.to_bytes());
+        stream.write_all(&messages).unwrap();
+
+        let mut frames = vec![];
+        while frames.last().map(|frame: &Vec<u8>| frame[0]) != Some(b'Z') {
+            frames.push(read_frame(&mut stream));
+        }
+        let ids: Vec<u8> = frames.iter().map(|frame| frame[0]).collect();
+        assert_eq!(ids, b"1tn2C12TDCZ".to_vec());
+        assert_eq!(ServerMsg::from_slice(&frames[1]).unwrap(), ServerMsg::ParameterDescription(vec![25]));
+        assert_eq!(ServerMsg::from_slice(&frames[4]).unwrap(), ServerMsg::CommandComplete("INSERT 0 1"));
+        // A single NULL column.
+        assert_eq!(frames[8], b"D\0\0\0\x0a\0\x01\xff\xff\xff\xff".to_vec());
+
+        // After an error, messages are skipped until Sync.
+        let mut messages = Parse { name: "", query: "SELECT 1", param_types: vec![] }.to_bytes();
+        messages.extend(Bind {
+            portal: "",
+            statement: "missing",
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        }.to_bytes());
+        messages.extend(Execute { portal: "", max_rows: 0 }.to_bytes());
+        messages.extend(Sync.to_bytes());
+        messages.extend(Query { query: "SELECT name FROM names".to_string() }.to_bytes());
+        stream.write_all(&messages).unwrap();
+        let mut ids = vec![];
+        for _ in 0..7 {
+            ids.push(read_frame(&mut stream)[0]);
+        }
+        assert_eq!(ids, b"1EZTDDC".to_vec());
+        while read_frame(&mut stream)[0] != b'Z' {}
+
+        // A statement which is refused as it is parsed is not stored.
+        let mut messages = Parse { name: "bad", query: "SELECT nonsense", param_types: vec![] }.to_bytes();
+        messages.extend(Bind {
+            portal: "",
+            statement: "bad",
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        }.to_bytes());
+        messages.extend(Sync.to_bytes());
+        messages.extend(Parse { name: "bad", query: "SELECT $1", param_types: vec![] }.to_bytes());
+        messages.extend(Sync.to_bytes());
+        stream.write_all(&messages).unwrap();
+        let frame = read_frame(&mut stream);
+        match ServerMsg::from_slice(&frame).unwrap() {
+            ServerMsg::ErrorResponse(fields) => assert_eq!(DbError::from_fields(&fields).code, "42703"),
+            msg => panic!("Expected the statement to be refused, got {:?}", msg),
+        }
+        let mut ids = vec![];
+        for _ in 0..3 {
+            ids.push(read_frame(&mut stream)[0]);
+        }
+        assert_eq!(ids, b"Z1Z".to_vec());
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Server::new(names()).parameter("server_version", "9.6.1");
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.serve_connection(stream)
+        });
+        {
+            let mut conn = Connection::connect(&Config::new("cliff").host("127.0.0.1").port(port)).unwrap();
+            assert_eq!(conn.query("SELECT user").unwrap(), vec![vec!["cliff".to_string()]]);
+        }
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let passwords = Passwords::new(AuthMethod::Md5).user("cliff", "secret");
+        let server = Server::new(names()).authenticator(Arc::new(passwords));
+        let handle = thread::spawn(move || {
+            let mut users = vec![];
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                if let Some(session) = server.handshake(&mut stream).unwrap() {
+                    users.push(session.user().to_string());
+                    server.serve_session(stream, &session).unwrap();
+                }
+            }
+            users
+        });
+        let config = Config::new("cliff").host("127.0.0.1").port(port);
+        assert!(Connection::connect(&config.clone().password("wrong")).is_err());
+        {
+            let mut conn = Connection::connect(&config.password("secret")).unwrap();
+            assert_eq!(conn.query("SELECT user").unwrap(), vec![vec!["cliff".to_string()]]);
+        }
+        assert_eq!(handle.join().unwrap(), vec!["cliff".to_string()]);
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+        let mut bytes = vec![0; 5];
+        stream.read_exact(&mut bytes).unwrap();
+        let length = bytes[1..].iter().fold(0, |length, &byte| length << 8 | byte as usize);
+        bytes.resize(length + 1, 0);
+        stream.read_exact(&mut bytes[5..]).unwrap();
+        assert_eq!(take_msg(&bytes).unwrap().1.len(), 0);
+        bytes
+    }
+}
@@ -1,15 +1,33 @@
 use std::str::from_utf8;
 use Result;
 use error::PgError;
+use message::{extend_i16, extend_i32, extend_string, extend_u16, extend_u32, frame};
 use self::erg::*;
 
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum FieldFormat {
     Text,
     Binary
 }
 
+impl FieldFormat {
+    pub(crate) fn from_code(code: u16) -> Result<FieldFormat> {
+        match code {
+            0 => Ok(FieldFormat::Text),
+            1 => Ok(FieldFormat::Binary),
+            _ => Err(PgError::Protocol(format!("Invalid field format: {}", code))),
+        }
+    }
+
+    pub(crate) fn code(&self) -> u16 {
+        match *self {
+            FieldFormat::Text => 0,
+            FieldFormat::Binary => 1,
+        }
+    }
+}
+
 mod erg {
     use std::str::from_utf8;
     use Result;
@@ -30,160 +48,305 @@ mod erg {
         }
     }
 
-    pub fn slice_to_u32(input: &[u8]) -> u32 {
+    pub fn slice_to_u32(input: &[u8]) -> Result<u32> {
         if input.len() != 4 {
-            panic!("Expected four bytes");
+            return Err(PgError::Protocol(format!("Expected four bytes, found {:?}", input)));
         }
         let mut value: u32 = 0;
         for x in input {
             value <<= 8;
             value += *x as u32;
         }
-        value
+        Ok(value)
     }
 
-    pub fn slice_to_u16(input: &[u8]) -> u16 {
+    pub fn slice_to_u16(input: &[u8]) -> Result<u16> {
         if input.len() != 2 {
-            panic!("Expected two bytes");
+            return Err(PgError::Protocol(format!("Expected two bytes, found {:?}", input)));
         }
         let mut value: u16 = 0;
         for x in input {
             value <<= 8;
             value += *x as u16;
         }
-        value
+        Ok(value)
     }
 
-    pub fn take_cstring_plus_fixed<'a>(input: &'a[u8], fixed: usize) -> Result<(&'a str, &'a[u8], &'a[u8])> {
+    /// Take a null terminated string and `fixed` bytes after it.  `what`
+    /// names the message and field, for errors.
+    pub fn take_cstring_plus_fixed<'a>(input: &'a[u8], fixed: usize, what: &str) -> Result<(&'a str, &'a[u8], &'a[u8])> {
         let strlen = find_first(input, b'\0');
         match strlen {
             Some(strlen) => {
-                let string = try!(from_utf8(&input[..strlen]));
+                if input.len() < strlen + 1 + fixed {
+                    return Err(PgError::Protocol(format!("Expected {} bytes after string, found {:?}", fixed, &input[strlen+1..])))
+                }
+                let string = try!(from_utf8(&input[..strlen]).map_err(|err| {
+                    PgError::Protocol(format!("Invalid UTF-8 in {}: {}", what, err))
+                }));
                 let fixed_data = &input[strlen+1..strlen+1+fixed];
                 let extra = &input[strlen+1+fixed..];
                 Ok((string, fixed_data, extra))
             },
-            None => Err(PgError::Error("null byte not found".to_string()))
+            None => Err(PgError::Protocol(format!("null byte not found in {:?}", input)))
         }
     }
-    pub fn take_sized_string<'a>(input: &'a[u8]) -> Result<(&'a str, &'a[u8])> {
-        let size = slice_to_u32(&input[..4]) as usize;
-        let data = try!(from_utf8(&input[4..4+size]));
-        let extra = &input[4+size..];
-        Ok((data, extra))
+
+    /// Take a value prefixed with its length, where a length of -1 means
+    /// NULL.  `what` names the message and field, for errors.
+    pub fn take_sized_string<'a>(input: &'a[u8], what: &str) -> Result<(Option<&'a str>, &'a[u8])> {
+        if input.len() < 4 {
+            return Err(PgError::Protocol(format!("Expected a value length, found {:?}", input)))
+        }
+        let size = try!(slice_to_u32(&input[..4]));
+        let input = &input[4..];
+        if size == 0xffffffff {
+            return Ok((None, input))
+        }
+        let size = size as usize;
+        if input.len() < size {
+            return Err(PgError::Protocol(format!("Expected a value of {} bytes, found {:?}", size, input)))
+        }
+        let data = try!(from_utf8(&input[..size]).map_err(|err| {
+            PgError::Protocol(format!("Invalid UTF-8 in {}: {}", what, err))
+        }));
+        Ok((Some(data), &input[size..]))
     }
 }
 
+/// The transaction status reported with each ReadyForQuery message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransactionStatus {
+    Idle,
+    InTransaction,
+    Failed,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct FieldDescription<'a> {
     field_name: &'a str,
+    table_oid: u32,
+    column: u16,
+    type_oid: u32,
+    type_size: i16,
+    type_modifier: i32,
     format: FieldFormat
 }
 
 impl <'a> FieldDescription<'a> {
+    /// A field which is not a table column, with the given type.
+    pub fn new(name: &'a str, type_oid: u32, format: FieldFormat) -> FieldDescription<'a> {
+        FieldDescription {
+            field_name: name,
+            table_oid: 0,
+            column: 0,
+            type_oid: type_oid,
+            type_size: -1,
+            type_modifier: -1,
+            format: format,
+        }
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.field_name
+    }
+
+    /// The table and column number the field comes from, if any.
+    pub fn column(&self) -> Option<(u32, u16)> {
+        if self.table_oid == 0 {
+            None
+        } else {
+            Some((self.table_oid, self.column))
+        }
+    }
+
+    pub fn type_oid(&self) -> u32 {
+        self.type_oid
+    }
+
+    pub fn format(&self) -> FieldFormat {
+        self.format
+    }
+
     fn take_field(input: &'a[u8]) -> Result<(&'a str, &'a[u8], &'a[u8])> {
-        take_cstring_plus_fixed(input, 18)
+        take_cstring_plus_fixed(input, 18, "row description field name")
     }
 
-    fn new(name: &'a str, fixed_data: &'a[u8]) -> Result<FieldDescription<'a>> {
-        let format = match slice_to_u16(&fixed_data[16..18]) {
-            0 => FieldFormat::Text,
-            1 => FieldFormat::Binary,
-            _ => return Err(PgError::Error("Invalid field format".to_string())),
-        };
+    fn from_fixed(name: &'a str, fixed_data: &'a[u8]) -> Result<FieldDescription<'a>> {
+        if fixed_data.len() != 18 {
+            return Err(PgError::Protocol(format!("Invalid field description: {:?}", fixed_data)))
+        }
+        let format = try!(FieldFormat::from_code(try!(slice_to_u16(&fixed_data[16..18]))));
         Ok(FieldDescription {
             field_name: name,
+            table_oid: try!(slice_to_u32(&fixed_data[..4])),
+            column: try!(slice_to_u16(&fixed_data[4..6])),
+            type_oid: try!(slice_to_u32(&fixed_data[6..10])),
+            type_size: try!(slice_to_u16(&fixed_data[10..12])) as i16,
+            type_modifier: try!(slice_to_u32(&fixed_data[12..16])) as i32,
             format: format,
         })
     }
+
+    fn extend_bytes(&self, body: &mut Vec<u8>) {
+        extend_string(body, self.field_name);
+        extend_u32(body, self.table_oid);
+        extend_u16(body, self.column);
+        extend_u32(body, self.type_oid);
+        extend_i16(body, self.type_size);
+        extend_i32(body, self.type_modifier);
+        extend_u16(body, self.format.code());
+    }
 }
 
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ServerMsg<'a> {
-    ErrorResponse(Vec<&'a str>),
+    ErrorResponse(Vec<(u8, &'a str)>),
     NoticeResponse(&'a[u8]),
     Auth(AuthMsg<'a>),
-    ReadyForQuery,
+    ReadyForQuery(TransactionStatus),
     CommandComplete(&'a str),
     ParamStatus(&'a str, &'a str),
-    BackendKeyData(u32, u32),
+    BackendKeyData(u32, &'a[u8]),
+    NotificationResponse(u32, &'a str, &'a str),
+    CopyInResponse(FieldFormat, Vec<FieldFormat>),
+    CopyOutResponse(FieldFormat, Vec<FieldFormat>),
+    CopyBothResponse(FieldFormat, Vec<FieldFormat>),
+    CopyData(&'a[u8]),
+    CopyDone,
     RowDescription(Vec<FieldDescription<'a>>),  // TBD
-    DataRow(Vec<&'a str>),  // TBD
+    /// Text values, where None is NULL.
+    DataRow(Vec<Option<&'a str>>),
+    EmptyQueryResponse,
+    ParseComplete,
+    BindComplete,
+    CloseComplete,
+    NoData,
+    PortalSuspended,
+    ParameterDescription(Vec<u32>),
+    FunctionCallResponse(Option<&'a[u8]>),
+    NegotiateProtocolVersion(u32, Vec<&'a str>),
     Unknown(&'a str, &'a[u8]),  // TBD
 }
 
 impl <'a> ServerMsg<'a> {
     pub fn from_slice(message: &[u8]) -> Result<ServerMsg> {
-        let length = 1 + slice_to_u32(&message[1 .. 5]) as usize;
+        if message.len() < 5 {
+            return Err(PgError::Protocol(format!("Message too short: {:?}", message)))
+        }
+        let length = 1 + try!(slice_to_u32(&message[1 .. 5])) as usize;
         if message.len() != length {
-            return Err(PgError::Error(format!("Wrong length for message.  Expected {}.  Found {}.", length, message.len())))
+            return Err(PgError::Protocol(format!("Wrong length for message.  Expected {}.  Found {}.", length, message.len())))
         }
-        let identifier = try!(from_utf8(&message[..1]));
+        let identifier = match from_utf8(&message[..1]) {
+            Ok(identifier) => identifier,
+            Err(_) => return Err(PgError::Protocol(format!("Invalid message identifier: {}", message[0]))),
+        };
         let (_, extra) = message.split_at(5);
         match identifier {
             "R" => {
                 AuthMsg::from_slice(extra).map(ServerMsg::Auth)
             },
             "S" => {  // Parameter Status
-                let mut param_iter = extra.split(|c| c == &0); // split on nulls
-                let name = try!(from_utf8(param_iter.next().unwrap()));
-                let value = try!(from_utf8(param_iter.next().unwrap()));
-                let nothing = param_iter.next().unwrap(); // The second null is the terminator
+                let (name, _, extra) = try!(take_cstring_plus_fixed(extra, 0, "parameter status name"));
+                let (value, _, nothing) = try!(take_cstring_plus_fixed(extra, 0, "parameter status value"));
                 if nothing != [] {
-                    Err(PgError::Error(format!("Extra value after param status: {:?}", nothing)))
+                    Err(PgError::Protocol(format!("Extra value after param status: {:?}", nothing)))
                 } else {
                     Ok(ServerMsg::ParamStatus(name, value))
                 }
             },
             "K" => {  // BackendKeyData
-                let pid = slice_to_u32(&extra[..4]);
-                let key = slice_to_u32(&extra[4..]);
+                // Protocol 3.0 sends a four byte key.  Protocol 3.2 allows
+                // keys of up to 256 bytes.
+                if extra.len() < 8 || extra.len() > 260 {
+                    return Err(PgError::Protocol(format!("Invalid backend key data: {:?}", extra)))
+                }
+                let pid = try!(slice_to_u32(&extra[..4]));
+                let key = &extra[4..];
                 Ok(ServerMsg::BackendKeyData(pid, key))
             },
+            "A" => {  // NotificationResponse
+                if extra.len() < 4 {
+                    return Err(PgError::Protocol(format!("Invalid notification: {:?}", extra)))
+                }
+                let pid = try!(slice_to_u32(&extra[..4]));
+                let (channel, _, extra) = try!(take_cstring_plus_fixed(&extra[4..], 0, "notification channel"));
+                let (payload, _, extra) = try!(take_cstring_plus_fixed(extra, 0, "notification payload"));
+                if extra == &b""[..] {
+                    Ok(ServerMsg::NotificationResponse(pid, channel, payload))
+                } else {
+                    Err(PgError::Protocol(format!("Unexpected extra data in notification: {:?}", extra)))
+                }
+            },
+            "G" => {  // CopyInResponse
+                let (format, columns) = try!(take_copy_formats(extra));
+                Ok(ServerMsg::CopyInResponse(format, columns))
+            },
+            "H" => {  // CopyOutResponse
+                let (format, columns) = try!(take_copy_formats(extra));
+                Ok(ServerMsg::CopyOutResponse(format, columns))
+            },
+            "d" => {  // CopyData
+                Ok(ServerMsg::CopyData(extra))
+            },
+            "c" => {  // CopyDone
+                Ok(ServerMsg::CopyDone)
+            },
             "T" => {  // Row Description
-                let field_count = slice_to_u16(&extra[..2]);
-                println!("Field count: {:?}", field_count);
+                if extra.len() < 2 {
+                    return Err(PgError::Protocol(format!("Invalid row description: {:?}", extra)))
+                }
+                let field_count = try!(slice_to_u16(&extra[..2]));
                 let mut extra = &extra[2..];
                 let mut fields = vec![];
 
                 for _ in 0..field_count {
-                    let (name, bytes, rem) = FieldDescription::take_field(extra).unwrap();
-                    let fd = FieldDescription::new(name, bytes).unwrap();
+                    let (name, bytes, rem) = try!(FieldDescription::take_field(extra));
+                    let fd = try!(FieldDescription::from_fixed(name, bytes));
                     fields.push(fd);
                     extra = rem;
                 }
                 if extra == &b""[..] {
                     Ok(ServerMsg::RowDescription(fields))
                 } else {
-                    Err(PgError::Error(format!("Unexpected extra data in row description: {:?} {:?}", fields, extra)))
+                    Err(PgError::Protocol(format!("Unexpected extra data in row description: {:?} {:?}", fields, extra)))
                 }
             },
             "D" => {  // Data Row
-                let field_count = slice_to_u16(&extra[..2]);
+                if extra.len() < 2 {
+                    return Err(PgError::Protocol(format!("Invalid data row: {:?}", extra)))
+                }
+                let field_count = try!(slice_to_u16(&extra[..2]));
                 let mut extra = &extra[2..];
                 let mut fields = vec![];
                 for _ in 0..field_count {
-                    let (string, more) = take_sized_string(extra).unwrap();
+                    let (string, more) = try!(take_sized_string(extra, "data row value"));
                     fields.push(string);
                     extra = more;
                 }
                 if extra == &b""[..] {
                     Ok(ServerMsg::DataRow(fields))
                 } else {
-                    Err(PgError::Error(format!("Unexpected extra data in data row: {:?}", extra)))
+                    Err(PgError::Protocol(format!("Unexpected extra data in data row: {:?}", extra)))
                 }
             },
             "C" => {  // Command Complete
-                let (command_tag, _, extra) = take_cstring_plus_fixed(extra, 0).unwrap();
+                let (command_tag, _, extra) = try!(take_cstring_plus_fixed(extra, 0, "command complete tag"));
                 if extra == &b""[..] {
                     Ok(ServerMsg::CommandComplete(command_tag))
                 } else {
-                    Err(PgError::Error(format!("Unexpected extra data in command complate: {:?}", extra)))
+                    Err(PgError::Protocol(format!("Unexpected extra data in command complate: {:?}", extra)))
                 }
             },
             "Z" => {  // ReadyForQuery
-                Ok(ServerMsg::ReadyForQuery)
+                match extra {
+                    b"I" => Ok(ServerMsg::ReadyForQuery(TransactionStatus::Idle)),
+                    b"T" => Ok(ServerMsg::ReadyForQuery(TransactionStatus::InTransaction)),
+                    b"E" => Ok(ServerMsg::ReadyForQuery(TransactionStatus::Failed)),
+                    _ => Err(PgError::Protocol(format!("Invalid transaction status: {:?}", extra))),
+                }
             },
             "N" => { // NoticeResponse
                 Ok(ServerMsg::NoticeResponse(extra))
@@ -192,27 +355,233 @@ impl <'a> ServerMsg<'a> {
                 let mut errors = Vec::new();
                 let mut remainder = extra;
                 if let None = remainder.get(0) {
-                    Err(PgError::Error(format!("No terminator in {:?}", extra)))
+                    Err(PgError::Protocol(format!("No terminator in {:?}", extra)))
                 } else {
                     while remainder.get(0) != Some(&0) {
-	                let (msg, _, end) = take_cstring_plus_fixed(&remainder[1..], 0).unwrap();
-                        errors.push(msg);
+                        let (msg, _, end) = try!(take_cstring_plus_fixed(&remainder[1..], 0, "error response field"));
+                        errors.push((remainder[0], msg));
                         remainder = end;
                         if let None = remainder.get(0) {
-                            return Err(PgError::Error(format!("No terminator in {:?}", extra)))
+                            return Err(PgError::Protocol(format!("No terminator in {:?}", extra)))
                         }
                     }
                     Ok(ServerMsg::ErrorResponse(errors))
                 }
             },
+            "W" => {  // CopyBothResponse
+                let (format, columns) = try!(take_copy_formats(extra));
+                Ok(ServerMsg::CopyBothResponse(format, columns))
+            },
+            "I" | "1" | "2" | "3" | "n" | "s" => {
+                if extra != &b""[..] {
+                    return Err(PgError::Protocol(format!("Unexpected data in {} message: {:?}", identifier, extra)))
+                }
+                Ok(match identifier {
+                    "I" => ServerMsg::EmptyQueryResponse,
+                    "1" => ServerMsg::ParseComplete,
+                    "2" => ServerMsg::BindComplete,
+                    "3" => ServerMsg::CloseComplete,
+                    "n" => ServerMsg::NoData,
+                    _ => ServerMsg::PortalSuspended,
+                })
+            },
+            "t" => {  // ParameterDescription
+                if extra.len() < 2 || extra.len() != 2 + 4 * try!(slice_to_u16(&extra[..2])) as usize {
+                    return Err(PgError::Protocol(format!("Invalid parameter description: {:?}", extra)))
+                }
+                let types = try!(extra[2..].chunks(4).map(slice_to_u32).collect());
+                Ok(ServerMsg::ParameterDescription(types))
+            },
+            "V" => {  // FunctionCallResponse
+                if extra.len() < 4 {
+                    return Err(PgError::Protocol(format!("Invalid function call response: {:?}", extra)))
+                }
+                let size = try!(slice_to_u32(&extra[..4]));
+                if size == 0xffffffff && extra.len() == 4 {
+                    Ok(ServerMsg::FunctionCallResponse(None))
+                } else if size as usize == extra.len() - 4 {
+                    Ok(ServerMsg::FunctionCallResponse(Some(&extra[4..])))
+                } else {
+                    Err(PgError::Protocol(format!("Invalid function call response: {:?}", extra)))
+                }
+            },
+            "v" => {  // NegotiateProtocolVersion
+                if extra.len() < 8 {
+                    return Err(PgError::Protocol(format!("Invalid protocol negotiation: {:?}", extra)))
+                }
+                let minor_version = try!(slice_to_u32(&extra[..4]));
+                let count = try!(slice_to_u32(&extra[4..8]));
+                let mut extra = &extra[8..];
+                let mut options = vec![];
+                for _ in 0..count {
+                    let (option, _, rest) = try!(take_cstring_plus_fixed(extra, 0, "protocol negotiation option"));
+                    options.push(option);
+                    extra = rest;
+                }
+                if extra == &b""[..] {
+                    Ok(ServerMsg::NegotiateProtocolVersion(minor_version, options))
+                } else {
+                    Err(PgError::Protocol(format!("Unexpected extra data in protocol negotiation: {:?}", extra)))
+                }
+            },
             _ => {
                 Ok(ServerMsg::Unknown(identifier, extra))
             },
         }
     }
+
+    /// Encode the message as the server would send it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![];
+        let id = match *self {
+            ServerMsg::ErrorResponse(ref fields) => {
+                for &(field, value) in fields {
+                    body.push(field);
+                    extend_string(&mut body, value);
+                }
+                body.push(0);
+                b'E'
+            },
+            ServerMsg::NoticeResponse(notice) => {
+                body.extend(notice);
+                b'N'
+            },
+            ServerMsg::Auth(ref auth) => {
+                auth.extend_bytes(&mut body);
+                b'R'
+            },
+            ServerMsg::ReadyForQuery(status) => {
+                body.push(match status {
+                    TransactionStatus::Idle => b'I',
+                    TransactionStatus::InTransaction => b'T',
+                    TransactionStatus::Failed => b'E',
+                });
+                b'Z'
+            },
+            ServerMsg::CommandComplete(tag) => {
+                extend_string(&mut body, tag);
+                b'C'
+            },
+            ServerMsg::ParamStatus(name, value) => {
+                extend_string(&mut body, name);
+                extend_string(&mut body, value);
+                b'S'
+            },
+            ServerMsg::BackendKeyData(pid, key) => {
+                extend_u32(&mut body, pid);
+                body.extend(key);
+                b'K'
+            },
+            ServerMsg::NotificationResponse(pid, channel, payload) => {
+                extend_u32(&mut body, pid);
+                extend_string(&mut body, channel);
+                extend_string(&mut body, payload);
+                b'A'
+            },
+            ServerMsg::CopyInResponse(format, ref columns) => {
+                extend_copy_formats(&mut body, format, columns);
+                b'G'
+            },
+            ServerMsg::CopyOutResponse(format, ref columns) => {
+                extend_copy_formats(&mut body, format, columns);
+                b'H'
+            },
+            ServerMsg::CopyBothResponse(format, ref columns) => {
+                extend_copy_formats(&mut body, format, columns);
+                b'W'
+            },
+            ServerMsg::CopyData(data) => {
+                body.extend(data);
+                b'd'
+            },
+            ServerMsg::CopyDone => b'c',
+            ServerMsg::RowDescription(ref fields) => {
+                extend_u16(&mut body, fields.len() as u16);
+                for field in fields {
+                    field.extend_bytes(&mut body);
+                }
+                b'T'
+            },
+            ServerMsg::DataRow(ref values) => {
+                extend_u16(&mut body, values.len() as u16);
+                for value in values {
+                    match *value {
+                        Some(value) => {
+                            extend_u32(&mut body, value.len() as u32);
+                            body.extend(value.as_bytes());
+                        },
+                        None => extend_i32(&mut body, -1),
+                    }
+                }
+                b'D'
+            },
+            ServerMsg::EmptyQueryResponse => b'I',
+            ServerMsg::ParseComplete => b'1',
+            ServerMsg::BindComplete => b'2',
+            ServerMsg::CloseComplete => b'3',
+            ServerMsg::NoData => b'n',
+            ServerMsg::PortalSuspended => b's',
+            ServerMsg::ParameterDescription(ref types) => {
+                extend_u16(&mut body, types.len() as u16);
+                for &oid in types {
+                    extend_u32(&mut body, oid);
+                }
+                b't'
+            },
+            ServerMsg::FunctionCallResponse(result) => {
+                match result {
+                    Some(value) => {
+                        extend_u32(&mut body, value.len() as u32);
+                        body.extend(value);
+                    },
+                    None => extend_i32(&mut body, -1),
+                }
+                b'V'
+            },
+            ServerMsg::NegotiateProtocolVersion(minor_version, ref options) => {
+                extend_u32(&mut body, minor_version);
+                extend_u32(&mut body, options.len() as u32);
+                for option in options {
+                    extend_string(&mut body, option);
+                }
+                b'v'
+            },
+            ServerMsg::Unknown(identifier, data) => {
+                body.extend(data);
+                identifier.as_bytes().get(0).cloned().unwrap_or(0)
+            },
+        };
+        frame(id, &body)
+    }
+}
+
+fn extend_copy_formats(body: &mut Vec<u8>, format: FieldFormat, columns: &[FieldFormat]) {
+    body.push(format.code() as u8);
+    extend_u16(body, columns.len() as u16);
+    for column in columns {
+        extend_u16(body, column.code());
+    }
 }
 
 
+/// Parse the overall and per-column formats of a COPY response.
+fn take_copy_formats(extra: &[u8]) -> Result<(FieldFormat, Vec<FieldFormat>)> {
+    if extra.len() < 3 {
+        return Err(PgError::Protocol(format!("Invalid copy response: {:?}", extra)))
+    }
+    let format = try!(FieldFormat::from_code(extra[0] as u16));
+    let column_count = try!(slice_to_u16(&extra[1..3])) as usize;
+    let extra = &extra[3..];
+    if extra.len() != column_count * 2 {
+        return Err(PgError::Protocol(format!("Wrong number of column formats in copy response: {:?}", extra)))
+    }
+    let mut columns = Vec::with_capacity(column_count);
+    for code in extra.chunks(2) {
+        columns.push(try!(FieldFormat::from_code(try!(slice_to_u16(code)))));
+    }
+    Ok((format, columns))
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum AuthMsg<'a> {
     Ok,
@@ -223,43 +592,101 @@ pub enum AuthMsg<'a> {
     Gss,
     Sspi,
     GssContinue(&'a[u8]),
-    Unknown,
+    Sasl(Vec<&'a str>),
+    SaslContinue(&'a[u8]),
+    SaslFinal(&'a[u8]),
+    Unknown(u32, &'a[u8]),
 }
 
 
 impl <'a> AuthMsg<'a> {
     pub fn from_slice(extra: &'a [u8]) -> Result<AuthMsg> {
-        match slice_to_u32(&extra[0..4]) {
+        if extra.len() < 4 {
+            return Err(PgError::Protocol(format!("Invalid authentication request: {:?}", extra)))
+        }
+        let data = &extra[4..];
+        match try!(slice_to_u32(&extra[0..4])) {
             0 => Ok(AuthMsg::Ok),
             2 => Ok(AuthMsg::Kerberos),
             3 => Ok(AuthMsg::Cleartext),
             5 => {
-                let salt = &extra[4..8];
-                Ok(AuthMsg::Md5(salt))
+                if data.len() != 4 {
+                    return Err(PgError::Protocol(format!("Invalid MD5 salt: {:?}", data)))
+                }
+                Ok(AuthMsg::Md5(data))
             },
             6 => Ok(AuthMsg::ScmCredential),
             7 => Ok(AuthMsg::Gss),
-            8 => {
-                let gss_data = &extra[4..8];
-                Ok(AuthMsg::GssContinue(gss_data))
-            },
+            8 => Ok(AuthMsg::GssContinue(data)),
             9 => Ok(AuthMsg::Sspi),
-            1|4|10...255 => Ok(AuthMsg::Unknown),
-            _ => Err(PgError::Other)
+            10 => {  // A list of mechanisms, ending with an empty one
+                let mut mechanisms = vec![];
+                let mut data = data;
+                loop {
+                    let (mechanism, _, rest) = try!(take_cstring_plus_fixed(data, 0, "SASL mechanism"));
+                    data = rest;
+                    if mechanism.is_empty() {
+                        break;
+                    }
+                    mechanisms.push(mechanism);
+                }
+                if data == &b""[..] {
+                    Ok(AuthMsg::Sasl(mechanisms))
+                } else {
+                    Err(PgError::Protocol(format!("Unexpected extra data in SASL mechanisms: {:?}", data)))
+                }
+            },
+            11 => Ok(AuthMsg::SaslContinue(data)),
+            12 => Ok(AuthMsg::SaslFinal(data)),
+            code => Ok(AuthMsg::Unknown(code, data)),
         }
     }
+
+    fn extend_bytes(&self, body: &mut Vec<u8>) {
+        let (code, data) = match *self {
+            AuthMsg::Ok => (0, &b""[..]),
+            AuthMsg::Kerberos => (2, &b""[..]),
+            AuthMsg::Cleartext => (3, &b""[..]),
+            AuthMsg::Md5(salt) => (5, salt),
+            AuthMsg::ScmCredential => (6, &b""[..]),
+            AuthMsg::Gss => (7, &b""[..]),
+            AuthMsg::GssContinue(data) => (8, data),
+            AuthMsg::Sspi => (9, &b""[..]),
+            AuthMsg::Sasl(ref mechanisms) => {
+                extend_u32(body, 10);
+                for mechanism in mechanisms {
+                    extend_string(body, mechanism);
+                }
+                body.push(0);
+                return;
+            },
+            AuthMsg::SaslContinue(data) => (11, data),
+            AuthMsg::SaslFinal(data) => (12, data),
+            AuthMsg::Unknown(code, data) => (code, data),
+        };
+        extend_u32(body, code);
+        body.extend(data);
+    }
 }
 
-pub fn take_msg(input: &[u8]) -> Result<(&[u8], &[u8])> {
+/// The length of the first message in `input`, or None if its header has
+/// not all arrived.
+pub fn message_length(input: &[u8]) -> Result<Option<usize>> {
     if input.len() < 5 {
-        Err(PgError::Error(format!("Input too short: {:?}", input)))
-    } else {
-        let length = 1 + slice_to_u32(&input[1 .. 5]) as usize;
-        if input.len() < length {
-            Err(PgError::Error(format!("Message too short: {:?}", input)))
-        } else {
-            Ok(input.split_at(length))
-        }
+        return Ok(None)
+    }
+    let length = try!(slice_to_u32(&input[1 .. 5]));
+    if length < 4 {
+        return Err(PgError::Protocol(format!("Invalid message length: {}", length)))
+    }
+    Ok(Some(1 + length as usize))
+}
+
+pub fn take_msg(input: &[u8]) -> Result<(&[u8], &[u8])> {
+    match try!(message_length(input)) {
+        Some(length) if input.len() >= length => Ok(input.split_at(length)),
+        Some(_) => Err(PgError::Protocol(format!("Message too short: {:?}", input))),
+        None => Err(PgError::Protocol(format!("Input too short: {:?}", input))),
     }
 }
 
@@ -342,20 +769,18 @@ mod tests {
 
         let (next, buffer) = take_msg(buffer).unwrap();
         let msg = ServerMsg::from_slice(next).unwrap();
-        assert!(
-            match msg {
-                ServerMsg::BackendKeyData(..) => true,
-                _ => false,
-            }
-        );
+        assert_eq!(msg, ServerMsg::BackendKeyData(0x17bb, b"\x15b\xfb1"));
         assert_eq!(buffer.len(), 6);
 
         let (next, buffer) = take_msg(buffer).unwrap();
         let msg = ServerMsg::from_slice(next).unwrap();
-        assert_eq!(msg, ServerMsg::ReadyForQuery);
+        assert_eq!(msg, ServerMsg::ReadyForQuery(TransactionStatus::Idle));
         assert_eq!(buffer.len(), 0);
 
-        assert!(take_msg(buffer).is_err())
+        match take_msg(buffer) {
+            Err(PgError::Protocol(_)) => {},
+            other => panic!("Expected a protocol error, got {:?}", other),
+        }
     }
 
     #[test]
@@ -367,17 +792,14 @@ mod tests {
         assert_eq!(
             msg,
             ServerMsg::RowDescription(
-                vec![FieldDescription {
-                    field_name: "version",
-                    format: FieldFormat::Text,
-                }]
+                vec![FieldDescription::new("version", 25, FieldFormat::Text)]
             )
         );
         assert_eq!(buffer.len(), 116);
 
         let (next, buffer) = take_msg(buffer).unwrap();
         let msg = ServerMsg::from_slice(next).unwrap();
-        assert_eq!(msg, ServerMsg::DataRow(vec!["PostgreSQL 9.6.1 on x86_64-pc-linux-gnu, compiled by gcc (GCC) 6.2.1 20160830, 64-bit"]));
+        assert_eq!(msg, ServerMsg::DataRow(vec![Some("PostgreSQL 9.6.1 on x86_64-pc-linux-gnu, compiled by gcc (GCC) 6.2.1 20160830, 64-bit")]));
         assert_eq!(buffer.len(), 20);
 
         let (next, buffer) = take_msg(buffer).unwrap();
@@ -387,7 +809,235 @@ mod tests {
 
         let (next, buffer) = take_msg(buffer).unwrap();
         let msg = ServerMsg::from_slice(next).unwrap();
-        assert_eq!(msg, ServerMsg::ReadyForQuery);
+        assert_eq!(msg, ServerMsg::ReadyForQuery(TransactionStatus::Idle));
         assert_eq!(buffer.len(), 0);
     }
+
+    #[test]
+    fn test_backend_key_data_with_long_key() {
+        let mut buffer = b"K\x00\x00\x00\x28\x00\x00\x04\xd2".to_vec();
+        buffer.extend(&[0xab; 32]);
+        let msg = ServerMsg::from_slice(&buffer).unwrap();
+        assert_eq!(msg, ServerMsg::BackendKeyData(1234, &[0xab; 32]));
+    }
+
+    #[test]
+    fn test_backend_key_data_too_short() {
+        let buffer = b"K\x00\x00\x00\x0a\x00\x00\x04\xd2\x01\x02";
+        assert!(ServerMsg::from_slice(buffer).is_err());
+    }
+
+    #[test]
+    fn test_notification_response() {
+        let buffer = b"A\x00\x00\x00\x14\x00\x00\x04\xd2jobs\x00run 42\x00";
+        let (next, buffer) = take_msg(buffer).unwrap();
+        let msg = ServerMsg::from_slice(next).unwrap();
+        assert_eq!(msg, ServerMsg::NotificationResponse(1234, "jobs", "run 42"));
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_copy_in_response() {
+        let buffer = b"G\x00\x00\x00\x0d\x00\x00\x03\x00\x00\x00\x00\x00\x00";
+        let msg = ServerMsg::from_slice(buffer).unwrap();
+        assert_eq!(
+            msg,
+            ServerMsg::CopyInResponse(FieldFormat::Text, vec![FieldFormat::Text; 3])
+        );
+    }
+
+    #[test]
+    fn test_copy_in_response_wrong_column_count() {
+        let buffer = b"G\x00\x00\x00\x0b\x01\x00\x03\x00\x01\x00\x01";
+        assert!(ServerMsg::from_slice(buffer).is_err());
+    }
+
+    #[test]
+    fn test_copy_out_response() {
+        let buffer = b"H\x00\x00\x00\x0b\x01\x00\x02\x00\x01\x00\x01d\x00\x00\x00\x08a|b\nc\x00\x00\x00\x04";
+        let (next, buffer) = take_msg(buffer).unwrap();
+        let msg = ServerMsg::from_slice(next).unwrap();
+        assert_eq!(
+            msg,
+            ServerMsg::CopyOutResponse(FieldFormat::Binary, vec![FieldFormat::Binary; 2])
+        );
+
+        let (next, buffer) = take_msg(buffer).unwrap();
+        let msg = ServerMsg::from_slice(next).unwrap();
+        assert_eq!(msg, ServerMsg::CopyData(b"a|b\n"));
+
+        let (next, buffer) = take_msg(buffer).unwrap();
+        let msg = ServerMsg::from_slice(next).unwrap();
+        assert_eq!(msg, ServerMsg::CopyDone);
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_ready_for_query_status() {
+        let msg = ServerMsg::from_slice(b"Z\x00\x00\x00\x05T").unwrap();
+        assert_eq!(msg, ServerMsg::ReadyForQuery(TransactionStatus::InTransaction));
+        let msg = ServerMsg::from_slice(b"Z\x00\x00\x00\x05E").unwrap();
+        assert_eq!(msg, ServerMsg::ReadyForQuery(TransactionStatus::Failed));
+        assert!(ServerMsg::from_slice(b"Z\x00\x00\x00\x05X").is_err());
+        assert!(ServerMsg::from_slice(b"Z\x00\x00\x00\x04").is_err());
+    }
+
+    #[test]
+    fn test_error_response() {
+        let buffer = b"E\x00\x00\x00\x2fSERROR\x00VERROR\x00C40001\x00Mcould not serialize\x00\x00";
+        let msg = ServerMsg::from_slice(buffer).unwrap();
+        assert_eq!(
+            msg,
+            ServerMsg::ErrorResponse(vec![
+                (b'S', "ERROR"),
+                (b'V', "ERROR"),
+                (b'C', "40001"),
+                (b'M', "could not serialize"),
+            ])
+        );
+    }
+
+    /// One of each kind of message.
+    fn samples() -> Vec<ServerMsg<'static>> {
+        vec![
+            ServerMsg::ErrorResponse(vec![(b'S', "ERROR"), (b'C', "42601"), (b'M', "syntax error")]),
+            ServerMsg::NoticeResponse(b"SNOTICE\0Mhello\0\0"),
+            ServerMsg::Auth(AuthMsg::Ok),
+            ServerMsg::Auth(AuthMsg::Cleartext),
+            ServerMsg::Auth(AuthMsg::Md5(b"salt")),
+            ServerMsg::Auth(AuthMsg::GssContinue(b"token")),
+            ServerMsg::Auth(AuthMsg::Sasl(vec!["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256"])),
+            ServerMsg::Auth(AuthMsg::SaslContinue(b"r=abc,s=c2FsdA==,i=4096")),
+            ServerMsg::Auth(AuthMsg::SaslFinal(b"v=c2lnbmF0dXJl")),
+            ServerMsg::Auth(AuthMsg::Unknown(13, b"")),
+            ServerMsg::ReadyForQuery(TransactionStatus::Failed),
+            ServerMsg::CommandComplete("INSERT 0 1"),
+            ServerMsg::ParamStatus("TimeZone", "UTC"),
+            ServerMsg::BackendKeyData(1234, &[7; 32]),
+            ServerMsg::NotificationResponse(1234, "jobs", "run 42"),
+            ServerMsg::CopyInResponse(FieldFormat::Text, vec![FieldFormat::Text; 2]),
+            ServerMsg::CopyOutResponse(FieldFormat::Binary, vec![FieldFormat::Binary]),
+            ServerMsg::CopyBothResponse(FieldFormat::Binary, vec![]),
+            ServerMsg::CopyData(b"a|b\n"),
+            ServerMsg::CopyDone,
+            ServerMsg::RowDescription(vec![
+                FieldDescription::new("id", 23, FieldFormat::Binary),
+                FieldDescription {
+                    field_name: "name",
+                    table_oid: 16384,
+                    column: 2,
+                    type_oid: 1043,
+                    type_size: -1,
+                    type_modifier: 68,
+                    format: FieldFormat::Text,
+                },
+            ]),
+            ServerMsg::DataRow(vec![Some("1"), None, Some("ann")]),
+            ServerMsg::EmptyQueryResponse,
+            ServerMsg::ParseComplete,
+            ServerMsg::BindComplete,
+            ServerMsg::CloseComplete,
+            ServerMsg::NoData,
+            ServerMsg::PortalSuspended,
+            ServerMsg::ParameterDescription(vec![23, 25]),
+            ServerMsg::FunctionCallResponse(Some(b"result")),
+            ServerMsg::FunctionCallResponse(None),
+            ServerMsg::NegotiateProtocolVersion(0, vec!["_pq_.compression"]),
+            ServerMsg::Unknown("?", b"data"),
+        ]
+    }
+
+    #[test]
+    fn test_server_round_trip() {
+        for msg in samples() {
+            let bytes = msg.to_bytes();
+            let (next, rest) = take_msg(&bytes).unwrap();
+            assert_eq!(rest.len(), 0);
+            assert_eq!(ServerMsg::from_slice(next).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_server_encoding_matches_captured_bytes() {
+        let buffer = b"T\x00\x00\x00 \x00\x01version\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x19\xff\xff\xff\xff\xff\xff\x00\x00";
+        let msg = ServerMsg::from_slice(buffer).unwrap();
+        assert_eq!(msg.to_bytes(), buffer.to_vec());
+        let buffer = b"E\x00\x00\x00\x2fSERROR\x00VERROR\x00C40001\x00Mcould not serialize\x00\x00";
+        let msg = ServerMsg::from_slice(buffer).unwrap();
+        assert_eq!(msg.to_bytes(), buffer.to_vec());
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        let invalid: Vec<&[u8]> = vec![
+            b"",
+            b"Z\0\0",
+            b"Z\0\0\0\x05",
+            b"Z\0\0\0\x06I",
+            b"S\0\0\0\x09name\0",
+            b"T\0\0\0\x04",
+            b"T\0\0\0\x0c\0\x01name\0\0\0\0",
+            b"D\0\0\0\x0a\0\x01\0\0\0\x09ab",
+            b"C\0\0\0\x08SELE",
+            b"E\0\0\0\x08SERR",
+            b"t\0\0\0\x07\0\x01\0",
+            b"R\0\0\0\x06\0\0",
+            b"\xff\0\0\0\x04",
+        ];
+        for bytes in invalid {
+            match ServerMsg::from_slice(bytes) {
+                Err(PgError::Protocol(_)) => {},
+                other => panic!("Expected a protocol error for {:?}, got {:?}", bytes, other),
+            }
+        }
+        match take_msg(b"Z\0\0\0\x02") {
+            Err(PgError::Protocol(_)) => {},
+            other => panic!("Expected a protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let messages: &[&[u8]] = &[
+            &[b'D', 0, 0, 0, 11, 0, 1, 0, 0, 0, 1, 0xff],
+            &[b'C', 0, 0, 0, 6, 0xff, 0],
+            &[b'S', 0, 0, 0, 8, b'a', 0, 0xff, 0],
+        ];
+        for bytes in messages {
+            match ServerMsg::from_slice(bytes) {
+                Err(PgError::Protocol(ref message)) => assert!(message.starts_with("Invalid UTF-8 in "), "{}", message),
+                other => panic!("Expected a protocol error for {:?}, got {:?}", bytes, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_and_corrupted_messages_do_not_panic() {
+        for msg in samples() {
+            let bytes = msg.to_bytes();
+            for end in 0..bytes.len() {
+                match take_msg(&bytes[..end]) {
+                    Err(PgError::Protocol(_)) => {},
+                    other => panic!("Expected a protocol error for {:?}, got {:?}", &bytes[..end], other),
+                }
+                match ServerMsg::from_slice(&bytes[..end]) {
+                    Err(PgError::Protocol(_)) => {},
+                    other => panic!("Expected a protocol error for {:?}, got {:?}", &bytes[..end], other),
+                }
+            }
+            for position in 0..bytes.len() {
+                for &value in &[0, 1, 0x7f, 0x80, 0xff] {
+                    let mut corrupted = bytes.clone();
+                    corrupted[position] = value;
+                    if let Ok((next, _)) = take_msg(&corrupted) {
+                        let _ = ServerMsg::from_slice(next);
+                    }
+                    match ServerMsg::from_slice(&corrupted) {
+                        Ok(_) | Err(PgError::Protocol(_)) => {},
+                        Err(err) => panic!("Expected a protocol error for {:?}, got {:?}", corrupted, err),
+                    }
+                }
+            }
+        }
+    }
 }
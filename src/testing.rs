@@ -0,0 +1,889 @@
+//! A scriptable fake backend, for testing code that talks to Postgres
+//! without a live server.
+//!
+//! A `MockBackend` describes how the server should authenticate clients and
+//! answer their queries.  Starting it binds a port on localhost and serves
+//! each client with a `pg::server::Server` on its own thread, recording every
+//! message the clients send.  LISTEN and NOTIFY work between its clients.
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use Result;
+use config::Config;
+use error::{DbError, PgError};
+use message::{take_frontend_msg, FrontendMsg, PasswordMessage, SaslInitialResponse, Target};
+use secret::Secret;
+use server::{copy_rows, AuthMethod, Authenticator, Column, Description, QueryHandler, QueryResult, Server, Session};
+use servermsg::{take_msg, FieldFormat, ServerMsg};
+use transport::Transport;
+
+/// How the backend authenticates clients.  Any user name is accepted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Auth {
+    Trust,
+    Cleartext(String),
+    Md5(String),
+    ScramSha256(String),
+}
+
+/// The backend's answer to a query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Response {
+    /// Rows of text values, under the named columns.  `None` is NULL.
+    Rows(Vec<String>, Vec<Vec<Option<String>>>),
+    /// A command which returns no rows, with its command tag.
+    Command(String),
+    /// An error with its SQLSTATE code and message.
+    Error(String, String),
+    /// Accept data in the given format for `COPY ... FROM STDIN`.
+    CopyIn(FieldFormat),
+    /// Accept text for `COPY ... FROM STDIN`, then refuse it with an error.
+    CopyInError(String, String),
+    /// Send each chunk as a CopyData message for `COPY ... TO STDOUT`.
+    CopyOut(FieldFormat, Vec<Vec<u8>>),
+    /// Answer after a delay, or with an error if the client cancels the
+    /// query first.
+    Delay(Duration, Box<Response>),
+    /// Close the connection without answering.
+    Disconnect,
+}
+
+impl Response {
+    pub fn rows(columns: &[&str], rows: &[&[&str]]) -> Response {
+        Response::Rows(
+            columns.iter().map(|column| column.to_string()).collect(),
+            rows.iter().map(|row| row.iter().map(|value| Some(value.to_string())).collect()).collect(),
+        )
+    }
+
+    /// Rows which may contain NULLs.
+    pub fn nullable_rows(columns: &[&str], rows: &[&[Option<&str>]]) -> Response {
+        Response::Rows(
+            columns.iter().map(|column| column.to_string()).collect(),
+            rows.iter().map(|row| row.iter().map(|value| value.map(|value| value.to_string())).collect()).collect(),
+        )
+    }
+
+    pub fn command(tag: &str) -> Response {
+        Response::Command(tag.to_string())
+    }
+
+    pub fn error(code: &str, message: &str) -> Response {
+        Response::Error(code.to_string(), message.to_string())
+    }
+
+    pub fn delay(duration: Duration, response: Response) -> Response {
+        Response::Delay(duration, Box::new(response))
+    }
+}
+
+/// A message received from a client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Received {
+    Startup { user: String, database: Option<String>, params: Vec<(String, String)> },
+    SslRequest,
+    GssEncRequest,
+    CancelRequest { pid: u32, key: Vec<u8> },
+    Password(String),
+    SaslInitialResponse { mechanism: String, data: Vec<u8> },
+    SaslResponse(Vec<u8>),
+    Query(String),
+    Parse { name: String, query: String, param_types: Vec<u32> },
+    Bind { portal: String, statement: String, params: Vec<Option<Vec<u8>>> },
+    Describe { target: Target, name: String },
+    Execute { portal: String, max_rows: u32 },
+    Close { target: Target, name: String },
+    Sync,
+    Flush,
+    CopyData(Vec<u8>),
+    CopyDone,
+    CopyFail(String),
+    Terminate,
+    Other(u8, Vec<u8>),
+}
+
+/// The script for a fake backend.
+#[derive(Clone, Debug)]
+pub struct MockBackend {
+    auth: Auth,
+    parameters: Vec<(String, String)>,
+    responses: Vec<(String, Response)>,
+    default_response: Option<Response>,
+}
+
+impl MockBackend {
+    /// A backend which trusts every client, and answers transaction control
+    /// statements such as BEGIN, COMMIT and SAVEPOINT, and LISTEN and NOTIFY.  Any
+    /// other query is an error until a response is added for it.
+    pub fn new() -> MockBackend {
+        MockBackend {
+            auth: Auth::Trust,
+            parameters: vec![],
+            responses: vec![],
+            default_response: None,
+        }
+    }
+
+    pub fn auth(mut self, auth: Auth) -> MockBackend {
+        self.auth = auth;
+        self
+    }
+
+    /// Report a server parameter to each client during startup, replacing
+    /// any default of the same name.
+    pub fn parameter(mut self, name: &str, value: &str) -> MockBackend {
+        self.parameters.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Answer `sql` with `response`.  Queries are matched ignoring
+    /// surrounding whitespace and a trailing semicolon.  A query holding
+    /// several statements is answered statement by statement, unless it
+    /// has a response of its own.
+    pub fn on_query(mut self, sql: &str, response: Response) -> MockBackend {
+        self.responses.push((normalize(sql).to_string(), response));
+        self
+    }
+
+    /// Answer queries with no response of their own with `response`.
+    pub fn default_response(mut self, response: Response) -> MockBackend {
+        self.default_response = Some(response);
+        self
+    }
+
+    /// Listen on a free port on localhost.
+    pub fn start(self) -> io::Result<MockServer> {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let addr = try!(listener.local_addr());
+        let shared = Arc::new(Shared {
+            received: Mutex::new(vec![]),
+            sessions: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+            clients: Mutex::new(HashMap::new()),
+            listening: Mutex::new(vec![]),
+        });
+        let scram = self.auth.method("") == AuthMethod::ScramSha256;
+        let handler = Handler { script: self.clone(), shared: shared.clone() };
+        let mut server = Server::new(Arc::new(handler)).authenticator(Arc::new(self.auth.clone()));
+        for &(ref name, ref value) in &self.parameters {
+            server = server.parameter(name, value);
+        }
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accepting.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let shared = accepting.clone();
+                    let server = server.clone();
+                    thread::spawn(move || {
+                        let mut stream = match Recorder::new(stream, shared.clone(), scram) {
+                            Ok(stream) => stream,
+                            Err(_) => return,
+                        };
+                        if let Ok(Some(session)) = server.handshake(&mut stream) {
+                            shared.sessions.fetch_add(1, Ordering::SeqCst);
+                            let _ = server.serve_session(stream, &session);
+                            lock(&shared.clients).remove(&session.pid());
+                            lock(&shared.listening).retain(|&(pid, _)| pid != session.pid());
+                        }
+                    });
+                }
+            }
+        });
+        Ok(MockServer { addr: addr, shared: shared })
+    }
+
+    fn scripted(&self, sql: &str) -> Option<&Response> {
+        let sql = normalize(sql);
+        self.responses.iter().find(|&&(ref query, _)| query == sql).map(|&(_, ref response)| response)
+    }
+
+    fn response(&self, sql: &str) -> Response {
+        if let Some(response) = self.scripted(sql) {
+            return response.clone();
+        }
+        let sql = normalize(sql);
+        let keyword = sql.split_whitespace().next().unwrap_or("").to_uppercase();
+        match &keyword[..] {
+            "BEGIN" | "START" => Response::command("BEGIN"),
+            "COMMIT" | "END" => Response::command("COMMIT"),
+            "ROLLBACK" | "ABORT" => Response::command("ROLLBACK"),
+            "SAVEPOINT" | "RELEASE" => Response::command(&keyword),
+            "LISTEN" | "UNLISTEN" | "NOTIFY" => Response::command(&keyword),
+            _ => match self.default_response {
+                Some(ref response) => response.clone(),
+                None => Response::error("XX000", &format!("No mock response for query: {}", sql)),
+            },
+        }
+    }
+}
+
+impl Default for MockBackend {
+    fn default() -> MockBackend {
+        MockBackend::new()
+    }
+}
+
+fn normalize(sql: &str) -> &str {
+    sql.trim().trim_end_matches(';').trim_end()
+}
+
+/// The statements in a simple query, split at semicolons outside quotes.
+fn statements(sql: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    for (index, c) in sql.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => {
+                statements.push(&sql[start..index]);
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    statements.push(&sql[start..]);
+    statements.into_iter().filter(|statement| !statement.trim().is_empty()).collect()
+}
+
+/// The channel of `LISTEN channel` or `UNLISTEN channel`.
+fn channel(sql: &str) -> Option<String> {
+    normalize(sql).split_whitespace().nth(1).map(|channel| channel.trim_matches('"').to_string())
+}
+
+/// The channel and payload of `NOTIFY channel, 'payload'`.
+fn notification(sql: &str) -> Option<(String, String)> {
+    let args = match normalize(sql).splitn(2, char::is_whitespace).nth(1) {
+        Some(args) => args,
+        None => return None,
+    };
+    let mut args = args.splitn(2, ',');
+    let channel = args.next().unwrap_or("").trim().trim_matches('"').to_string();
+    let payload = args.next().map(|payload| payload.trim().trim_matches('\'').replace("''", "'"));
+    Some((channel, payload.unwrap_or_default()))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Shared {
+    received: Mutex<Vec<Received>>,
+    sessions: AtomicUsize,
+    stopped: AtomicBool,
+    /// The stream of each client which has been sent its process ID, so
+    /// others' notifications can be sent to it.
+    clients: Mutex<HashMap<u32, Arc<Mutex<TcpStream>>>>,
+    /// The process ID and channel of each LISTEN.
+    listening: Mutex<Vec<(u32, String)>>,
+}
+
+impl Shared {
+    fn record(&self, msg: Received) {
+        lock(&self.received).push(msg);
+    }
+
+    /// The number of cancel requests received for the process.
+    fn cancels(&self, pid: u32) -> usize {
+        lock(&self.received).iter().filter(|msg| match **msg {
+            Received::CancelRequest { pid: target, .. } => target == pid,
+            _ => false,
+        }).count()
+    }
+
+    fn notify(&self, pid: u32, channel: &str, payload: &str) {
+        let bytes = ServerMsg::NotificationResponse(pid, channel, payload).to_bytes();
+        let clients = lock(&self.clients);
+        for &(listener, ref listened) in lock(&self.listening).iter() {
+            if listened == channel {
+                if let Some(writer) = clients.get(&listener) {
+                    let _ = lock(writer).write_all(&bytes);
+                }
+            }
+        }
+    }
+}
+
+/// A running fake backend.  It stops accepting connections when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl MockServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Settings for connecting to this server as `user`.
+    pub fn config(&self, user: &str) -> Config {
+        Config::new(user).host("127.0.0.1").port(self.port())
+    }
+
+    /// Every message received so far, from all clients, in order.
+    pub fn received(&self) -> Vec<Received> {
+        lock(&self.shared.received).clone()
+    }
+
+    /// The queries received so far, whether simple or parsed.
+    pub fn queries(&self) -> Vec<String> {
+        self.received().into_iter().filter_map(|msg| match msg {
+            Received::Query(query) => Some(query),
+            Received::Parse { query, .. } => Some(query),
+            _ => None,
+        }).collect()
+    }
+
+    /// The number of clients which have completed startup.
+    pub fn sessions(&self) -> usize {
+        self.shared.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Close the connection of the client with the given process ID, as
+    /// `pg_terminate_backend` does.  False if there is no such client.
+    pub fn terminate(&self, pid: u32) -> bool {
+        let writer = match lock(&self.shared.clients).remove(&pid) {
+            Some(writer) => writer,
+            None => return false,
+        };
+        let mut err = DbError::new("57P01", "terminating connection due to administrator command");
+        err.severity = "FATAL".to_string();
+        let mut stream = lock(&writer);
+        let _ = stream.write_all(&ServerMsg::ErrorResponse(err.fields()).to_bytes());
+        let _ = stream.shutdown(Shutdown::Both);
+        true
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        // Wake the listener so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Answers each query from the script.  Parameters are ignored, so a
+/// statement is answered by its text alone.
+struct Handler {
+    script: MockBackend,
+    shared: Arc<Shared>,
+}
+
+impl Handler {
+    fn answer(&self, session: &Session, sql: &str, response: Response) -> Result<QueryResult> {
+        match response {
+            Response::Rows(columns, rows) => {
+                Ok(QueryResult::Rows(columns.iter().map(|column| Column::text(column)).collect(), rows))
+            },
+            Response::Command(tag) => {
+                self.command(session, sql, &tag);
+                Ok(QueryResult::Command(tag))
+            },
+            Response::Error(code, message) => Err(PgError::Db(DbError::new(&code, &message))),
+            Response::CopyIn(format) => Ok(QueryResult::CopyIn(format)),
+            Response::CopyInError(..) => Ok(QueryResult::CopyIn(FieldFormat::Text)),
+            Response::CopyOut(format, chunks) => Ok(QueryResult::CopyOut(format, chunks)),
+            Response::Delay(duration, response) => {
+                try!(self.wait(session, duration));
+                self.answer(session, sql, *response)
+            },
+            Response::Disconnect => {
+                Err(PgError::Io(io::Error::new(io::ErrorKind::ConnectionAborted, "Scripted disconnect")))
+            },
+        }
+    }
+
+    /// LISTEN, UNLISTEN and NOTIFY take effect when answered as such.
+    fn command(&self, session: &Session, sql: &str, tag: &str) {
+        match tag {
+            "LISTEN" => if let Some(channel) = channel(sql) {
+                lock(&self.shared.listening).push((session.pid(), channel));
+            },
+            "UNLISTEN" => {
+                let channel = channel(sql).unwrap_or_else(|| "*".to_string());
+                lock(&self.shared.listening).retain(|&(pid, ref listened)| {
+                    pid != session.pid() || (channel != "*" && *listened != channel)
+                });
+            },
+            "NOTIFY" => if let Some((channel, payload)) = notification(sql) {
+                self.shared.notify(session.pid(), &channel, &payload);
+            },
+            _ => {},
+        }
+    }
+
+    /// Sleep for `duration`, unless the client cancels the query first.
+    fn wait(&self, session: &Session, duration: Duration) -> Result<()> {
+        let cancels = self.shared.cancels(session.pid());
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            if self.shared.cancels(session.pid()) > cancels {
+                return Err(PgError::Db(DbError::new("57014", "canceling statement due to user request")));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
+
+impl QueryHandler for Handler {
+    fn query(&self, session: &Session, sql: &str) -> Result<Vec<QueryResult>> {
+        let statements = statements(sql);
+        if statements.len() < 2 || self.script.scripted(sql).is_some() {
+            return self.execute(session, sql, &[]).map(|result| vec![result]);
+        }
+        statements.iter().map(|statement| self.execute(session, statement, &[])).collect()
+    }
+
+    fn describe(&self, _session: &Session, sql: &str, param_types: &[u32]) -> Result<Description> {
+        let columns = match self.script.response(sql) {
+            Response::Rows(columns, _) => Some(columns.iter().map(|column| Column::text(column)).collect()),
+            _ => None,
+        };
+        Ok(Description { params: param_types.to_vec(), columns: columns })
+    }
+
+    fn execute(&self, session: &Session, sql: &str, _params: &[Option<String>]) -> Result<QueryResult> {
+        self.answer(session, sql, self.script.response(sql))
+    }
+
+    /// Like Postgres, the header line of CSV is not counted as a row.
+    fn copy_in(&self, _session: &Session, sql: &str, format: FieldFormat, data: &[u8]) -> Result<String> {
+        if let Response::CopyInError(code, message) = self.script.response(sql) {
+            return Err(PgError::Db(DbError::new(&code, &message)));
+        }
+        let rows = try!(copy_rows(format, data));
+        let header = format == FieldFormat::Text && sql.to_uppercase().contains("HEADER");
+        Ok(format!("COPY {}", if header { rows.saturating_sub(1) } else { rows }))
+    }
+}
+
+/// Accepts any user name with the one password.
+impl Authenticator for Auth {
+    fn method(&self, _user: &str) -> AuthMethod {
+        match *self {
+            Auth::Trust => AuthMethod::Trust,
+            Auth::Cleartext(_) => AuthMethod::Cleartext,
+            Auth::Md5(_) => AuthMethod::Md5,
+            Auth::ScramSha256(_) => AuthMethod::ScramSha256,
+        }
+    }
+
+    fn password(&self, _user: &str) -> Option<Secret> {
+        match *self {
+            Auth::Trust => None,
+            Auth::Cleartext(ref password) | Auth::Md5(ref password) | Auth::ScramSha256(ref password) => {
+                Some(Secret::new(password))
+            },
+        }
+    }
+}
+
+/// A client's stream, which records each message as the server reads it.
+/// Writes are shared with other clients' sessions, which send notifications.
+struct Recorder {
+    stream: TcpStream,
+    writer: Arc<Mutex<TcpStream>>,
+    shared: Arc<Shared>,
+    /// Password messages hold SASL responses rather than passwords.
+    scram: bool,
+    input: Vec<u8>,
+    /// Until the startup message, messages have no identifier byte.
+    startup: bool,
+    passwords: usize,
+    registered: bool,
+}
+
+impl Recorder {
+    fn new(stream: TcpStream, shared: Arc<Shared>, scram: bool) -> io::Result<Recorder> {
+        let writer = try!(stream.try_clone());
+        Ok(Recorder {
+            stream: stream,
+            writer: Arc::new(Mutex::new(writer)),
+            shared: shared,
+            scram: scram,
+            input: vec![],
+            startup: true,
+            passwords: 0,
+            registered: false,
+        })
+    }
+
+    /// Add the client to the shared clients once its process ID is sent,
+    /// so it can be found as soon as it knows the ID.
+    fn register(&mut self, mut output: &[u8]) {
+        while let Ok((msg, rest)) = take_msg(output) {
+            if let Ok(ServerMsg::BackendKeyData(pid, _)) = ServerMsg::from_slice(msg) {
+                lock(&self.shared.clients).insert(pid, self.writer.clone());
+                self.registered = true;
+            }
+            output = rest;
+        }
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        let msg = match FrontendMsg::from_slice(bytes) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let received = match msg {
+            FrontendMsg::Startup(_) => {
+                self.startup = false;
+                Received::from_msg(&msg)
+            },
+            FrontendMsg::Password(body) => {
+                self.passwords += 1;
+                if !self.scram {
+                    Received::Password(PasswordMessage::from_body(body).map(|msg| msg.hash.to_string()).unwrap_or_default())
+                } else if self.passwords == 1 {
+                    match SaslInitialResponse::from_body(body) {
+                        Ok(initial) => Received::SaslInitialResponse {
+                            mechanism: initial.mechanism.to_string(),
+                            data: initial.data.unwrap_or(&[]).to_vec(),
+                        },
+                        Err(_) => Received::Other(b'p', body.to_vec()),
+                    }
+                } else {
+                    Received::SaslResponse(body.to_vec())
+                }
+            },
+            _ => Received::from_msg(&msg),
+        };
+        self.shared.record(received);
+    }
+}
+
+impl Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = try!(self.stream.read(buf));
+        self.input.extend(&buf[..count]);
+        loop {
+            let length = match take_frontend_msg(&self.input, self.startup) {
+                Ok((msg, _)) => msg.len(),
+                Err(_) => break,
+            };
+            let bytes: Vec<u8> = self.input.drain(..length).collect();
+            self.record(&bytes);
+        }
+        Ok(count)
+    }
+}
+
+impl Write for Recorder {
+    /// Each write is sent whole, so notifications cannot split a message.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.registered {
+            self.register(buf);
+        }
+        try!(lock(&self.writer).write_all(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(&self.writer).flush()
+    }
+}
+
+impl Transport for Recorder {
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+impl Received {
+    fn from_msg(msg: &FrontendMsg) -> Received {
+        match *msg {
+            FrontendMsg::Startup(ref startup) => Received::Startup {
+                user: startup.user.to_string(),
+                database: startup.database.map(|database| database.to_string()),
+                params: startup.params.clone(),
+            },
+            FrontendMsg::SslRequest => Received::SslRequest,
+            FrontendMsg::GssEncRequest => Received::GssEncRequest,
+            FrontendMsg::CancelRequest(ref cancel) => Received::CancelRequest {
+                pid: cancel.pid,
+                key: cancel.key.to_vec(),
+            },
+            FrontendMsg::Password(body) => Received::Other(b'p', body.to_vec()),
+            FrontendMsg::Query(ref query) => Received::Query(query.query.clone()),
+            FrontendMsg::Parse(ref parse) => Received::Parse {
+                name: parse.name.to_string(),
+                query: parse.query.to_string(),
+                param_types: parse.param_types.clone(),
+            },
+            FrontendMsg::Bind(ref bind) => Received::Bind {
+                portal: bind.portal.to_string(),
+                statement: bind.statement.to_string(),
+                params: bind.params.iter().map(|param| param.map(|value| value.to_vec())).collect(),
+            },
+            FrontendMsg::Describe(ref describe) => Received::Describe {
+                target: describe.target,
+                name: describe.name.to_string(),
+            },
+            FrontendMsg::Execute(ref execute) => Received::Execute {
+                portal: execute.portal.to_string(),
+                max_rows: execute.max_rows,
+            },
+            FrontendMsg::Close(ref close) => Received::Close {
+                target: close.target,
+                name: close.name.to_string(),
+            },
+            FrontendMsg::Sync => Received::Sync,
+            FrontendMsg::Flush => Received::Flush,
+            FrontendMsg::CopyData(ref copy) => Received::CopyData(copy.data.to_vec()),
+            FrontendMsg::CopyDone => Received::CopyDone,
+            FrontendMsg::CopyFail(ref fail) => Received::CopyFail(fail.message.to_string()),
+            FrontendMsg::Terminate => Received::Terminate,
+            FrontendMsg::FunctionCall(_) => Received::Other(b'F', msg.to_bytes()[5..].to_vec()),
+            FrontendMsg::Unknown(id, body) => Received::Other(id, body.to_vec()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use std::thread;
+    use auth::scram_client_final;
+    use cancel::CancelToken;
+    use connection::Connection;
+    use error::PgError;
+    use message::*;
+    use servermsg::{take_msg, AuthMsg, ServerMsg, TransactionStatus};
+    use super::*;
+
+    #[test]
+    fn test_simple_query() {
+        let server = MockBackend::new()
+            .on_query("SELECT name FROM users", Response::rows(&["name"], &[&["ann"], &["bob"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff").database("app")).unwrap();
+        let rows = conn.query("SELECT name FROM users;").unwrap();
+        assert_eq!(rows, vec![vec!["ann".to_string()], vec!["bob".to_string()]]);
+        assert_eq!(server.sessions(), 1);
+        assert_eq!(server.received()[0], Received::Startup {
+            user: "cliff".to_string(),
+            database: Some("app".to_string()),
+            params: vec![],
+        });
+        assert_eq!(server.queries(), vec!["SELECT name FROM users;".to_string()]);
+    }
+
+    #[test]
+    fn test_null_values() {
+        let server = MockBackend::new()
+            .on_query("SELECT 1, NULL", Response::nullable_rows(&["a", "b"], &[&[Some("1"), None]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        conn.send_message(&Query { query: "SELECT 1, NULL;".to_string() }.to_bytes()).unwrap();
+        let mut rows = vec![];
+        loop {
+            let bytes = conn.read_message().unwrap();
+            match ServerMsg::from_slice(&bytes).unwrap() {
+                ServerMsg::DataRow(values) => rows.push(values.iter().map(|value| value.map(|value| value.to_string())).collect::<Vec<_>>()),
+                ServerMsg::ReadyForQuery(_) => break,
+                _ => {},
+            }
+        }
+        assert_eq!(rows, vec![vec![Some("1".to_string()), None]]);
+    }
+
+    #[test]
+    fn test_md5_auth() {
+        let server = MockBackend::new().auth(Auth::Md5("secret".to_string())).start().unwrap();
+        assert!(Connection::connect(&server.config("cliff").password("secret")).is_ok());
+        match Connection::connect(&server.config("cliff").password("wrong")) {
+            Err(err) => assert_eq!(err.code(), Some("28P01")),
+            Ok(_) => panic!("Expected authentication to fail"),
+        }
+    }
+
+    #[test]
+    fn test_scram_auth() {
+        let server = MockBackend::new().auth(Auth::ScramSha256("secret".to_string())).start().unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(&StartupMessage { user: "cliff", database: None, params: vec![] }.to_bytes()).unwrap();
+        let bytes = read_frame(&mut stream);
+        assert_eq!(ServerMsg::from_slice(&bytes).unwrap(), ServerMsg::Auth(AuthMsg::Sasl(vec!["SCRAM-SHA-256"])));
+
+        let client_first_bare = "n=,r=clientnonce";
+        let client_first = format!("n,,{}", client_first_bare);
+        let initial = SaslInitialResponse { mechanism: "SCRAM-SHA-256", data: Some(client_first.as_bytes()) };
+        stream.write_all(&initial.to_bytes()).unwrap();
+        let bytes = read_frame(&mut stream);
+        let server_first = match ServerMsg::from_slice(&bytes).unwrap() {
+            ServerMsg::Auth(AuthMsg::SaslContinue(data)) => String::from_utf8(data.to_vec()).unwrap(),
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        assert!(server_first.starts_with("r=clientnonce"));
+        let (client_final, expected) = scram_client_final("secret", client_first_bare, &server_first);
+        stream.write_all(&SaslResponse { data: client_final.as_bytes() }.to_bytes()).unwrap();
+
+        let bytes = read_frame(&mut stream);
+        assert_eq!(ServerMsg::from_slice(&bytes).unwrap(), ServerMsg::Auth(AuthMsg::SaslFinal(expected.as_bytes())));
+        let bytes = read_frame(&mut stream);
+        assert_eq!(ServerMsg::from_slice(&bytes).unwrap(), ServerMsg::Auth(AuthMsg::Ok));
+    }
+
+    #[test]
+    fn test_errors_and_transactions() {
+        let server = MockBackend::new()
+            .on_query("INSERT INTO t VALUES (1)", Response::command("INSERT 0 1"))
+            .on_query("INSERT INTO t VALUES ('x')", Response::error("22P02", "invalid input syntax"))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        conn.query("BEGIN;").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::InTransaction);
+        conn.query("INSERT INTO t VALUES (1);").unwrap();
+        conn.query("SAVEPOINT a;").unwrap();
+        match conn.query("INSERT INTO t VALUES ('x');") {
+            Err(PgError::Db(err)) => assert_eq!(err.code, "22P02"),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        assert_eq!(conn.transaction_status(), TransactionStatus::Failed);
+        conn.query("ROLLBACK TO SAVEPOINT a;").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::InTransaction);
+        conn.query("ROLLBACK;").unwrap();
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        assert!(conn.query("SELECT unscripted;").is_err());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let server = MockBackend::new().on_query("SELECT pg_terminate_backend(1)", Response::Disconnect).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        match conn.query("SELECT pg_terminate_backend(1);") {
+            Err(PgError::Io(_)) => {},
+            other => panic!("Expected the connection to be closed, got {:?}", other),
+        }
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_terminate() {
+        let server = MockBackend::new().start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let pid = conn.cancel_token().unwrap().pid();
+        assert!(!server.terminate(pid + 1));
+        assert!(server.terminate(pid));
+        assert!(conn.query("BEGIN;").is_err());
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_copy() {
+        let server = MockBackend::new()
+            .on_query("COPY t FROM STDIN", Response::CopyIn(FieldFormat::Text))
+            .on_query("COPY t TO STDOUT", Response::CopyOut(FieldFormat::Text, vec![b"1\n".to_vec(), b"2\n".to_vec()]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let mut writer = conn.copy_in("COPY t FROM STDIN;").unwrap();
+        writer.write_all(b"1\n2\n3\n").unwrap();
+        assert_eq!(writer.finish().unwrap(), 3);
+        assert!(server.received().contains(&Received::CopyData(b"1\n2\n3\n".to_vec())));
+
+        let mut reader = conn.copy_out("COPY t TO STDOUT;").unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "1\n2\n");
+        assert_eq!(reader.finish().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_extended_query() {
+        let server = MockBackend::new()
+            .on_query("SELECT $1::int", Response::rows(&["int4"], &[&["5"]]))
+            .start()
+            .unwrap();
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(&StartupMessage { user: "cliff", database: None, params: vec![] }.to_bytes()).unwrap();
+        while read_frame(&mut stream)[0] != b'Z' {}
+
+        let mut messages = Parse { name: "", query: "SELECT $1::int", param_types: vec![23] }.to_bytes();
+        messages.extend(Bind {
+            portal: "",
+            statement: "",
+            param_formats: vec![],
+            params: vec![Some(b"5")],
+            result_formats: vec![],
+        }.to_bytes());
+        messages.extend(Describe { target: Target::Portal, name: "" }.to_bytes());
+        messages.extend(Execute { portal: "", max_rows: 0 }.to_bytes());
+        messages.extend(Sync.to_bytes());
+        messages.extend(Bind {
+            portal: "",
+            statement: "missing",
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        }.to_bytes());
+        messages.extend(Execute { portal: "", max_rows: 0 }.to_bytes());
+        messages.extend(Sync.to_bytes());
+        stream.write_all(&messages).unwrap();
+
+        let mut ids = vec![];
+        for _ in 0..8 {
+            ids.push(read_frame(&mut stream)[0]);
+        }
+        assert_eq!(ids, b"12TDCZEZ".to_vec());
+        assert!(server.received().contains(&Received::Bind {
+            portal: String::new(),
+            statement: String::new(),
+            params: vec![Some(b"5".to_vec())],
+        }));
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let server = MockBackend::new().start().unwrap();
+        CancelToken::new("127.0.0.1", server.port(), 1000, &[1, 2, 3, 4]).cancel().unwrap();
+        let mut received = vec![];
+        for _ in 0..100 {
+            received = server.received();
+            if !received.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(received, vec![Received::CancelRequest { pid: 1000, key: vec![1, 2, 3, 4] }]);
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(statements("BEGIN; SELECT 1;"), vec!["BEGIN", " SELECT 1"]);
+        assert_eq!(statements("NOTIFY jobs, 'a;b'"), vec!["NOTIFY jobs, 'a;b'"]);
+        assert_eq!(channel("LISTEN \"jobs\";"), Some("jobs".to_string()));
+        assert_eq!(notification("NOTIFY jobs, 'it''s done';"), Some(("jobs".to_string(), "it's done".to_string())));
+        assert_eq!(notification("NOTIFY jobs"), Some(("jobs".to_string(), String::new())));
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+        let mut bytes = vec![0; 5];
+        stream.read_exact(&mut bytes).unwrap();
+        let length = bytes[1..].iter().fold(0, |length, &byte| length << 8 | byte as usize);
+        bytes.resize(length + 1, 0);
+        stream.read_exact(&mut bytes[5..]).unwrap();
+        assert_eq!(take_msg(&bytes).unwrap().1.len(), 0);
+        bytes
+    }
+}
@@ -0,0 +1,708 @@
+use std::cmp;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+use Result;
+use auth::random_bytes;
+use connection::{Connection, CopyInWriter, CopyOutReader};
+use error::PgError;
+use servermsg::TransactionStatus;
+
+impl Connection {
+    /// Begin a transaction.  The transaction is rolled back when the
+    /// returned guard is dropped, unless it has been committed.
+    pub fn transaction(&mut self) -> Result<Transaction<'_>> {
+        Transaction::begin(self, 0)
+    }
+
+    /// Configure a transaction's isolation level and access mode before
+    /// beginning it.
+    pub fn build_transaction(&mut self) -> TransactionBuilder<'_> {
+        TransactionBuilder {
+            conn: self,
+            isolation_level: None,
+            read_only: None,
+            deferrable: None,
+        }
+    }
+
+    /// Run `body` in a transaction, and commit it.  If the transaction fails
+    /// with a serialization failure or a deadlock, it is rolled back and
+    /// `body` is run again, after a randomized exponential backoff.  Any
+    /// other error is returned immediately.
+    pub fn transaction_with_retry<T, F>(&mut self, options: &RetryOptions, mut body: F) -> Result<T>
+        where F: FnMut(&mut Transaction) -> Result<T>
+    {
+        let mut attempt = 1;
+        loop {
+            let result = {
+                let mut builder = self.build_transaction();
+                if let Some(isolation_level) = options.isolation_level {
+                    builder = builder.isolation_level(isolation_level);
+                }
+                builder.begin().and_then(|mut tx| {
+                    let value = try!(body(&mut tx));
+                    try!(tx.commit());
+                    Ok(value)
+                })
+            };
+            match result {
+                Err(ref err) if is_retryable(err) && attempt < options.max_attempts => {
+                    thread::sleep(options.delay(attempt));
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// List the transactions prepared for two-phase commit on this server.
+    pub fn prepared_transactions(&mut self) -> Result<Vec<PreparedTransaction>> {
+        let rows = try!(self.query(
+            "SELECT gid, transaction::text, owner, database, prepared::text \
+             FROM pg_prepared_xacts ORDER BY prepared"
+        ));
+        let mut prepared = Vec::with_capacity(rows.len());
+        for row in rows {
+            if row.len() != 5 {
+                return Err(PgError::Error(format!("Unexpected row from pg_prepared_xacts: {:?}", row)));
+            }
+            let mut columns = row.into_iter();
+            prepared.push(PreparedTransaction {
+                gid: columns.next().unwrap_or_default(),
+                transaction: columns.next().unwrap_or_default(),
+                owner: columns.next().unwrap_or_default(),
+                database: columns.next().unwrap_or_default(),
+                prepared: columns.next().unwrap_or_default(),
+            });
+        }
+        Ok(prepared)
+    }
+
+    /// Commit a transaction prepared with `Transaction::prepare`.
+    pub fn commit_prepared(&mut self, gid: &str) -> Result<()> {
+        try!(self.query(&format!("COMMIT PREPARED {}", quote_literal(gid))));
+        Ok(())
+    }
+
+    /// Roll back a transaction prepared with `Transaction::prepare`.
+    pub fn rollback_prepared(&mut self, gid: &str) -> Result<()> {
+        try!(self.query(&format!("ROLLBACK PREPARED {}", quote_literal(gid))));
+        Ok(())
+    }
+}
+
+/// A transaction prepared for two-phase commit, as listed in
+/// `pg_prepared_xacts`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PreparedTransaction {
+    pub gid: String,
+    pub transaction: String,
+    pub owner: String,
+    pub database: String,
+    pub prepared: String,
+}
+
+/// Serialization failures and deadlocks can succeed when retried.
+fn is_retryable(err: &PgError) -> bool {
+    match err.code() {
+        Some("40001") | Some("40P01") => true,
+        _ => false,
+    }
+}
+
+/// How `Connection::transaction_with_retry` retries failed transactions.
+#[derive(Clone, Debug)]
+pub struct RetryOptions {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    isolation_level: Option<IsolationLevel>,
+}
+
+impl RetryOptions {
+    /// Five attempts, with a backoff starting at 10ms and capped at 1s.
+    pub fn new() -> RetryOptions {
+        RetryOptions {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            isolation_level: None,
+        }
+    }
+
+    /// The total number of times the transaction is attempted.
+    pub fn max_attempts(mut self, max_attempts: u32) -> RetryOptions {
+        self.max_attempts = cmp::max(max_attempts, 1);
+        self
+    }
+
+    /// The backoff before the first retry, which doubles after each
+    /// further attempt up to `max_backoff`.
+    pub fn backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> RetryOptions {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn isolation_level(mut self, isolation_level: IsolationLevel) -> RetryOptions {
+        self.isolation_level = Some(isolation_level);
+        self
+    }
+
+    /// How long to wait after the given failed attempt.  Half of the
+    /// backoff is fixed and half is random, so that competing clients
+    /// spread out their retries.
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::max_value());
+        let backoff = cmp::min(
+            self.initial_backoff.checked_mul(factor).unwrap_or(self.max_backoff),
+            self.max_backoff,
+        );
+        let half = backoff / 2;
+        let jitter_nanos = (half.as_nanos() as u64).saturating_add(1);
+        half + Duration::from_nanos(random() % jitter_nanos)
+    }
+}
+
+impl Default for RetryOptions {
+    fn default() -> RetryOptions {
+        RetryOptions::new()
+    }
+}
+
+fn random() -> u64 {
+    random_bytes(8).iter().fold(0, |n, &byte| n << 8 | byte as u64)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl fmt::Display for IsolationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IsolationLevel::ReadUncommitted => write!(f, "READ UNCOMMITTED"),
+            IsolationLevel::ReadCommitted => write!(f, "READ COMMITTED"),
+            IsolationLevel::RepeatableRead => write!(f, "REPEATABLE READ"),
+            IsolationLevel::Serializable => write!(f, "SERIALIZABLE"),
+        }
+    }
+}
+
+/// Options for a new transaction.  Settings that are not given use the
+/// server's defaults.
+pub struct TransactionBuilder<'a> {
+    conn: &'a mut Connection,
+    isolation_level: Option<IsolationLevel>,
+    read_only: Option<bool>,
+    deferrable: Option<bool>,
+}
+
+impl <'a> TransactionBuilder<'a> {
+    pub fn isolation_level(mut self, isolation_level: IsolationLevel) -> TransactionBuilder<'a> {
+        self.isolation_level = Some(isolation_level);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> TransactionBuilder<'a> {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Only allowed for serializable, read only transactions.
+    pub fn deferrable(mut self, deferrable: bool) -> TransactionBuilder<'a> {
+        self.deferrable = Some(deferrable);
+        self
+    }
+
+    pub fn begin(self) -> Result<Transaction<'a>> {
+        let sql = try!(begin_sql(self.isolation_level, self.read_only, self.deferrable));
+        Transaction::start(self.conn, 0, &sql)
+    }
+}
+
+/// The `BEGIN` statement for a transaction with the given options.
+fn begin_sql(isolation_level: Option<IsolationLevel>, read_only: Option<bool>, deferrable: Option<bool>) -> Result<String> {
+    if deferrable == Some(true) {
+        if isolation_level != Some(IsolationLevel::Serializable) || read_only != Some(true) {
+            return Err(PgError::Error(
+                "DEFERRABLE requires a SERIALIZABLE, READ ONLY transaction".to_string()
+            ));
+        }
+    }
+    let mut sql = "BEGIN".to_string();
+    if let Some(isolation_level) = isolation_level {
+        sql.push_str(&format!(" ISOLATION LEVEL {}", isolation_level));
+    }
+    match read_only {
+        Some(true) => sql.push_str(" READ ONLY"),
+        Some(false) => sql.push_str(" READ WRITE"),
+        None => {},
+    }
+    match deferrable {
+        Some(true) => sql.push_str(" DEFERRABLE"),
+        Some(false) => sql.push_str(" NOT DEFERRABLE"),
+        None => {},
+    }
+    Ok(sql)
+}
+
+/// An open transaction on a `Connection`.
+///
+/// The transaction borrows the connection for its whole lifetime, so all
+/// queries must go through it.  Nested transactions are implemented with
+/// savepoints.
+pub struct Transaction<'a> {
+    conn: &'a mut Connection,
+    depth: u32,
+    done: bool,
+}
+
+impl <'a> Transaction<'a> {
+    fn begin(conn: &'a mut Connection, depth: u32) -> Result<Transaction<'a>> {
+        let sql = if depth == 0 {
+            "BEGIN".to_string()
+        } else {
+            format!("SAVEPOINT {}", savepoint_name(depth))
+        };
+        Transaction::start(conn, depth, &sql)
+    }
+
+    fn start(conn: &'a mut Connection, depth: u32, sql: &str) -> Result<Transaction<'a>> {
+        try!(conn.query(sql));
+        Ok(Transaction {
+            conn: conn,
+            depth: depth,
+            done: false,
+        })
+    }
+
+    /// How deeply this transaction is nested.  The outermost transaction has
+    /// depth 0.
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn query(&mut self, sql: &str) -> Result<Vec<Vec<String>>> {
+        self.conn.query(sql)
+    }
+
+    pub fn query_with_timeout(&mut self, sql: &str, timeout: Duration) -> Result<Vec<Vec<String>>> {
+        self.conn.query_with_timeout(sql, timeout)
+    }
+
+    pub fn copy_in(&mut self, sql: &str) -> Result<CopyInWriter<'_>> {
+        self.conn.copy_in(sql)
+    }
+
+    pub fn copy_out(&mut self, sql: &str) -> Result<CopyOutReader<'_>> {
+        self.conn.copy_out(sql)
+    }
+
+    /// Begin a nested transaction, using a savepoint.
+    pub fn transaction(&mut self) -> Result<Transaction<'_>> {
+        Transaction::begin(self.conn, self.depth + 1)
+    }
+
+    /// Commit the transaction.  If an earlier statement failed, the
+    /// transaction is rolled back instead and `PgError::FailedTransaction`
+    /// is returned.
+    pub fn commit(mut self) -> Result<()> {
+        self.done = true;
+        if self.conn.transaction_status() == TransactionStatus::Failed {
+            try!(self.finish_rollback());
+            return Err(PgError::FailedTransaction);
+        }
+        let sql = if self.depth == 0 {
+            "COMMIT".to_string()
+        } else {
+            format!("RELEASE SAVEPOINT {}", savepoint_name(self.depth))
+        };
+        try!(self.conn.query(&sql));
+        Ok(())
+    }
+
+    /// Prepare the transaction for two-phase commit under the global
+    /// transaction id `gid`.  Once prepared, the transaction is no longer
+    /// tied to this session, and is finished with
+    /// `Connection::commit_prepared` or `Connection::rollback_prepared`.
+    pub fn prepare(mut self, gid: &str) -> Result<()> {
+        self.done = true;
+        if self.depth != 0 {
+            try!(self.finish_rollback());
+            return Err(PgError::Error("Only the outermost transaction can be prepared".to_string()));
+        }
+        if self.conn.transaction_status() == TransactionStatus::Failed {
+            try!(self.finish_rollback());
+            return Err(PgError::FailedTransaction);
+        }
+        try!(self.conn.query(&format!("PREPARE TRANSACTION {}", quote_literal(gid))));
+        Ok(())
+    }
+
+    pub fn rollback(mut self) -> Result<()> {
+        self.done = true;
+        self.finish_rollback()
+    }
+
+    fn finish_rollback(&mut self) -> Result<()> {
+        if self.depth == 0 {
+            try!(self.conn.query("ROLLBACK"));
+        } else {
+            let name = savepoint_name(self.depth);
+            try!(self.conn.query(&format!("ROLLBACK TO SAVEPOINT {}", name)));
+            try!(self.conn.query(&format!("RELEASE SAVEPOINT {}", name)));
+        }
+        Ok(())
+    }
+}
+
+impl <'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.finish_rollback();
+        }
+    }
+}
+
+fn savepoint_name(depth: u32) -> String {
+    format!("pg_savepoint_{}", depth)
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use connection::Connection;
+    use error::PgError;
+    use servermsg::TransactionStatus;
+    use std::time::Duration;
+    use testing::{MockBackend, MockServer, Response};
+    use super::{begin_sql, quote_literal, IsolationLevel, RetryOptions};
+
+    fn connect() -> Connection {
+        let user_string = env::var("USER").unwrap();
+        let user = user_string.as_ref();
+        let pass = Some(user);
+        let host = "127.0.0.1";
+        let mut conn = Connection::new(user, pass, host, Some(user)).expect("Could not establish connection");
+        conn.query("CREATE TEMPORARY TABLE items (n int);").unwrap();
+        conn
+    }
+
+    fn items(conn: &mut Connection) -> Vec<Vec<String>> {
+        conn.query("SELECT n FROM items ORDER BY n;").unwrap()
+    }
+
+    /// A mock server which accepts any statement, except a query of a
+    /// table which does not exist.
+    fn mock() -> MockServer {
+        MockBackend::new()
+            .on_query("SELECT * FROM no_such_table;", Response::error("42P01", "relation \"no_such_table\" does not exist"))
+            .default_response(Response::command("INSERT 0 1"))
+            .start()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_commit() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut tx = conn.transaction().unwrap();
+            tx.query("INSERT INTO items VALUES (1);").unwrap();
+            tx.commit().unwrap();
+        }
+        assert_eq!(server.queries(), vec!["BEGIN", "INSERT INTO items VALUES (1);", "COMMIT"]);
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+    }
+
+    #[test]
+    fn test_rollback_on_drop() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut tx = conn.transaction().unwrap();
+            tx.query("INSERT INTO items VALUES (1);").unwrap();
+        }
+        assert_eq!(server.queries(), vec!["BEGIN", "INSERT INTO items VALUES (1);", "ROLLBACK"]);
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+    }
+
+    #[test]
+    fn test_rollback_after_error() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut tx = conn.transaction().unwrap();
+            tx.query("INSERT INTO items VALUES (1);").unwrap();
+            assert!(tx.query("SELECT * FROM no_such_table;").is_err());
+            tx.rollback().unwrap();
+        }
+        assert_eq!(
+            server.queries(),
+            vec!["BEGIN", "INSERT INTO items VALUES (1);", "SELECT * FROM no_such_table;", "ROLLBACK"]
+        );
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut tx = conn.transaction().unwrap();
+            tx.query("INSERT INTO items VALUES (1);").unwrap();
+            {
+                let mut inner = tx.transaction().unwrap();
+                assert_eq!(inner.depth(), 1);
+                inner.query("INSERT INTO items VALUES (2);").unwrap();
+            }
+            {
+                let mut inner = tx.transaction().unwrap();
+                inner.query("INSERT INTO items VALUES (3);").unwrap();
+                {
+                    let mut innermost = inner.transaction().unwrap();
+                    assert_eq!(innermost.depth(), 2);
+                    assert!(innermost.query("SELECT * FROM no_such_table;").is_err());
+                }
+                inner.query("INSERT INTO items VALUES (4);").unwrap();
+                inner.commit().unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        assert_eq!(server.queries(), vec![
+            "BEGIN",
+            "INSERT INTO items VALUES (1);",
+            "SAVEPOINT pg_savepoint_1",
+            "INSERT INTO items VALUES (2);",
+            "ROLLBACK TO SAVEPOINT pg_savepoint_1",
+            "RELEASE SAVEPOINT pg_savepoint_1",
+            "SAVEPOINT pg_savepoint_1",
+            "INSERT INTO items VALUES (3);",
+            "SAVEPOINT pg_savepoint_2",
+            "SELECT * FROM no_such_table;",
+            "ROLLBACK TO SAVEPOINT pg_savepoint_2",
+            "RELEASE SAVEPOINT pg_savepoint_2",
+            "INSERT INTO items VALUES (4);",
+            "RELEASE SAVEPOINT pg_savepoint_1",
+            "COMMIT",
+        ]);
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+    }
+
+    #[test]
+    fn test_builder_sql() {
+        assert_eq!(begin_sql(None, None, None).unwrap(), "BEGIN");
+        assert_eq!(
+            begin_sql(Some(IsolationLevel::RepeatableRead), None, None).unwrap(),
+            "BEGIN ISOLATION LEVEL REPEATABLE READ"
+        );
+        assert_eq!(
+            begin_sql(Some(IsolationLevel::Serializable), Some(true), Some(true)).unwrap(),
+            "BEGIN ISOLATION LEVEL SERIALIZABLE READ ONLY DEFERRABLE"
+        );
+        assert_eq!(begin_sql(None, Some(false), Some(false)).unwrap(), "BEGIN READ WRITE NOT DEFERRABLE");
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_deferrable() {
+        assert!(begin_sql(None, None, Some(true)).is_err());
+        assert!(begin_sql(Some(IsolationLevel::Serializable), None, Some(true)).is_err());
+        assert!(begin_sql(Some(IsolationLevel::RepeatableRead), Some(true), Some(true)).is_err());
+    }
+
+    #[test]
+    fn test_builder_begin() {
+        let server = MockBackend::new()
+            .on_query(
+                "CREATE TABLE read_only_test (n int);",
+                Response::error("25006", "cannot execute CREATE TABLE in a read-only transaction"),
+            )
+            .default_response(Response::command("INSERT 0 1"))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut tx = conn.build_transaction()
+                .isolation_level(IsolationLevel::Serializable)
+                .read_only(true)
+                .deferrable(true)
+                .begin()
+                .unwrap();
+            assert!(tx.query("CREATE TABLE read_only_test (n int);").is_err());
+        }
+        let mut tx = conn.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .begin()
+            .unwrap();
+        tx.query("INSERT INTO items VALUES (1);").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(server.queries(), vec![
+            "BEGIN ISOLATION LEVEL SERIALIZABLE READ ONLY DEFERRABLE",
+            "CREATE TABLE read_only_test (n int);",
+            "ROLLBACK",
+            "BEGIN ISOLATION LEVEL REPEATABLE READ",
+            "INSERT INTO items VALUES (1);",
+            "COMMIT",
+        ]);
+    }
+
+    #[test]
+    fn test_commit_failed_transaction() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        {
+            let mut tx = conn.transaction().unwrap();
+            tx.query("INSERT INTO items VALUES (1);").unwrap();
+            assert!(tx.query("SELECT * FROM no_such_table;").is_err());
+            match tx.commit() {
+                Err(PgError::FailedTransaction) => {},
+                other => panic!("Expected failed transaction error, got {:?}", other),
+            }
+        }
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        assert_eq!(server.queries().last().map(|sql| &sql[..]), Some("ROLLBACK"));
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let options = RetryOptions::new().backoff(Duration::from_millis(100), Duration::from_millis(300));
+        for _ in 0..20 {
+            let first = options.delay(1);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+            let second = options.delay(2);
+            assert!(second >= Duration::from_millis(100) && second <= Duration::from_millis(200));
+            let capped = options.delay(40);
+            assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+        }
+    }
+
+    fn fast_retries() -> RetryOptions {
+        RetryOptions::new()
+            .max_attempts(3)
+            .backoff(Duration::from_millis(1), Duration::from_millis(5))
+    }
+
+    #[test]
+    #[ignore = "needs a live server to fail and then succeed"]
+    fn test_retry_serialization_failure() {
+        let mut conn = connect();
+        let mut attempts = 0;
+        let result = conn.transaction_with_retry(&fast_retries(), |tx| {
+            attempts += 1;
+            tx.query("INSERT INTO items VALUES (1);").unwrap();
+            if attempts < 3 {
+                try!(tx.query("DO $$ BEGIN RAISE EXCEPTION 'conflict' USING ERRCODE = '40001'; END $$;"));
+            }
+            Ok(attempts)
+        });
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(items(&mut conn), vec![vec!["1".to_string()]]);
+    }
+
+    #[test]
+    fn test_retry_gives_up() {
+        let deadlock = "DO $$ BEGIN RAISE EXCEPTION 'deadlock' USING ERRCODE = '40P01'; END $$;";
+        let server = MockBackend::new().on_query(deadlock, Response::error("40P01", "deadlock")).start().unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let mut attempts = 0;
+        let result = conn.transaction_with_retry(&fast_retries(), |tx| {
+            attempts += 1;
+            try!(tx.query(deadlock));
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().code(), Some("40P01"));
+        assert_eq!(attempts, 3);
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        assert_eq!(server.queries().iter().filter(|sql| *sql == "ROLLBACK").count(), 3);
+    }
+
+    #[test]
+    fn test_retry_propagates_other_errors() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let mut attempts = 0;
+        let result = conn.transaction_with_retry(&fast_retries(), |tx| {
+            attempts += 1;
+            tx.query("SELECT * FROM no_such_table;")
+        });
+        assert_eq!(result.unwrap_err().code(), Some("42P01"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_retry_with_isolation_level() {
+        let server = MockBackend::new()
+            .on_query("SHOW transaction_isolation;", Response::rows(&["transaction_isolation"], &[&["serializable"]]))
+            .start()
+            .unwrap();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let options = fast_retries().isolation_level(IsolationLevel::Serializable);
+        let isolation = conn.transaction_with_retry(&options, |tx| {
+            tx.query("SHOW transaction_isolation;")
+        }).unwrap();
+        assert_eq!(isolation, vec![vec!["serializable".to_string()]]);
+        assert_eq!(server.queries()[0], "BEGIN ISOLATION LEVEL SERIALIZABLE");
+    }
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("txn-1"), "'txn-1'");
+        assert_eq!(quote_literal("it's"), "'it''s'");
+    }
+
+    #[test]
+    #[ignore = "needs a live server with max_prepared_transactions set"]
+    fn test_two_phase_commit() {
+        let mut conn = connect();
+        if conn.query("SHOW max_prepared_transactions;").unwrap() == vec![vec!["0".to_string()]] {
+            eprintln!("skipping test_two_phase_commit: the server has max_prepared_transactions = 0");
+            return;
+        }
+        // Transactions which use temporary tables can't be prepared, so this
+        // table is real, and dropped at the end.
+        conn.query("DROP TABLE IF EXISTS two_phase_items; CREATE TABLE two_phase_items (gid text);").unwrap();
+        for gid in &["test 2pc 'commit'", "test 2pc 'rollback'"] {
+            let mut tx = conn.transaction().unwrap();
+            tx.query(&format!("INSERT INTO two_phase_items VALUES ({});", quote_literal(gid))).unwrap();
+            tx.prepare(gid).unwrap();
+        }
+        assert_eq!(conn.transaction_status(), TransactionStatus::Idle);
+        {
+            let prepared = conn.prepared_transactions().unwrap();
+            let gids: Vec<&str> = prepared.iter().map(|p| &p.gid[..]).collect();
+            assert!(gids.contains(&"test 2pc 'commit'"));
+            assert!(gids.contains(&"test 2pc 'rollback'"));
+        }
+
+        conn.commit_prepared("test 2pc 'commit'").unwrap();
+        conn.rollback_prepared("test 2pc 'rollback'").unwrap();
+        let prepared = conn.prepared_transactions().unwrap();
+        assert!(prepared.iter().all(|p| !p.gid.starts_with("test 2pc")));
+        let rows = conn.query("SELECT gid FROM two_phase_items WHERE gid LIKE 'test 2pc%';").unwrap();
+        assert_eq!(rows, vec![vec!["test 2pc 'commit'".to_string()]]);
+        conn.query("DROP TABLE two_phase_items;").unwrap();
+    }
+
+    #[test]
+    fn test_prepare_nested_transaction() {
+        let server = mock();
+        let mut conn = Connection::connect(&server.config("cliff")).unwrap();
+        let mut tx = conn.transaction().unwrap();
+        let inner = tx.transaction().unwrap();
+        assert!(inner.prepare("test nested").is_err());
+        assert_eq!(tx.conn.transaction_status(), TransactionStatus::InTransaction);
+        assert!(!server.queries().iter().any(|sql| sql.starts_with("PREPARE TRANSACTION")));
+    }
+}
@@ -0,0 +1,194 @@
+use std::str::from_utf8;
+use Result;
+use error::PgError;
+
+/// Whether a value was encoded as SQL NULL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IsNull {
+    Yes,
+    No,
+}
+
+/// A value that can be sent to the server in binary format.
+pub trait ToSql {
+    /// Append the binary encoding of the value to `out`.  Nothing is
+    /// appended for NULL.
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull>;
+}
+
+/// A value that can be read from the binary format sent by the server.
+pub trait FromSql: Sized {
+    fn from_sql(raw: &[u8]) -> Result<Self>;
+
+    fn from_sql_null() -> Result<Self> {
+        Err(PgError::Error("Unexpected NULL value".to_string()))
+    }
+
+    /// Read a value which may be NULL.
+    fn from_sql_nullable(raw: Option<&[u8]>) -> Result<Self> {
+        match raw {
+            Some(raw) => Self::from_sql(raw),
+            None => Self::from_sql_null(),
+        }
+    }
+}
+
+fn fixed<'a>(raw: &'a [u8], size: usize, type_name: &str) -> Result<&'a [u8]> {
+    if raw.len() == size {
+        Ok(raw)
+    } else {
+        Err(PgError::Error(format!("Expected {} bytes for {}, found {}", size, type_name, raw.len())))
+    }
+}
+
+impl ToSql for bool {
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+        out.push(*self as u8);
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql for bool {
+    fn from_sql(raw: &[u8]) -> Result<bool> {
+        let raw = try!(fixed(raw, 1, "bool"));
+        Ok(raw[0] != 0)
+    }
+}
+
+macro_rules! number {
+    ($t:ty, $size:expr, $name:expr) => {
+        impl ToSql for $t {
+            fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+                out.extend(&self.to_be_bytes());
+                Ok(IsNull::No)
+            }
+        }
+
+        impl FromSql for $t {
+            fn from_sql(raw: &[u8]) -> Result<$t> {
+                let raw = try!(fixed(raw, $size, $name));
+                let mut bytes = [0; $size];
+                bytes.copy_from_slice(raw);
+                Ok(<$t>::from_be_bytes(bytes))
+            }
+        }
+    }
+}
+
+number!(i16, 2, "int2");
+number!(i32, 4, "int4");
+number!(i64, 8, "int8");
+number!(f32, 4, "float4");
+number!(f64, 8, "float8");
+
+impl <'a> ToSql for &'a str {
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+        out.extend(self.as_bytes());
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for String {
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+        self.as_str().to_sql(out)
+    }
+}
+
+impl FromSql for String {
+    fn from_sql(raw: &[u8]) -> Result<String> {
+        Ok(try!(from_utf8(raw)).to_string())
+    }
+}
+
+impl <'a> ToSql for &'a [u8] {
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+        out.extend(*self);
+        Ok(IsNull::No)
+    }
+}
+
+impl ToSql for Vec<u8> {
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+        (&self[..]).to_sql(out)
+    }
+}
+
+impl FromSql for Vec<u8> {
+    fn from_sql(raw: &[u8]) -> Result<Vec<u8>> {
+        Ok(raw.to_vec())
+    }
+}
+
+impl <T: ToSql> ToSql for Option<T> {
+    fn to_sql(&self, out: &mut Vec<u8>) -> Result<IsNull> {
+        match *self {
+            Some(ref value) => value.to_sql(out),
+            None => Ok(IsNull::Yes),
+        }
+    }
+}
+
+impl <T: FromSql> FromSql for Option<T> {
+    fn from_sql(raw: &[u8]) -> Result<Option<T>> {
+        T::from_sql(raw).map(Some)
+    }
+
+    fn from_sql_null() -> Result<Option<T>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<T: ToSql>(value: T) -> (IsNull, Vec<u8>) {
+        let mut out = vec![];
+        let is_null = value.to_sql(&mut out).unwrap();
+        (is_null, out)
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(encode(1i16), (IsNull::No, vec![0, 1]));
+        assert_eq!(encode(-2i32), (IsNull::No, vec![0xff, 0xff, 0xff, 0xfe]));
+        assert_eq!(encode(258i64), (IsNull::No, vec![0, 0, 0, 0, 0, 0, 1, 2]));
+        assert_eq!(i16::from_sql(&[0, 1]).unwrap(), 1);
+        assert_eq!(i32::from_sql(&[0xff, 0xff, 0xff, 0xfe]).unwrap(), -2);
+        assert_eq!(i64::from_sql(&[0, 0, 0, 0, 0, 0, 1, 2]).unwrap(), 258);
+        assert!(i32::from_sql(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_floats() {
+        let (_, bytes) = encode(0.1f64);
+        assert_eq!(f64::from_sql(&bytes).unwrap(), 0.1);
+        let (_, bytes) = encode(-1.5f32);
+        assert_eq!(bytes, vec![0xbf, 0xc0, 0, 0]);
+        assert_eq!(f32::from_sql(&bytes).unwrap(), -1.5);
+    }
+
+    #[test]
+    fn test_strings_and_bytes() {
+        assert_eq!(encode("héllo"), (IsNull::No, "héllo".as_bytes().to_vec()));
+        assert_eq!(String::from_sql("héllo".as_bytes()).unwrap(), "héllo");
+        assert!(String::from_sql(&[0xff]).is_err());
+        assert_eq!(encode(vec![0u8, 255]), (IsNull::No, vec![0, 255]));
+        assert_eq!(Vec::<u8>::from_sql(&[0, 255]).unwrap(), vec![0, 255]);
+    }
+
+    #[test]
+    fn test_bool() {
+        assert_eq!(encode(true), (IsNull::No, vec![1]));
+        assert_eq!(bool::from_sql(&[0]).unwrap(), false);
+    }
+
+    #[test]
+    fn test_null() {
+        assert_eq!(encode(None::<i32>), (IsNull::Yes, vec![]));
+        assert_eq!(encode(Some(7i32)), (IsNull::No, vec![0, 0, 0, 7]));
+        assert_eq!(Option::<i32>::from_sql_nullable(None).unwrap(), None);
+        assert_eq!(Option::<i32>::from_sql_nullable(Some(&[0, 0, 0, 7])).unwrap(), Some(7));
+        assert!(i32::from_sql_nullable(None).is_err());
+    }
+}